@@ -0,0 +1,141 @@
+use std::env;
+use serde_json::{
+    Map,
+    Value,
+};
+
+
+// the build metadata concourse hands the out step, by placeholder name
+const BUILD_VARS: &'static [(&'static str, &'static str)] = &[
+    ("build_id", "BUILD_ID"),
+    ("build_name", "BUILD_NAME"),
+    ("build_job_name", "BUILD_JOB_NAME"),
+    ("build_pipeline_name", "BUILD_PIPELINE_NAME"),
+    ("build_team_name", "BUILD_TEAM_NAME"),
+    ("atc_external_url", "ATC_EXTERNAL_URL"),
+];
+
+// the annotations tying a release to the build that deployed it, and where they come from
+const PROVENANCE: &'static [(&'static str, &'static str)] = &[
+    ("concourse/pipeline", "{{build_pipeline_name}}"),
+    ("concourse/job", "{{build_job_name}}"),
+    ("concourse/build-id", "{{build_id}}"),
+];
+
+/// Replaces placeholders like `{{build_id}}` with the metadata of the
+/// build running the out step, and optionally `$VAR`s with the environment.
+/// Unknown placeholders and unset variables are left alone.
+pub struct BuildMetadata {
+    vars: Vec<(String, String)>,
+    expand_env: bool,
+}
+
+impl BuildMetadata {
+    pub fn from_env() -> Self {
+        BuildMetadata {
+            vars: BUILD_VARS.iter()
+                .filter_map(|&(name, var)| env::var(var).ok()
+                    .map(|value| (format!("{{{{{}}}}}", name), value)))
+                .collect(),
+            expand_env: false,
+        }
+    }
+
+    /// Also expand `$VAR` and `${VAR}` from our environment, `$$` being a plain `$`.
+    pub fn expanding_env(mut self, expand_env: bool) -> Self {
+        self.expand_env = expand_env;
+        self
+    }
+
+    pub fn interpolate(&self, text: &str) -> String {
+        let text = self.vars.iter().fold(text.to_string(), |text, &(ref placeholder, ref value)| {
+            text.replace(placeholder, value)
+        });
+        if self.expand_env {
+            expand_env(&text)
+        } else {
+            text
+        }
+    }
+
+    /// Which build this is, e.g. `deploy/prod #42`, if concourse told us.
+    pub fn description(&self) -> Option<String> {
+        let description = self.interpolate("{{build_pipeline_name}}/{{build_job_name}} #{{build_name}}");
+        if description.contains("{{") {
+            None
+        } else {
+            Some(description)
+        }
+    }
+
+    /// Annotations naming the build, e.g. `concourse/build-id`, and the
+    /// commit it deployed as `concourse/git-ref` when there's one.
+    pub fn provenance(&self, git_ref: Option<&str>) -> Map<String, Value> {
+        let mut annotations: Map<String, Value> = PROVENANCE.iter()
+            .map(|&(annotation, placeholder)| (annotation, self.interpolate(placeholder)))
+            .filter(|&(_, ref value)| !value.contains("{{"))
+            .map(|(annotation, value)| (annotation.to_string(), Value::String(value)))
+            .collect();
+        if let Some(git_ref) = git_ref {
+            annotations.insert("concourse/git-ref".to_string(), Value::String(git_ref.to_string()));
+        }
+        annotations
+    }
+
+    /// Interpolate every string in `value`, however deeply nested.
+    pub fn interpolate_value(&self, value: &mut Value) {
+        match *value {
+            Value::String(ref mut s) => *s = self.interpolate(s),
+            Value::Array(ref mut items) => for item in items.iter_mut() {
+                self.interpolate_value(item);
+            },
+            Value::Object(ref mut fields) => for (_, field) in fields.iter_mut() {
+                self.interpolate_value(field);
+            },
+            _ => (),
+        }
+    }
+}
+
+// substitute the environment for `$VAR` and `${VAR}` in `text`
+fn expand_env(text: &str) -> String {
+    let mut expanded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(at) = rest.find('$') {
+        expanded.push_str(&rest[..at]);
+        rest = &rest[at + 1..];
+
+        if rest.starts_with('$') {
+            expanded.push('$');
+            rest = &rest[1..];
+            continue;
+        }
+        let (name, len) = if rest.starts_with('{') {
+            match rest.find('}') {
+                Some(end) => (&rest[1..end], end + 1),
+                None => ("", 0),
+            }
+        } else {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            (&rest[..end], end)
+        };
+
+        match env::var(name) {
+            Ok(ref value) if is_var_name(name) => expanded.push_str(value),
+            _ => {
+                expanded.push('$');
+                expanded.push_str(&rest[..len]);
+            },
+        }
+        rest = &rest[len..];
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+fn is_var_name(name: &str) -> bool {
+    name.chars().next().map(|c| c.is_ascii_alphabetic() || c == '_').unwrap_or(false)
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
@@ -7,36 +7,241 @@ use self::serde::{
     Serialize,
 };
 use self::serde_json::error::Result as JsonResult;
+use std::collections::{
+    BTreeMap,
+    HashMap,
+};
 use std::io::{
     self,
 };
 
-impl ::std::convert::Into<helm_api::Config> for Source {
-    fn into(self) -> helm_api::Config {
-        helm_api::Config {
-            url: self.url,
-            username: self.username,
-            password: self.password,
-            namespace: self.namespace,
-            skip_tls_verify: self.skip_tls_verify,
-            ca_data: self.ca_data,
-        }
+impl Source {
+    /// The helm-api config for this source, if it's valid.
+    pub fn config(self) -> Result<helm_api::Config, helm_api::HelmError> {
+        helm_api::Config::builder(self.namespace.as_ref().map(|n| n as &str))
+            .url(self.url)
+            .username(self.username)
+            .password(self.password)
+            .token(self.token)
+            .client_cert(self.client_cert)
+            .client_key(self.client_key)
+            .in_cluster(self.in_cluster)
+            .kubeconfig(self.kubeconfig)
+            .context(self.context)
+            .cluster(self.cluster)
+            .user(self.user)
+            .skip_tls_verify(self.skip_tls_verify)
+            .ca_data(self.ca_data)
+            .ca_file(self.ca_file)
+            .release(self.release)
+            .release_prefix(self.release_prefix)
+            .release_suffix(self.release_suffix)
+            .list_backend(self.list_backend)
+            .release_selector(self.release_selector)
+            .release_labels(self.release_labels)
+            .protected_releases(self.protected_releases)
+            .repos(self.repos)
+            .plugins(self.plugins)
+            .sops(self.sops)
+            .create_namespace(self.create_namespace)
+            .debug(self.debug)
+            .log_format(self.log_format)
+            .tiller_namespace(self.tiller_namespace)
+            .install_tiller(self.install_tiller)
+            .tiller_tls(self.tiller_tls)
+            .impersonate(self.impersonate)
+            .registries(self.registries)
+            .history_max(self.history_max)
+            .extra_args(self.extra_args)
+            .env(self.env)
+            .aws(self.aws)
+            .gcp(self.gcp)
+            .api_connect_timeout(self.api_connect_timeout)
+            .api_timeout(self.api_timeout)
+            .api_qps(self.api_qps)
+            .api_burst(self.api_burst)
+            .statuses(self.statuses)
+            .detect_drift(self.detect_drift)
+            .fast_check(self.fast_check)
+            .digest_algorithm(self.digest_algorithm)
+            .keyring(self.keyring)
+            .helm_bin(self.helm_bin)
+            .required_version(self.required_version)
+            .version_skew(self.version_skew)
+            .skip_repo_update(self.skip_repo_update)
+            .cache_dir(self.cache_dir)
+            .repo_cache_ttl(self.repo_cache_ttl)
+            .build()
+    }
+
+    /// This source pointed at each of its `targets` instead of its own cluster, by name.
+    pub fn target_sources(&self) -> Vec<(String, Source)> {
+        self.targets.iter().flat_map(|targets| targets.iter()).map(|(name, cluster)| {
+            let cluster = cluster.clone();
+            (name.clone(), Source {
+                url: cluster.url,
+                username: cluster.username,
+                password: cluster.password,
+                token: cluster.token,
+                client_cert: cluster.client_cert,
+                client_key: cluster.client_key,
+                in_cluster: cluster.in_cluster,
+                kubeconfig: cluster.kubeconfig,
+                context: cluster.context,
+                cluster: cluster.cluster,
+                user: cluster.user,
+                // the source's namespace, unless the target has its own
+                namespace: cluster.namespace.or_else(|| self.namespace.clone()),
+                skip_tls_verify: cluster.skip_tls_verify,
+                ca_data: cluster.ca_data,
+                ca_file: cluster.ca_file,
+                targets: None,
+                ..self.clone()
+            })
+        }).collect()
     }
 }
 
-#[derive(Deserialize)]
+/// Another cluster charts can be deployed to, reached and authenticated
+/// like the source's own. Everything else is the source's.
+#[derive(Clone, Deserialize)]
+pub struct Cluster {
+    pub url: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub token: Option<String>,
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+    pub in_cluster: Option<bool>,
+    pub kubeconfig: Option<String>,
+    pub context: Option<String>,
+    pub cluster: Option<String>,
+    pub user: Option<String>,
+    pub namespace: Option<String>,
+    pub skip_tls_verify: Option<bool>,
+    pub ca_data: Option<String>,
+    pub ca_file: Option<String>,
+}
+
+#[derive(Clone, Deserialize)]
 pub struct Source {
-    pub url: String,
-    pub username: String,
-    pub password: String,
-    pub namespace: String,
+    pub url: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// a bearer token, instead of username/password
+    pub token: Option<String>,
+    /// PEM client certificate, instead of username/password
+    pub client_cert: Option<String>,
+    /// PEM private key of `client_cert`
+    pub client_key: Option<String>,
+    /// use the service account of the pod the resource runs in
+    pub in_cluster: Option<bool>,
+    /// a full kubeconfig, raw or base64, instead of url/username/password
+    pub kubeconfig: Option<String>,
+    /// the context of `kubeconfig` to use, its current-context by default
+    pub context: Option<String>,
+    /// the cluster of `kubeconfig` to use, the context's by default
+    pub cluster: Option<String>,
+    /// the user of `kubeconfig` to use, the context's by default
+    pub user: Option<String>,
+    /// where releases are deployed, every namespace when left out
+    pub namespace: Option<String>,
     pub skip_tls_verify: Option<bool>,
     pub ca_data: Option<String>,
+    /// path to a CA bundle in the container, instead of `ca_data`
+    pub ca_file: Option<String>,
+    pub release: Option<String>,
+    /// prepended to every release name, e.g. to keep pipelines apart
+    pub release_prefix: Option<String>,
+    /// appended to every release name
+    pub release_suffix: Option<String>,
+    pub list_backend: Option<helm_api::ListBackend>,
+    /// labels every workload of a release has, instead of helm's, e.g. for relabelled charts
+    pub release_selector: Option<HashMap<String, String>>,
+    /// the labels to read a workload's release and chart from, instead of helm's
+    pub release_labels: Option<helm_api::ReleaseLabels>,
+    pub protected_releases: Option<Vec<String>>,
+    pub plugins: Option<Vec<helm_api::Plugin>>,
+    /// chart repositories to add, with credentials for private ones
+    pub repos: Option<Vec<helm_api::Repo>>,
+    pub sops: Option<helm_api::SopsConfig>,
+    /// create `namespace` if it doesn't exist yet
+    pub create_namespace: Option<bool>,
+    /// log full helm command lines, kube API calls and timings
+    pub debug: Option<bool>,
+    /// `text` or `json` log lines on stderr
+    pub log_format: Option<helm_api::logger::LogFormat>,
+    /// where tiller runs, if it isn't `kube-system`
+    pub tiller_namespace: Option<String>,
+    /// install tiller while configuring, for bootstrapping clusters
+    pub install_tiller: Option<helm_api::InstallTiller>,
+    /// PEM client credentials for a TLS secured tiller
+    pub tiller_tls: Option<helm_api::TillerTls>,
+    /// `user` and `groups` to act as, for clusters that grant the credentials impersonation rights
+    pub impersonate: Option<helm_api::Impersonate>,
+    /// OCI registries `params.publish` can push charts to
+    pub registries: Option<Vec<helm_api::Registry>>,
+    /// how many revisions of each release to keep
+    pub history_max: Option<u32>,
+    /// helm flags to add to every upgrade, one argument per entry
+    pub extra_args: Option<Vec<String>>,
+    /// environment variables for helm and its plugins, e.g. `AWS_*` for helm-s3
+    pub env: Option<HashMap<String, String>>,
+    /// credentials for `s3://` repos, the worker's IAM role is used without them
+    pub aws: Option<helm_api::AwsConfig>,
+    /// credentials for `gs://` repos, the worker's default credentials are used without them
+    pub gcp: Option<helm_api::GcpConfig>,
+    /// seconds to wait for a connection to the apiserver
+    pub api_connect_timeout: Option<u64>,
+    /// seconds any one apiserver request may take, so a hung apiserver fails `check`
+    pub api_timeout: Option<u64>,
+    /// apiserver requests a second at most, so many pipelines on one cluster don't get throttled
+    pub api_qps: Option<f64>,
+    /// apiserver requests allowed at once on top of `api_qps`
+    pub api_burst: Option<u32>,
+    /// only version releases that are `deployed`, `failed` or `all` of them
+    pub statuses: Option<Vec<helm_api::StatusFilter>>,
+    /// trigger on hand edits to the releases' workloads, too
+    pub detect_drift: Option<bool>,
+    /// check with a single apiserver request and tight timeouts, e.g. when checks are run by webhooks
+    pub fast_check: Option<bool>,
+    /// `md5`, `sha256` or `blake3`; versions digested another way keep matching
+    pub digest_algorithm: Option<helm_api::DigestAlgorithm>,
+    /// ASCII-armored public keys, or a keyring path, to verify signed charts with
+    pub keyring: Option<String>,
+    /// path to the helm executable
+    pub helm_bin: Option<String>,
+    /// semver requirement the helm client has to satisfy
+    pub required_version: Option<String>,
+    /// `fail`, `warn` or `ignore` when tiller is another minor version
+    pub version_skew: Option<helm_api::VersionSkew>,
+    /// skip `helm repo update` unless a chart isn't pinned to a version
+    pub skip_repo_update: Option<bool>,
+    /// a directory mounted into the container that keeps repo indexes and charts between runs,
+    /// and where checks keep watching for changes from
+    pub cache_dir: Option<String>,
+    /// seconds the repo indexes in `cache_dir` are used for before downloading them again
+    pub repo_cache_ttl: Option<u64>,
+    /// where `vault:` override values are looked up
+    pub vault: Option<helm_api::VaultConfig>,
+    /// where `params.publish` uploads packaged charts
+    pub chartmuseum: Option<helm_api::ChartMuseumConfig>,
+    /// a webhook each put POSTs a summary of how it went to
+    pub notify: Option<helm_api::NotifyConfig>,
+    /// default for every chart's `wait`
+    pub wait: Option<bool>,
+    /// default for every chart's `timeout`, in seconds
+    pub timeout: Option<u64>,
+    /// more clusters, by name, that charts can pick with their `target`, e.g. one per region
+    pub targets: Option<BTreeMap<String, Cluster>>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Version {
     pub digest: String,
+    /// where the cluster was at when the digest was taken, to watch for changes from
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resource_version: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -45,7 +250,14 @@ pub struct CheckRequest {
     pub version: Option<Version>,
 }
 
-pub type InRequest = CheckRequest;
+#[derive(Deserialize)]
+pub struct InRequest<P>
+where P: Deserialize,
+{
+    pub source: Source,
+    pub version: Option<Version>,
+    pub params: Option<P>,
+}
 
 #[derive(Serialize)]
 pub struct InResponse<M>
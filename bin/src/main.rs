@@ -1,34 +1,137 @@
 #[macro_use] extern crate serde_derive;
 extern crate helm_api;
 extern crate serde_json;
+extern crate serde_yaml;
 
+mod build_metadata;
 mod concourse_api;
 
 use std::env::args;
 use std::collections::{
+    BTreeMap,
     HashMap,
 };
+use std::fmt;
+use std::io::{
+    Read,
+    Write,
+};
+use std::process;
+use std::sync::{
+    Arc,
+    Mutex,
+};
+use std::sync::atomic::{
+    AtomicBool,
+    Ordering,
+};
+use std::thread;
+use std::time::{
+    Duration,
+    Instant,
+};
+use std::path::{
+    Path,
+    PathBuf,
+};
+use std::fs::{
+    self,
+    File,
+};
 use serde_json::Value;
+use build_metadata::BuildMetadata;
 use concourse_api::{
     CheckRequest,
     InRequest,
     InResponse,
     OutRequest,
     OutResponse,
+    Source,
     Version,
 };
 use helm_api::{
     Helm,
     Chart,
-    Charts,
+    ChartMuseum,
+    Notifier,
+    Release,
+    Releases,
+    DeleteOptions,
+    HelmError,
+    LockMode,
+    MergeStrategy,
+    PostRenderer,
+    Recover,
+    UpgradeOptions,
+    Vault,
+    logger,
+    values,
+};
+use helm_api::logger::{
+    Level,
+    Timing,
 };
 
+// how long verify_rollout waits when the chart doesn't say
+const DEFAULT_VERIFY_TIMEOUT_SECS: u64 = 300;
+
+// what tells a canary release apart from its primary, and how far it's scaled down
+const DEFAULT_CANARY_SUFFIX: &'static str = "-canary";
+const DEFAULT_CANARY_REPLICAS: u64 = 1;
+// the value most charts scale their workload with
+const DEFAULT_REPLICAS_KEY: &'static str = "replicaCount";
+
+// the colors of a blue-green deploy, and the pod label its service selects one of them by
+const BLUE: &'static str = "blue";
+const GREEN: &'static str = "green";
+const DEFAULT_COLOR_LABEL: &'static str = "color";
+
+// how long to wait for CRDs installed ahead of their chart to be established
+const DEFAULT_CRD_TIMEOUT_SECS: u64 = 120;
+
+// how long a release's lock outlives a deploy that never released it
+const DEFAULT_LOCK_TTL_SECS: u64 = 3600;
+
+// where the out step writes the manifests it applied, relative to its sources
+const MANIFESTS_DIR: &'static str = "manifests";
+
+// where the out step writes the notes of the releases it deployed, relative to its sources
+const NOTES_DIR: &'static str = "notes";
+
+// where the out step writes the resolved values of deep-merged charts, relative to its sources
+const VALUES_DIR: &'static str = "values";
+
+// where the in step writes what the latest deploy of each release changed, relative to its destination
+const DIFFS_DIR: &'static str = "diffs";
+
+// where the in step writes the revisions of each release, relative to its destination
+const HISTORY_DIR: &'static str = "history";
+
+// where the out step packages charts it publishes, relative to its sources
+const PACKAGES_DIR: &'static str = "packages";
+
+// where the out step keeps what releases ran before it upgraded them, relative to its sources
+const BACKUP_DIR: &'static str = "backup";
+
+// where the out step writes how the put went, relative to its sources
+const SUMMARY_FILE: &'static str = "summary.json";
+
+// where the out step writes how long each of its phases took, relative to its sources
+const METRICS_FILE: &'static str = "metrics.json";
+
+// where checks keep the resource version to watch for changes from, relative to `source.cache_dir`
+const CURSOR_FILE: &'static str = "check-cursor.json";
+
+/// The clusters of `source.targets` by name, each set up like the source's own.
+type Clusters = BTreeMap<String, Arc<Helm>>;
+
 fn main() {
     match args().nth(1).as_ref().map(|s| s as &str) {
         Some("check") => request_check(),
         Some("in") => request_in(),
         Some("out") => request_out(),
-        _ => panic!("Must provide either check, in, or out as first argument!"),
+        Some("doctor") => request_doctor(),
+        _ => panic!("Must provide either check, in, out or doctor as first argument!"),
     }
 }
 
@@ -36,107 +139,1785 @@ fn request_check() {
     // get request from concourse
     let check_request: CheckRequest = concourse_api::receive_message().unwrap();
 
-    // set up helm to connect to our cluster
-    let helm = Helm::configure(check_request.source.into()).unwrap();
+    // set up helm to connect to our cluster, and any other targets
+    let clusters = configure_targets(&check_request.source);
+    let cursor = check_request.source.cache_dir.as_ref().map(|dir| Path::new(dir).join(CURSOR_FILE));
+    let helm = Helm::configure(check_request.source.config().unwrap()).unwrap();
 
-    // get a digest of the current state of installed packages
-    let response = vec![Version {
-        digest: helm.digest().unwrap(),
-    }];
+    let response = vec![check_version(check_request.version.as_ref(), cursor.as_ref().map(|path| path as &Path),
+        |rv| changed_since(&helm, &clusters, rv),
+        || current_version(&helm, &clusters),
+        |digest, current| same_version(&helm, &clusters, digest, current))];
 
     // reply with a message
     concourse_api::send_message(&response).unwrap();
 }
 
+// the version a check reports: `previous` again as long as the apiserver says nothing changed since,
+// else a digest of the current state of installed packages. the previous version still stands if
+// it's of the same state, however it was digested, but it can't carry a newer resource version,
+// concourse would take it for a new version. so the newer one is kept at `cursor` to watch from
+// instead, without it every check after a change the digest doesn't see takes the digest again
+fn check_version<C, D, S>(previous: Option<&Version>, cursor: Option<&Path>, changed_since: C, current: D, same: S)
+    -> Version
+where C: Fn(&str) -> bool,
+      D: FnOnce() -> Version,
+      S: Fn(&str, &str) -> bool,
+{
+    let previous = match previous {
+        Some(previous) => previous,
+        None => return current(),
+    };
+    let watched_from = cursor.and_then(|cursor| read_cursor(cursor, &previous.digest))
+        .or_else(|| previous.resource_version.clone());
+    if let Some(ref rv) = watched_from {
+        if !changed_since(rv) {
+            return previous.clone();
+        }
+    }
+
+    let current = current();
+    if !same(&previous.digest, &current.digest) {
+        return current;
+    }
+    if let (Some(cursor), Some(rv)) = (cursor, current.resource_version) {
+        let moved = Version { digest: previous.digest.clone(), resource_version: Some(rv) };
+        if let Err(e) = File::create(cursor).and_then(|mut file|
+            file.write_all(serde_json::to_string(&moved).unwrap().as_bytes()))
+        {
+            logger::debug(format_args!("Could not keep the check's cursor at `{}`: {}", cursor.display(), e));
+        }
+    }
+    previous.clone()
+}
+
+// the resource version kept at `cursor` to watch from, if it's still for the version of `digest`
+fn read_cursor(cursor: &Path, digest: &str) -> Option<String> {
+    let mut contents = String::new();
+    File::open(cursor).and_then(|mut file| file.read_to_string(&mut contents)).ok()
+        .and_then(|_| serde_json::from_str::<Version>(&contents).ok())
+        .and_then(|kept| if kept.digest == digest { kept.resource_version } else { None })
+}
+
+// set up helm for every one of the source's targets
+fn configure_targets(source: &Source) -> Clusters {
+    source.target_sources().into_iter()
+        .map(|(name, source)| {
+            let helm = source.config().and_then(Helm::configure)
+                .unwrap_or_else(|e| panic!("Could not set up target `{}`: {}", name, e));
+            (name, Arc::new(helm))
+        })
+        .collect()
+}
+
+// the digest of what's deployed, and where the cluster was at before taking it
+// so that anything changing meanwhile shows up in the next check.
+// with targets, both have every target's after our own, by name
+fn current_version(helm: &Helm, clusters: &Clusters) -> Version {
+    let mut resource_versions = vec![helm.resource_version()];
+    resource_versions.extend(clusters.iter()
+        .map(|(name, helm)| helm.resource_version().map(|rv| format!("{}={}", name, rv))));
+    let mut digests = vec![helm.digest().unwrap()];
+    digests.extend(clusters.iter().map(|(name, helm)| format!("{}={}", name, helm.digest().unwrap())));
+    Version {
+        digest: digests.join(","),
+        // a cluster we can't watch has every check digest it again
+        resource_version: resource_versions.into_iter().collect::<Option<Vec<_>>>().map(|rvs| rvs.join(",")),
+    }
+}
+
+// whether anything may have changed since the resource versions of a version, cluster by cluster
+fn changed_since(helm: &Helm, clusters: &Clusters, resource_version: &str) -> bool {
+    let mut resource_versions = resource_version.split(',');
+    if resource_versions.next().map(|rv| helm.changed_since(rv)).unwrap_or(true) {
+        return true;
+    }
+    // targets that were added or removed since change the digest anyway
+    let theirs: Vec<&str> = resource_versions.collect();
+    theirs.len() != clusters.len() || clusters.iter().zip(theirs).any(|((name, helm), rv)| {
+        let mut parts = rv.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(target), Some(rv)) if target == name => helm.changed_since(rv),
+            _ => true,
+        }
+    })
+}
+
+// whether `digest` is of the same state as `current`, cluster by cluster
+fn same_version(helm: &Helm, clusters: &Clusters, digest: &str, current: &str) -> bool {
+    let digests: Vec<&str> = digest.split(',').collect();
+    let currents: Vec<&str> = current.split(',').collect();
+    if digests.len() != currents.len() {
+        return false;
+    }
+    digests.iter().zip(&currents).enumerate().all(|(i, (digest, current))| {
+        if i == 0 {
+            return helm.same_digest(digest, current).unwrap();
+        }
+        let mut digest = digest.splitn(2, '=');
+        let mut current = current.splitn(2, '=');
+        match (digest.next(), digest.next(), current.next(), current.next()) {
+            (Some(name), Some(digest), Some(current_name), Some(current)) if name == current_name =>
+                clusters.get(name).map(|helm| helm.same_digest(digest, current).unwrap()).unwrap_or(false),
+            _ => false,
+        }
+    })
+}
+
+// check a source bit by bit, printing what works and what doesn't
+fn request_doctor() {
+    // the same request check gets, only the source matters
+    let request: CheckRequest = concourse_api::receive_message().unwrap();
+
+    // every check needs the ones before it to have passed
+    let targets = request.source.target_sources();
+    let config = request.source.config();
+    let mut healthy = report("source", config.as_ref().map(|_| "valid"));
+    if let Ok(config) = config {
+        let helm = Helm::configure(config);
+        healthy &= report("kubeconfig", helm.as_ref().map(|_| "rendered"));
+
+        if let Ok(helm) = helm {
+            healthy &= report("helm", helm.helm_version());
+            healthy &= report("apiserver", helm.check_api_access().map(|_| "reachable and authorized"));
+            healthy &= report("tiller", helm.tiller_version()
+                .map(|version| version.unwrap_or("not needed by helm 3".to_string())));
+            healthy &= report("repos", helm.check_repos().map(|_| "reachable"));
+        }
+    }
+
+    // the targets share everything else with the source, so being reachable is what's left
+    for (name, source) in targets {
+        let access = source.config().and_then(Helm::configure).and_then(|helm| helm.check_api_access());
+        healthy &= report(&format!("target `{}`", name), access.map(|_| "reachable and authorized"));
+    }
+
+    if !healthy {
+        process::exit(1);
+    }
+}
+
+// print how one of the doctor's checks went, returning whether it passed
+fn report<T, E>(check: &str, result: Result<T, E>) -> bool
+where T: fmt::Display,
+      E: fmt::Display,
+{
+    match result {
+        Ok(detail) => {
+            println!("PASS  {}: {}", check, detail);
+            true
+        },
+        Err(e) => {
+            println!("FAIL  {}: {}", check, e);
+            false
+        },
+    }
+}
+
 fn request_in() {
     // get request from concourse
-    let in_request: InRequest = concourse_api::receive_message().unwrap();
+    let in_request: InRequest<InParams> = concourse_api::receive_message().unwrap();
+    let params = in_request.params.unwrap_or_default();
 
-    // set up helm to connect to our cluster
-    let helm = Helm::configure(in_request.source.into()).unwrap();
+    // set up helm to connect to our cluster, and any other targets
+    let clusters = configure_targets(&in_request.source);
+    let helm = Helm::configure(in_request.source.config().unwrap()).unwrap();
 
-    // get the list of deployed charts
-    let deployed_charts = helm.list().unwrap();
+    // get the list of deployed releases
+    let deployed_releases = helm.list().unwrap();
 
-    // get the digest
-    let digest = helm.digest().unwrap();
+    // where the files for later steps go
+    let dest = PathBuf::from(args().nth(2).unwrap_or(".".to_string()));
+
+    // the exact chart archives that are deployed, e.g. to promote them elsewhere
+    if params.fetch_charts.unwrap_or(false) {
+        for release in &deployed_releases {
+            logger::info(format_args!("Fetching chart `{}` of release `{}`.", release.chart, release.name));
+            helm.fetch(params.fetch_repo.as_ref().map(|r| r as &str), &release.chart,
+                release.version.as_ref().map(|v| v as &str), &dest).unwrap();
+        }
+    }
+
+    // what the latest deploy of each release changed, for whoever audits it
+    if let Some(DiffAgainst::Previous) = params.diff_against {
+        let diffs_dir = dest.join(DIFFS_DIR);
+        fs::create_dir_all(&diffs_dir).unwrap();
+        for release in &deployed_releases {
+            let to = match release.revision {
+                Some(revision) if revision > 1 => revision,
+                _ => continue,
+            };
+            match helm.diff_revisions(&release.name, to - 1, to) {
+                Ok(diff) => {
+                    File::create(release_file(&helm, &diffs_dir, release, "diff")).unwrap()
+                        .write_all(diff.unified().as_bytes()).unwrap();
+                    File::create(release_file(&helm, &diffs_dir, release, "json")).unwrap()
+                        .write_all(serde_json::to_string_pretty(&diff).unwrap().as_bytes()).unwrap();
+                },
+                // the revision before may be gone past `history_max`
+                Err(e) => logger::warn(format_args!("Could not diff revision {} of `{}` against the one before: {}",
+                    to, release.name, e)),
+            }
+        }
+    }
+
+    // how each release got to where it is, for the archives
+    if params.include_history.unwrap_or(false) {
+        let history_dir = dest.join(HISTORY_DIR);
+        fs::create_dir_all(&history_dir).unwrap();
+        for release in &deployed_releases {
+            let history = helm.history(&release.name).unwrap();
+            File::create(release_file(&helm, &history_dir, release, "yaml")).unwrap()
+                .write_all(serde_yaml::to_string(&history).unwrap().as_bytes()).unwrap();
+        }
+    }
+
+    // the version asked for, if that's still what's deployed
+    let current = current_version(&helm, &clusters);
+    let version = match in_request.version {
+        Some(ref version) if same_version(&helm, &clusters, &version.digest, &current.digest) => version.clone(),
+        _ => current,
+    };
+
+    // the state of the namespace, for later steps to read
+    File::create(dest.join("version")).unwrap()
+        .write_all(version.digest.as_bytes()).unwrap();
+    File::create(dest.join("releases.yaml")).unwrap()
+        .write_all(serde_yaml::to_string(&deployed_releases).unwrap().as_bytes()).unwrap();
+    if let Some(namespace) = helm.namespace() {
+        File::create(dest.join("namespace")).unwrap()
+            .write_all(namespace.as_bytes()).unwrap();
+    }
 
     // reply with a message
     let response = InResponse {
-        version: Version {
-            digest: digest,
-        },
-        metadata: deployed_charts,
+        version: version,
+        metadata: deployed_releases,
     };
     concourse_api::send_message(&response).unwrap();
 }
 
+/// Where the artifact of `release` with `extension` goes in `dir`, e.g. `diffs/web.diff`,
+/// or `diffs/prod/web.diff` when the source covers every namespace.
+fn release_file(helm: &Helm, dir: &Path, release: &Release, extension: &str) -> PathBuf {
+    let dir = match helm.namespace() {
+        Some(_) => dir.to_path_buf(),
+        None => dir.join(&release.namespace),
+    };
+    fs::create_dir_all(&dir).unwrap();
+    dir.join(format!("{}.{}", release.name, extension))
+}
+
+/// Set the value at the dotted `key` of `overrides`, e.g. `image.tag`, merging it into whatever's there.
+fn set_override(overrides: &mut HashMap<String, Value>, key: &str, value: Value) {
+    let mut path: Vec<&str> = key.split('.').collect();
+    let top = path.remove(0).to_string();
+    let nested = path.iter().rev().fold(value, |value, key| {
+        let mut object = serde_json::Map::new();
+        object.insert(key.to_string(), value);
+        Value::Object(object)
+    });
+    values::merge(overrides.entry(top).or_insert(Value::Null), nested);
+}
+
+/// Keep the values and manifest `deployed` runs as `backup/<release>/rev-<n>/` in `sources_dir`,
+/// nested in its namespace like `release_file`, returning where relative to the sources.
+fn backup(helm: &Helm, sources_dir: &Path, deployed: &Release) -> Result<String, HelmError> {
+    let revision = match deployed.revision {
+        Some(revision) => revision,
+        None => try!(helm.history(&deployed.name)).last().map(|r| r.revision).unwrap_or(0),
+    };
+    let mut backup_dir = PathBuf::from(BACKUP_DIR);
+    if helm.namespace().is_none() {
+        backup_dir.push(&deployed.namespace);
+    }
+    backup_dir.push(&deployed.name);
+    backup_dir.push(format!("rev-{}", revision));
+    let dir = sources_dir.join(&backup_dir);
+    try!(fs::create_dir_all(&dir));
+
+    let values = try!(serde_yaml::to_string(&try!(helm.values(&deployed.name))));
+    try!(try!(File::create(dir.join("values.yaml"))).write_all(values.as_bytes()));
+    let manifest = try!(helm.manifest(&deployed.name));
+    try!(try!(File::create(dir.join("manifest.yaml"))).write_all(manifest.as_bytes()));
+    // what chart and version to restore the values and manifest with
+    try!(try!(File::create(dir.join("release.json"))).write_all(try!(serde_json::to_string(deployed)).as_bytes()));
+
+    logger::info(format_args!("Backed up revision {} of `{}` to `{}`.", revision, deployed.name, backup_dir.display()));
+    Ok(backup_dir.to_string_lossy().into_owned())
+}
+
 fn request_out() {
+    let started = Instant::now();
+
     // get request from concourse
     let mut in_request: OutRequest<Params> = concourse_api::receive_message().unwrap();
 
-    // set up helm to connect to our cluster
-    let helm = Helm::configure(in_request.source.into()).unwrap();
+    // source-wide defaults the charts can override
+    let mut defaults = UpgradeOptions::default();
+    defaults.wait = in_request.source.wait.unwrap_or(defaults.wait);
+    defaults.timeout = in_request.source.timeout.or(defaults.timeout);
+
+    // a dry run renders every chart but leaves the cluster alone
+    let dry_run = in_request.params.dry_run.unwrap_or(false);
+    defaults.dry_run = dry_run;
+
+    // log in to vault, if any values come from there
+    let mut vault = in_request.source.vault.take().map(|config| Vault::login(config).unwrap());
+
+    // where published charts go
+    let chartmuseum = in_request.source.chartmuseum.take().map(|config| ChartMuseum::new(config).unwrap());
+    let notifier = in_request.source.notify.take().map(|config| Notifier::new(config).unwrap());
+
+    // set up helm to connect to our cluster, and any other targets
+    let configuring = Instant::now();
+    let clusters = configure_targets(&in_request.source);
+    let helm = Arc::new(Helm::configure(in_request.source.config().unwrap()).unwrap());
+    let configure_ms = logger::millis(configuring.elapsed());
+
+    // get the list of deployed releases, on the targets too
+    let deployed_releases = helm.list().unwrap();
+    let target_releases: HashMap<String, Releases> = clusters.iter()
+        .map(|(name, helm)| (name.clone(), helm.list().unwrap()))
+        .collect();
 
-    // get the list of deployed charts
-    let deployed_charts = helm.list().unwrap();
+    // how many upgrades we're allowed to run at the same time
+    let max_in_flight = in_request.params.max_in_flight.unwrap_or(1);
+
+    // whether to preview changes with helm-diff, and whether changes are allowed at all
+    let show_diff = in_request.params.show_diff.unwrap_or(false);
+    let fail_on_diff = in_request.params.fail_on_diff.unwrap_or(false);
+
+    // what a failing chart means for the rest, stopping at the first as puts always have
+    let on_failure = in_request.params.on_failure.unwrap_or(OnFailure::FailFast);
+
+    // whether to leave charts alone that are already deployed as requested
+    let skip_unchanged = in_request.params.skip_unchanged.unwrap_or(true);
+
+    // local chart paths are relative to the build's sources
+    let sources_dir = PathBuf::from(args().nth(2).unwrap_or(".".to_string()));
+
+    // charts may also come from a file in an input, versioned with the app
+    let mut environments = in_request.params.environments.take().unwrap_or_default();
+    if let Some(ref charts_file) = in_request.params.charts_file {
+        let file = File::open(sources_dir.join(charts_file)).unwrap();
+        let mut charts: HashMap<String, Value> = serde_yaml::from_reader(file).unwrap();
+
+        // next to the charts, the file may define the environments they're deployed to
+        if let Some(file_environments) = charts.remove("environments") {
+            let file_environments: HashMap<String, Environment> =
+                serde_json::from_value(file_environments).unwrap();
+            for (name, environment) in file_environments {
+                environments.entry(name).or_insert(environment);
+            }
+        }
+        for (release, chart) in charts {
+            // the inline charts win, they're the more specific of the two
+            let chart: ChartSpec = serde_json::from_value(chart).unwrap();
+            in_request.params.charts.entry(release).or_insert(chart);
+        }
+    }
+
+    // tailor the charts to the environment this put deploys to
+    if let Some(ref name) = in_request.params.environment {
+        let environment = environments.remove(name)
+            .unwrap_or_else(|| panic!("There is no environment `{}`!", name));
+        for (release, changes) in environment {
+            let chart = in_request.params.charts.get_mut(&release)
+                .unwrap_or_else(|| panic!("Environment `{}` changes `{}`, which isn't one of the charts!",
+                    name, release));
+            chart.apply_environment(changes);
+        }
+    }
+
+    // clean up releases whose last deploy failed, protected ones excepted
+    let collected: Vec<String> = if in_request.params.gc_failed.unwrap_or(false) && !dry_run {
+        helm.failed_releases().unwrap().into_iter()
+            .filter(|release| if helm.is_protected(release) {
+                logger::info(format_args!("Not collecting protected release `{}`.", release));
+                false
+            } else {
+                true
+            })
+            .map(|release| {
+                logger::info(format_args!("Deleting failed release `{}`.", release));
+                helm.delete(&release, &DeleteOptions { purge: true, ..DeleteOptions::default() }).unwrap();
+                release
+            })
+            .collect()
+    } else {
+        Vec::new()
+    };
+
+    // `{{build_id}}` and friends in release names and values, `$VAR`s too if asked
+    let build_metadata = BuildMetadata::from_env()
+        .expanding_env(in_request.params.expand_env.unwrap_or(false));
+
+    // publish a chart before deploying anything, so the charts below can use it
+    let published = in_request.params.publish.take().map(|publish| {
+        let packages_dir = sources_dir.join(PACKAGES_DIR);
+        fs::create_dir_all(&packages_dir).unwrap();
+
+        let version = publish.version.map(|v| build_metadata.interpolate(&v));
+        let app_version = publish.app_version.map(|v| build_metadata.interpolate(&v));
+        let archive = helm.package(
+            &sources_dir.join(&publish.chart).to_string_lossy(),
+            version.as_ref().map(|v| v as &str),
+            app_version.as_ref().map(|v| v as &str),
+            &packages_dir).unwrap();
+
+        if dry_run {
+            logger::info(format_args!("Not uploading `{}` in a dry run.", archive.display()));
+        } else if publish.oci.unwrap_or(false) {
+            let reference = helm.push_oci(&archive, publish.registry.as_ref().map(|r| r as &str)).unwrap();
+            logger::info(format_args!("Published `{}` to `{}`.", archive.display(), reference));
+        } else {
+            let chartmuseum = chartmuseum.as_ref().expect("`params.publish` needs a `source.chartmuseum`");
+            chartmuseum.upload(&archive).unwrap();
+            logger::info(format_args!("Published `{}`.", archive.display()));
+        }
+        archive.file_name()
+            .map(|name| format!("{}/{}", PACKAGES_DIR, name.to_string_lossy()))
+            .unwrap_or_default()
+    });
+
+    // dry runs change nothing, so they don't need to keep anyone out
+    let lock = match in_request.params.lock {
+        Some(mode) if !dry_run => Some(ReleaseLockSpec {
+            mode: mode,
+            holder: build_metadata.description()
+                .unwrap_or_else(|| format!("helm-resource (pid {})", process::id())),
+            ttl: Duration::from_secs(in_request.params.lock_ttl_seconds.unwrap_or(DEFAULT_LOCK_TTL_SECS)),
+        }),
+        _ => None,
+    };
+
+    // charts can stamp their resources with what deployed them
+    let provenance = if in_request.params.provenance.unwrap_or(false) {
+        let git_ref = in_request.params.git_ref_file.as_ref().map(|path| {
+            let mut git_ref = String::new();
+            File::open(sources_dir.join(path))
+                .and_then(|mut file| file.read_to_string(&mut git_ref))
+                .unwrap();
+            git_ref.trim().to_string()
+        });
+        let mut provenance = serde_json::Map::new();
+        provenance.insert("provenance".to_string(),
+            Value::Object(build_metadata.provenance(git_ref.as_ref().map(|r| r as &str))));
+        Some(Value::Object(provenance))
+    } else {
+        None
+    };
+
+    // whether to keep what the releases ran before upgrading them
+    let backup_default = in_request.params.backup.unwrap_or(false);
+
+    // a canary deploys every chart beside its primary release, scaled down,
+    // until a later put promotes it to the primary and removes it
+    let promote = in_request.params.promote.unwrap_or(false);
+    let canary = in_request.params.canary.take();
+    if canary.is_some() && !promote && in_request.params.sync.unwrap_or(false) {
+        panic!("`canary` can't be combined with `sync`, it would prune the primary releases!");
+    }
+    let canary_suffix = canary.as_ref().and_then(|canary| canary.suffix.clone())
+        .unwrap_or_else(|| DEFAULT_CANARY_SUFFIX.to_string());
+    let in_put: Vec<String> = in_request.params.charts.keys().map(|k| build_metadata.interpolate(k)).collect();
+    if promote {
+        // the canaries go once their primaries are upgraded, like any other `deletes`
+        let canaries = in_put.iter()
+            .map(|release| format!("{}{}", release, canary_suffix))
+            .filter(|canary| deployed_releases.iter().any(|r| r.name == *canary));
+        in_request.params.deletes.get_or_insert_with(Vec::new).extend(canaries);
+    }
+    let canary = if promote { None } else { canary };
 
     // morph the charts rep into a friendly format
-    let target_charts: Charts = in_request.params.charts
+    let target_charts: Vec<Target> = in_request.params.charts
         .drain()
-        .map(|(k, v)| Chart {
-            release: k,
-            name: v.name,
-            version: v.version,
-            overrides: v.overrides,
+        .map(|(k, mut v)| {
+            // tie release names and values to the build deploying them
+            let k = build_metadata.interpolate(&k);
+
+            // the cluster the chart goes to, ours unless it picks a target
+            let target = v.target.take();
+            let (chart_helm, chart_releases) = match target {
+                Some(ref target) => match (clusters.get(target), target_releases.get(target)) {
+                    (Some(helm), Some(releases)) => (helm.clone(), releases),
+                    _ => panic!("Chart `{}` picks target `{}`, which isn't one of `source.targets`!", k, target),
+                },
+                None => (helm.clone(), &deployed_releases),
+            };
+
+            let mut depends_on: Vec<String> = v.depends_on.take().unwrap_or_default().into_iter()
+                .map(|release| build_metadata.interpolate(&release))
+                .collect();
+
+            let k = if let Some(ref canary) = canary {
+                // canaries depend on the other canaries of the put, not their primaries
+                for dependency in depends_on.iter_mut().filter(|dependency| in_put.contains(dependency)) {
+                    dependency.push_str(&canary_suffix);
+                }
+                let replicas = canary.replicas.unwrap_or(DEFAULT_CANARY_REPLICAS);
+                let key = canary.replicas_key.as_ref().map(|key| key as &str).unwrap_or(DEFAULT_REPLICAS_KEY);
+                set_override(v.overrides.get_or_insert_with(HashMap::new), key, Value::U64(replicas));
+                format!("{}{}", k, canary_suffix)
+            } else {
+                k
+            };
+
+            // blue-green charts deploy whichever color the service doesn't route to
+            let (k, blue_green) = if v.strategy == Some(Strategy::BlueGreen) {
+                let service = v.service.clone().unwrap_or_else(|| k.clone());
+                let label = v.color_label.clone().unwrap_or_else(|| DEFAULT_COLOR_LABEL.to_string());
+                // just enough of the chart for its namespace
+                let routed = Chart::builder(&k, &v.name).namespace(v.namespace.clone()).build()
+                    .and_then(|chart| chart_helm.service_selector(&chart, &service, &label))
+                    .unwrap();
+                let color = match routed.as_ref().map(|color| color as &str) {
+                    Some(BLUE) => GREEN,
+                    _ => BLUE,
+                };
+                let previous = routed.and_then(|routed| chart_releases.iter()
+                    .find(|r| r.name == format!("{}-{}", k, routed)
+                        && v.namespace.as_ref().map(|n| *n == r.namespace).unwrap_or(true))
+                    .map(|r| r.name.clone()));
+                let key = v.color_key.clone().unwrap_or_else(|| label.clone());
+                set_override(v.overrides.get_or_insert_with(HashMap::new), &key, Value::String(color.to_string()));
+                (format!("{}-{}", k, color), Some(BlueGreen {
+                    service: service,
+                    label: label,
+                    color: color,
+                    previous: previous,
+                }))
+            } else {
+                (k, None)
+            };
+            if let Some(overrides) = v.overrides.as_mut() {
+                for value in overrides.values_mut() {
+                    build_metadata.interpolate_value(value);
+                }
+            }
+            if let Some(vars) = v.template_vars.as_mut() {
+                for value in vars.values_mut() {
+                    *value = build_metadata.interpolate(value);
+                }
+            }
+            if let Some(ref provenance) = provenance {
+                let overrides = v.overrides.get_or_insert_with(HashMap::new);
+                let global = overrides.entry("global".to_string())
+                    .or_insert_with(|| Value::Object(serde_json::Map::new()));
+                values::merge(global, provenance.clone());
+            }
+
+            let mut options = v.upgrade_options(&defaults);
+            // find out about options this helm can't do before anything is deployed with them
+            chart_helm.check_options(&options).unwrap_or_else(|e| panic!("Chart `{}` can't be deployed: {}", k, e));
+            // `helm history` tells which build made each revision
+            options.description = v.description.take()
+                .map(|description| build_metadata.interpolate(&description))
+                .or_else(|| build_metadata.description());
+            options.post_renderer = v.post_renderer.take().map(|post_renderer| PostRenderer {
+                path: sources_dir.join(post_renderer.path).to_string_lossy().into_owned(),
+                args: post_renderer.args,
+            });
+            let deployed = chart_releases.iter()
+                .find(|r| r.name == k && v.namespace.as_ref().map(|n| *n == r.namespace).unwrap_or(true))
+                .cloned();
+
+            // swap `vault:` references for the secrets they point at
+            if let (Some(vault), Some(overrides)) = (vault.as_mut(), v.overrides.as_mut()) {
+                for value in overrides.values_mut() {
+                    vault.resolve(value).unwrap();
+                }
+            }
+            // traffic only moves to a color once it's ready, whether or not the chart asks to verify
+            let verify_rollout = if (v.verify_rollout.unwrap_or(false) || blue_green.is_some()) && !dry_run {
+                Some(Duration::from_secs(v.verify_timeout.unwrap_or(DEFAULT_VERIFY_TIMEOUT_SECS)))
+            } else {
+                None
+            };
+            let install_crds = if v.install_crds_first.unwrap_or(false) && !dry_run {
+                Some(Duration::from_secs(v.crd_timeout.or(options.timeout).unwrap_or(DEFAULT_CRD_TIMEOUT_SECS)))
+            } else {
+                None
+            };
+            Target {
+                chart: Chart::builder(&k, &v.name)
+                    .version(v.version)
+                    .overrides(v.overrides)
+                    .path(v.path.map(|path| sources_dir.join(path).to_string_lossy().into_owned()))
+                    .repo(v.repo)
+                    .values_files(v.values_files.map(|files| files.into_iter()
+                        .map(|file| sources_dir.join(file).to_string_lossy().into_owned())
+                        .collect::<Vec<_>>()))
+                    .values_json_files(v.values_json_files.map(|files| files.into_iter()
+                        .map(|file| sources_dir.join(file).to_string_lossy().into_owned())
+                        .collect::<Vec<_>>()))
+                    .values_json(v.values_json)
+                    .secret_values_files(v.secret_values_files.map(|files| files.into_iter()
+                        .map(|file| sources_dir.join(file).to_string_lossy().into_owned())
+                        .collect::<Vec<_>>()))
+                    .secret_keys(v.secret_keys)
+                    .values_template(v.values_template
+                        .map(|path| sources_dir.join(path).to_string_lossy().into_owned()))
+                    .template_vars(v.template_vars)
+                    .verify(v.verify)
+                    .devel(v.devel)
+                    .merge_strategy(v.merge_strategy)
+                    .namespace(v.namespace)
+                    .build()
+                    .unwrap(),
+                target: target,
+                helm: chart_helm,
+                options: options,
+                verify_rollout: verify_rollout,
+                test: v.test.unwrap_or(false) && !dry_run,
+                lint_strict: v.lint_strict.unwrap_or(false),
+                show_diff: show_diff,
+                fail_on_diff: fail_on_diff,
+                skip_unchanged: skip_unchanged,
+                deployed: deployed,
+                depends_on: depends_on,
+                deadline: v.deadline_seconds.map(Duration::from_secs),
+                rollback_on_deadline: v.rollback_on_deadline.unwrap_or(false) && !dry_run,
+                lock: lock.clone(),
+                backup_to: if v.backup.unwrap_or(backup_default) && !dry_run {
+                    Some(sources_dir.clone())
+                } else {
+                    None
+                },
+                blue_green: blue_green,
+                install_crds: install_crds,
+            }
         })
         .collect();
 
-    // find which charts are deleted
-    let removed_charts = deployed_charts.into_iter().filter(|chart| {
-        !target_charts.iter().any(|c| c.release == chart.release)
-    });
+    // the charts are the complete desired state unless `sync: false`, so anything deployed but
+    // not listed gets pruned, as it always was. puts deploying only some of it, canaries beside
+    // their primaries or no charts at all for tests and teardowns, only prune when asked to
+    let prune_by_default = !target_charts.is_empty() && (canary.is_none() || promote);
+    let sync = match in_request.params.sync {
+        Some(true) if helm.namespace().is_none() =>
+            panic!("`sync` needs a `source.namespace`, it would delete the releases of every other namespace!"),
+        Some(sync) => sync,
+        // without a namespace, the releases not listed could be any other namespace's
+        None => prune_by_default && helm.namespace().is_some(),
+    };
+    let unlisted: Releases = deployed_releases.into_iter().filter(|release| {
+        // the color a blue-green chart routed to before stays to route back to.
+        // only our own cluster is synced
+        !target_charts.iter().any(|t| t.target.is_none() && (t.chart.release == release.name
+            || t.blue_green.as_ref().and_then(|b| b.previous.as_ref()) == Some(&release.name)))
+    }).collect();
+    let removed_releases: Releases = if sync && !dry_run {
+        unlisted
+    } else {
+        if !sync && in_request.params.sync.is_none() && prune_by_default && !unlisted.is_empty() {
+            let names: Vec<&str> = unlisted.iter().map(|release| &release.name as &str).collect();
+            logger::warn(format_args!("Not pruning `{}`, which aren't listed, without a `source.namespace`. \
+                Set `sync: false` to leave them be on purpose.", names.join("`, `")));
+        }
+        Vec::new()
+    };
 
     // run upgrade for added, changed and unchanged charts.
     // this is because its hard to know what overrides were used
     // during the initial install, and what the current version is,
     // e.g. is it 'latest'?
     // upgrading a chart that is not installed will install it.
-    for to_install in &target_charts {
-        helm.upgrade(to_install).unwrap();
+    // dependencies go first, each wave waiting for the one before it
+    // keep the values deep-merged charts resolve to, secrets masked, for the record
+    let mut resolved_values: HashMap<String, String> = HashMap::new();
+    for target in target_charts.iter().filter(|t| t.chart.merge_strategy == Some(MergeStrategy::Deep)) {
+        let values = target.helm.resolved_values(&target.chart).unwrap();
+        let values_path = format!("{}/{}.yaml", VALUES_DIR, target.chart.release);
+        fs::create_dir_all(sources_dir.join(VALUES_DIR)).unwrap();
+        File::create(sources_dir.join(&values_path)).unwrap()
+            .write_all(serde_yaml::to_string(&values).unwrap().as_bytes()).unwrap();
+        resolved_values.insert(target.chart.release.clone(), values_path);
+    }
+
+    let waves = deploy_order(target_charts).unwrap_or_else(|e| panic!("{}", e));
+    let mut upgrades: Vec<UpgradeResult> = Vec::new();
+    // set once a chart fails, unless the rest should go on regardless
+    let halted = Arc::new(AtomicBool::new(false));
+    for wave in waves {
+        // don't deploy anything on top of a dependency that failed
+        let (blocked, wave): (Vec<Target>, Vec<Target>) = wave.into_iter().partition(|target| {
+            target.depends_on.iter().any(|dependency| upgrades.iter()
+                .any(|u| &u.release == dependency && u.outcome != Outcome::Succeeded))
+        });
+        for target in blocked {
+            let result = UpgradeResult::skipped(&target, "a release it depends on failed");
+            log_result(&result, Duration::from_secs(0));
+            upgrades.push(result);
+        }
+        upgrades.extend(upgrade_all(wave, max_in_flight, &halted, on_failure != OnFailure::Continue));
+    }
+    for upgrade in &mut upgrades {
+        upgrade.values = resolved_values.remove(&upgrade.release);
+    }
+
+    // all or nothing: take back what did deploy
+    let failed = upgrades.iter().filter(|u| u.outcome == Outcome::Failed).count();
+    if failed > 0 && on_failure == OnFailure::RollbackAll && !dry_run {
+        for upgrade in upgrades.iter_mut().filter(|u| u.outcome == Outcome::Succeeded && !u.unchanged) {
+            match helm_of(&helm, &clusters, &upgrade.target).undo(&upgrade.release) {
+                Ok(()) => upgrade.rolled_back = true,
+                Err(e) => logger::error(format_args!("Rolling back `{}` failed: {}", upgrade.release, e)),
+            }
+        }
+    }
+
+    // every failure was logged as it happened, this is the overview
+    log_summary(&upgrades);
+
+    // how long the deploy took so far, kept for failed puts too
+    let metrics = Metrics::new(&helm, &clusters, configure_ms, started);
+    File::create(sources_dir.join(METRICS_FILE)).unwrap()
+        .write_all(&serde_json::to_vec(&metrics).unwrap()).unwrap();
+
+    // whatever is told about the put hears about failures too
+    if notifier.is_some() || in_request.params.summary.unwrap_or(false) {
+        let summary = serde_json::to_vec(&Summary::new(&helm, &upgrades, dry_run)).unwrap();
+        if in_request.params.summary.unwrap_or(false) {
+            File::create(sources_dir.join(SUMMARY_FILE)).unwrap().write_all(&summary).unwrap();
+        }
+        if let Some(ref notifier) = notifier {
+            // a webhook that's down is no reason to fail a deploy that worked
+            if let Err(e) = notifier.send(&summary) {
+                logger::warn(format_args!("Could not send the summary: {}", e));
+            }
+        }
+    }
+    if failed > 0 {
+        panic!("{} of the charts failed to upgrade!", failed);
     }
 
-    for deleted in removed_charts {
-        helm.delete(&deleted.release).unwrap();
+    // keep what was applied to the cluster next to the build's sources
+    if !dry_run {
+        let manifests_dir = sources_dir.join(MANIFESTS_DIR);
+        fs::create_dir_all(&manifests_dir).unwrap();
+
+        for upgrade in &mut upgrades {
+            let chart_helm = helm_of(&helm, &clusters, &upgrade.target);
+            // releases on targets may share names with ours, so they get a directory each
+            let name = match upgrade.target {
+                Some(ref target) => format!("{}/{}", target, upgrade.release),
+                None => upgrade.release.clone(),
+            };
+
+            let manifest = chart_helm.manifest(&upgrade.release).unwrap();
+            let manifest_path = format!("{}/{}.yaml", MANIFESTS_DIR, name);
+            fs::create_dir_all(sources_dir.join(&manifest_path).parent().unwrap()).unwrap();
+            File::create(sources_dir.join(&manifest_path)).unwrap()
+                .write_all(manifest.as_bytes()).unwrap();
+            upgrade.manifest = Some(manifest_path);
+
+            // the notes tend to say where to find what was just deployed, but they're no reason to fail
+            match chart_helm.notes(&upgrade.release) {
+                Ok(Some(notes)) => {
+                    let notes_path = sources_dir.join(format!("{}/{}.txt", NOTES_DIR, name));
+                    fs::create_dir_all(notes_path.parent().unwrap()).unwrap();
+                    File::create(notes_path).unwrap()
+                        .write_all(notes.as_bytes()).unwrap();
+                    upgrade.notes = Some(notes);
+                },
+                Ok(None) => (),
+                Err(e) => logger::warn(format_args!("Couldn't get the notes of `{}`: {}", upgrade.release, e)),
+            }
+        }
+    }
+
+    // test already deployed releases
+    let tests: Vec<TestResult> = in_request.params.tests.take().unwrap_or_default()
+        .into_iter()
+        .filter(|_| !dry_run)
+        .map(|release| {
+            let result = helm.test(&release);
+            TestResult {
+                release: release,
+                passed: result.is_ok(),
+                error: result.err().map(|e| e.to_string()),
+            }
+        })
+        .collect();
+
+    let failures: Vec<&TestResult> = tests.iter().filter(|t| !t.passed).collect();
+    if !failures.is_empty() {
+        for failure in &failures {
+            logger::error(format_args!("Testing `{}` failed: {}",
+                failure.release, failure.error.as_ref().unwrap()));
+        }
+        panic!("{} of the releases failed their tests!", failures.len());
+    }
+
+    // how releases are deleted, e.g. whether they're purged. Re-running a teardown is fine
+    let delete_options = in_request.params.delete_options.take().unwrap_or_default();
+
+    for deleted in removed_releases {
+        // pruning never touches protected releases, but says so
+        if helm.is_protected(&deleted.name) {
+            logger::info(format_args!("Not pruning protected release `{}`.", deleted.name));
+            continue;
+        }
+        helm.delete(&deleted.name, &delete_options).unwrap();
+    }
+
+    // tear down the releases we were asked to, e.g. ephemeral environments
+    let deletes: Vec<DeleteResult> = in_request.params.deletes.take().unwrap_or_default()
+        .into_iter()
+        .filter(|release| if dry_run {
+            logger::info(format_args!("Not deleting `{}` in a dry run.", release));
+            false
+        } else {
+            true
+        })
+        .map(|release| {
+            let release = build_metadata.interpolate(&release);
+            let result = helm.delete(&release, &delete_options);
+            DeleteResult {
+                release: release,
+                deleted: result.is_ok(),
+                already_absent: result.as_ref().map(|deleted| !deleted).unwrap_or(false),
+                error: result.err().map(|e| e.to_string()),
+            }
+        })
+        .collect();
+
+    let failures: Vec<&DeleteResult> = deletes.iter().filter(|d| !d.deleted).collect();
+    if !failures.is_empty() {
+        for failure in &failures {
+            logger::error(format_args!("Deleting `{}` failed: {}",
+                failure.release, failure.error.as_ref().unwrap()));
+        }
+        panic!("{} of the releases failed to delete!", failures.len());
     }
 
     // send back a response
-    // get the list of deployed charts
-    let deployed_charts = helm.list().unwrap();
+    // get the list of deployed releases
+    let deployed_releases = helm.list().unwrap();
 
-    // get the digest
-    let digest = helm.digest().unwrap();
+    // the tests and deletes took time too
+    let metrics = Metrics::new(&helm, &clusters, configure_ms, started);
+    File::create(sources_dir.join(METRICS_FILE)).unwrap()
+        .write_all(&serde_json::to_vec(&metrics).unwrap()).unwrap();
 
     // reply with a message
     let response = OutResponse {
-        version: Version {
-            digest: digest,
+        version: current_version(&helm, &clusters),
+        metadata: OutMetadata {
+            releases: deployed_releases,
+            upgrades: upgrades,
+            tests: tests,
+            published: published,
+            collected: collected,
+            deletes: deletes,
+            dry_run: dry_run,
+            metrics: metrics,
         },
-        metadata: deployed_charts,
     };
     concourse_api::send_message(&response).unwrap();
 }
 
+// the helm of the cluster a chart went to, ours unless it picked a target
+fn helm_of(helm: &Arc<Helm>, clusters: &Clusters, target: &Option<String>) -> Arc<Helm> {
+    target.as_ref().and_then(|target| clusters.get(target)).unwrap_or(helm).clone()
+}
+
+/// Group the charts into waves to deploy one after the other, so that
+/// every chart comes after the charts it depends on.
+fn deploy_order(mut charts: Vec<Target>) -> Result<Vec<Vec<Target>>, String> {
+    let mut waves = Vec::new();
+    while !charts.is_empty() {
+        // releases outside of this put are assumed to be deployed already
+        let pending: Vec<String> = charts.iter().map(|t| t.chart.release.clone()).collect();
+        let (ready, waiting): (Vec<Target>, Vec<Target>) = charts.into_iter()
+            .partition(|t| !t.depends_on.iter().any(|dependency| pending.contains(dependency)));
+        if ready.is_empty() {
+            let releases: Vec<String> = waiting.iter().map(|t| format!("`{}`", t.chart.release)).collect();
+            return Err(format!("The charts {} depend on each other in a cycle!", releases.join(", ")));
+        }
+        waves.push(ready);
+        charts = waiting;
+    }
+    Ok(waves)
+}
+
+/// Upgrade every chart, running at most `max_in_flight` upgrades at once,
+/// and report how each one went.
+/// Once `halted` is set no more charts are started, and with `halt_on_failure`
+/// any failure sets it.
+fn upgrade_all(charts: Vec<Target>, max_in_flight: usize, halted: &Arc<AtomicBool>, halt_on_failure: bool)
+    -> Vec<UpgradeResult>
+{
+    let queue = Arc::new(Mutex::new(charts.into_iter()));
+    let results = Arc::new(Mutex::new(Vec::new()));
+
+    let workers: Vec<_> = (0..max_in_flight.max(1)).map(|_| {
+        let queue = queue.clone();
+        let results = results.clone();
+        let halted = halted.clone();
+
+        thread::spawn(move || loop {
+            let target = match queue.lock().unwrap().next() {
+                Some(target) => target,
+                None => break,
+            };
+            if halted.load(Ordering::SeqCst) {
+                let result = UpgradeResult::skipped(&target, "an earlier chart failed");
+                log_result(&result, Duration::from_secs(0));
+                results.lock().unwrap().push(result);
+                continue;
+            }
+            let started = Instant::now();
+            let helm = &target.helm;
+            let mut result = UpgradeResult::new(&target);
+            // held until we're done with the release, any rollback included
+            let held = match target.lock {
+                Some(ref lock) => helm.lock_release(&target.chart, &lock.holder, lock.ttl, lock.mode).map(Some),
+                None => Ok(None),
+            };
+            let deployed = match held {
+                Ok(_) => deploy(helm, &target, &mut result),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = deployed {
+                // a release that ran out of time may be left half upgraded
+                if let (&HelmError::DeadlineExceeded(ref release), true) = (&e, target.rollback_on_deadline) {
+                    match helm.rollback(release) {
+                        Ok(()) => result.rolled_back = true,
+                        Err(e) => logger::error(format_args!("Rolling back `{}` failed: {}", release, e)),
+                    }
+                }
+                result.outcome = Outcome::Failed;
+                result.error = Some(e.to_string());
+                if halt_on_failure {
+                    halted.store(true, Ordering::SeqCst);
+                }
+            }
+            result.duration_ms = logger::millis(started.elapsed());
+            log_result(&result, started.elapsed());
+            results.lock().unwrap().push(result);
+        })
+    }).collect();
+
+    for worker in workers {
+        worker.join().unwrap();
+    }
+
+    let mut results = results.lock().unwrap();
+    results.drain(..).collect()
+}
+
+/// Lint, diff, upgrade, verify and test one chart, recording how far it got.
+/// Whatever fails once the chart's deadline has passed, failed for missing it.
+fn deploy(helm: &Helm, target: &Target, result: &mut UpgradeResult) -> Result<(), HelmError> {
+    let deadline = target.deadline.map(|deadline| Instant::now() + deadline);
+    match deploy_before(helm, target, result, deadline) {
+        Err(_) if deadline.map(|deadline| Instant::now() >= deadline).unwrap_or(false) =>
+            Err(HelmError::DeadlineExceeded(target.chart.release.clone())),
+        outcome => outcome,
+    }
+}
+
+fn deploy_before(helm: &Helm, target: &Target, result: &mut UpgradeResult, deadline: Option<Instant>)
+    -> Result<(), HelmError>
+{
+    // each step only starts while there's time left
+    let in_time = || match deadline {
+        Some(deadline) if Instant::now() >= deadline =>
+            Err(HelmError::DeadlineExceeded(target.chart.release.clone())),
+        _ => Ok(()),
+    };
+
+    // pin version ranges like `^1.2` to what they match right now
+    let chart = try!(helm.resolve_version(&target.chart));
+    result.version = chart.version.clone();
+    let release = &chart.release;
+
+    // local charts have to pass lint before we try deploying them
+    if let Some(ref path) = chart.path {
+        try!(in_time());
+        try!(helm.lint(path, target.lint_strict));
+    }
+
+    if target.show_diff || target.fail_on_diff {
+        try!(in_time());
+        let changed = !try!(helm.diff(&chart)).is_empty();
+        result.changed = Some(changed);
+        if changed && target.fail_on_diff {
+            return Err(HelmError::DiffDetected(release.clone()));
+        }
+    }
+
+    // don't pile up identical revisions of releases that are already as requested
+    let unchanged = match target.deployed {
+        Some(ref deployed) if target.skip_unchanged => try!(helm.unchanged(&chart, deployed, &target.options)),
+        _ => false,
+    };
+
+    if unchanged {
+        logger::info(format_args!("Release `{}` is unchanged, not upgrading it.", release));
+        result.unchanged = true;
+    } else {
+        // a backup that can't be taken is no backup to restore, so don't upgrade without one
+        if let (&Some(ref sources_dir), &Some(ref deployed)) = (&target.backup_to, &target.deployed) {
+            result.backup = Some(try!(backup(helm, sources_dir, deployed)));
+        }
+        if let Some(timeout) = target.install_crds {
+            try!(in_time());
+            try!(helm.install_crds(&chart, timeout));
+        }
+
+        try!(in_time());
+        let mut options = target.options.clone();
+        options.deadline = deadline;
+        try!(helm.upgrade(&chart, &options));
+        // helm only returns successfully from --wait once everything is ready
+        result.waited = target.options.wait;
+        result.forced = target.options.force;
+        result.recreated_pods = target.options.recreate_pods;
+
+        // unpinned charts get whatever is latest, so find out what that was
+        if chart.version.is_none() && !target.options.dry_run {
+            if let Some(deployed) = try!(helm.release_version(release)) {
+                logger::info(format_args!("Deployed `{}` at chart version `{}`, app version `{}`.",
+                    release,
+                    deployed.version.as_ref().map(|v| v as &str).unwrap_or("unknown"),
+                    deployed.app_version.as_ref().map(|v| v as &str).unwrap_or("unknown")));
+                result.version = deployed.version;
+                result.app_version = deployed.app_version;
+            }
+        }
+    }
+
+    // what the chart says about itself is only for the metadata, so it may fail or be skipped
+    // once the deployed release has told us already or there's no time left for it
+    if result.app_version.is_none() && in_time().is_ok() {
+        match helm.inspect(&chart, None) {
+            Ok(info) => {
+                result.app_version = info.app_version;
+                result.description = info.description;
+            },
+            Err(e) => logger::warn(format_args!("Could not inspect the chart of `{}`: {}", release, e)),
+        }
+    }
+
+    if let Some(timeout) = target.verify_rollout {
+        try!(in_time());
+        // waiting for the rollout can't outlast the deadline either
+        let now = Instant::now();
+        let timeout = match deadline {
+            Some(deadline) if deadline > now => timeout.min(deadline - now),
+            Some(_) => Duration::from_secs(0),
+            None => timeout,
+        };
+        try!(helm.verify_rollout(release, timeout));
+        result.verified = true;
+    }
+
+    if target.test {
+        try!(in_time());
+        try!(helm.test(release));
+        result.tested = true;
+    }
+
+    // only a color that made it this far takes the traffic
+    if let Some(ref blue_green) = target.blue_green {
+        if !target.options.dry_run {
+            try!(in_time());
+            try!(helm.select_service(&chart, &blue_green.service, &blue_green.label, blue_green.color));
+            result.routed = Some(blue_green.color.to_string());
+            result.previous = blue_green.previous.clone();
+        }
+    }
+
+    Ok(())
+}
+
+// log how a chart's deploy went, with every detail for json logs
+fn log_result(result: &UpgradeResult, elapsed: Duration) {
+    let fields = vec![
+        ("release", Value::String(result.release.clone())),
+        ("unchanged", Value::Bool(result.unchanged)),
+        ("waited", Value::Bool(result.waited)),
+        ("verified", Value::Bool(result.verified)),
+        ("tested", Value::Bool(result.tested)),
+        ("duration_ms", Value::U64(logger::millis(elapsed))),
+    ];
+    match (result.outcome, &result.error) {
+        (Outcome::Skipped, error) => logger::event(Level::Warn,
+            format_args!("Skipped `{}`: {}", result.release, error.as_ref().map(|e| e as &str).unwrap_or("")),
+            fields),
+        (_, &Some(ref error)) => logger::event(Level::Error,
+            format_args!("Upgrading `{}` failed: {}", result.release, error), fields),
+        (_, &None) => logger::event(Level::Info,
+            format_args!("Deployed `{}` in {}.", result.release, logger::duration(elapsed)), fields),
+    }
+}
+
+// log a table of how every chart went, e.g. `web  succeeded  1.2.3`
+fn log_summary(upgrades: &[UpgradeResult]) {
+    if upgrades.is_empty() {
+        return;
+    }
+    let width = upgrades.iter().map(|u| u.release.len()).max().unwrap_or(0);
+    let rows: Vec<String> = upgrades.iter().map(|upgrade| {
+        let mut outcome = upgrade.outcome.to_string();
+        if upgrade.rolled_back {
+            outcome.push_str(", rolled back");
+        }
+        format!("{:width$}  {:24}  {}", upgrade.release, outcome,
+            upgrade.error.as_ref().or(upgrade.version.as_ref()).map(|s| s as &str).unwrap_or(""),
+            width = width)
+    }).collect();
+
+    let results = upgrades.iter().map(|upgrade| {
+        let mut result = serde_json::Map::new();
+        result.insert("release".to_string(), Value::String(upgrade.release.clone()));
+        result.insert("outcome".to_string(), Value::String(upgrade.outcome.to_string()));
+        result.insert("rolled_back".to_string(), Value::Bool(upgrade.rolled_back));
+        Value::Object(result)
+    }).collect();
+    logger::event(Level::Info, format_args!("Summary:\n{}", rows.join("\n")),
+        vec![("results", Value::Array(results))]);
+}
 
 #[derive(Deserialize)]
 struct ChartSpec {
     name: String,
     version: Option<String>,
     overrides: Option<HashMap<String, Value>>,
+    install: Option<bool>,
+    wait: Option<bool>,
+    timeout: Option<u64>,
+    /// skip the chart's hooks, e.g. to recover from a broken one
+    no_hooks: Option<bool>,
+    /// seconds any one hook job may run before the upgrade is given up on
+    hook_timeout: Option<u64>,
+    /// follow the hook jobs, with their logs, and fail on the first that fails, e.g. a migration
+    watch_hooks: Option<bool>,
+    /// keep the values of the last release, only applying the overrides on top
+    reuse_values: Option<bool>,
+    reset_values: Option<bool>,
+    /// what `helm history` shows for the revision, the build's pipeline, job and number by default
+    description: Option<String>,
+    force: Option<bool>,
+    recreate_pods: Option<bool>,
+    /// `rollback`, `delete` or `fail` when a crashed deploy left the release failed or pending
+    recover: Option<Recover>,
+    /// wait for the deployments and statefulsets to be ready, which `blue_green` charts always do
+    verify_rollout: Option<bool>,
+    verify_timeout: Option<u64>,
+    test: Option<bool>,
+    path: Option<String>,
+    /// the repo to take the chart from, e.g. one of `source.repos`, `stable` by default
+    repo: Option<String>,
+    lint_strict: Option<bool>,
+    post_renderer: Option<PostRendererSpec>,
+    /// values files relative to the sources
+    values_files: Option<Vec<String>>,
+    /// JSON values files relative to the sources, applied after `values_files`
+    values_json_files: Option<Vec<String>>,
+    /// a JSON object of values, as a string
+    values_json: Option<String>,
+    secret_values_files: Option<Vec<String>>,
+    /// dotted paths of overrides to print as `***`
+    secret_keys: Option<Vec<String>>,
+    /// a mustache values file relative to the sources, e.g. written by an earlier job
+    values_template: Option<String>,
+    /// what to fill the values template's `{{placeholders}}` with
+    template_vars: Option<HashMap<String, String>>,
+    /// fail unless the chart's provenance checks out against `source.keyring`
+    verify: Option<bool>,
+    /// deploy prerelease chart versions too
+    devel: Option<bool>,
+    /// `deep` to merge the values sources ourselves instead of leaving it to helm
+    merge_strategy: Option<MergeStrategy>,
+    /// where to deploy the release, required when the source has no namespace
+    namespace: Option<String>,
+    /// which of `source.targets` to deploy to, the source's own cluster by default
+    target: Option<String>,
+    /// releases in the same put to deploy before this one
+    depends_on: Option<Vec<String>>,
+    /// helm flags to add to this chart's upgrade, after `source.extra_args`
+    extra_args: Option<Vec<String>>,
+    /// seconds the chart's whole deploy may take, from fetching it to verifying the rollout
+    deadline_seconds: Option<u64>,
+    /// roll the release back when it misses its deadline
+    rollback_on_deadline: Option<bool>,
+    /// keep the release's values and manifest in `backup/` before upgrading it
+    backup: Option<bool>,
+    /// `blue_green` to deploy `<release>-blue` and `<release>-green` by turns, upgrading in place by default
+    strategy: Option<Strategy>,
+    /// the service a blue-green deploy routes, named like the release by default
+    service: Option<String>,
+    /// the pod label the service selects a color by, `color` by default
+    color_label: Option<String>,
+    /// the value the chart labels its pods with the color from, `color_label` by default
+    color_key: Option<String>,
+    /// don't install the chart's CRDs at all, helm 3 only
+    skip_crds: Option<bool>,
+    /// apply the chart's CRDs and wait for them to be established before upgrading, helm 3 only
+    install_crds_first: Option<bool>,
+    /// seconds to wait for CRDs installed first to be established, the upgrade's `timeout` by default
+    crd_timeout: Option<u64>,
+}
+
+/// What an environment changes about the charts, by release.
+type Environment = HashMap<String, EnvironmentChart>;
+
+#[derive(Deserialize)]
+struct EnvironmentChart {
+    /// more values files relative to the sources, applied after the chart's own
+    values_files: Option<Vec<String>>,
+    /// merged into the chart's overrides, winning over them
+    overrides: Option<HashMap<String, Value>>,
+}
+
+#[derive(Deserialize)]
+struct PostRendererSpec {
+    /// executable relative to the build's sources
+    path: String,
+    #[serde(default)]
+    args: Vec<String>,
+}
+
+impl ChartSpec {
+    /// Layer an environment's values on top of the chart's own.
+    fn apply_environment(&mut self, environment: EnvironmentChart) {
+        if let Some(values_files) = environment.values_files {
+            self.values_files.get_or_insert_with(Vec::new).extend(values_files);
+        }
+        if let Some(changes) = environment.overrides {
+            let overrides = self.overrides.get_or_insert_with(HashMap::new);
+            for (key, value) in changes {
+                if overrides.contains_key(&key) {
+                    values::merge(overrides.get_mut(&key).unwrap(), value);
+                } else {
+                    overrides.insert(key, value);
+                }
+            }
+        }
+    }
+
+    fn upgrade_options(&self, defaults: &UpgradeOptions) -> UpgradeOptions {
+        UpgradeOptions {
+            install: self.install.unwrap_or(defaults.install),
+            wait: self.wait.unwrap_or(defaults.wait),
+            timeout: self.timeout.or(defaults.timeout),
+            dry_run: defaults.dry_run,
+            post_renderer: None,
+            no_hooks: self.no_hooks.unwrap_or(defaults.no_hooks),
+            hook_timeout: self.hook_timeout.or(defaults.hook_timeout),
+            watch_hooks: self.watch_hooks.unwrap_or(defaults.watch_hooks),
+            reuse_values: self.reuse_values.unwrap_or(defaults.reuse_values),
+            reset_values: self.reset_values.unwrap_or(defaults.reset_values),
+            description: None,
+            force: self.force.unwrap_or(defaults.force),
+            recreate_pods: self.recreate_pods.unwrap_or(defaults.recreate_pods),
+            // CRDs installed first are already there for helm to skip
+            skip_crds: self.skip_crds.unwrap_or(defaults.skip_crds) || self.install_crds_first.unwrap_or(false),
+            recover: self.recover.or(defaults.recover),
+            extra_args: self.extra_args.clone().unwrap_or_else(|| defaults.extra_args.clone()),
+            deadline: None,
+        }
+    }
+}
+
+#[derive(Default, Deserialize)]
+struct InParams {
+    /// download the archives of the deployed charts into the destination
+    fetch_charts: Option<bool>,
+    /// the repo to fetch them from, `stable` by default
+    fetch_repo: Option<String>,
+    /// write what each release's manifests changed since the given revision
+    diff_against: Option<DiffAgainst>,
+    /// write every revision of each release
+    include_history: Option<bool>,
+}
+
+/// The revision of a release the `in` step diffs its manifests against.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+enum DiffAgainst {
+    /// the one before the latest
+    #[serde(rename = "previous")]
+    Previous,
 }
 
 #[derive(Deserialize)]
 struct Params {
+    #[serde(default)]
     charts: HashMap<String, ChartSpec>,
+    /// a YAML file of more charts, relative to the sources
+    charts_file: Option<String>,
+    /// how the charts differ per environment, on top of any in the charts file
+    environments: Option<HashMap<String, Environment>>,
+    /// which of the environments to deploy to
+    environment: Option<String>,
+    /// releases to run `helm test` against without deploying anything
+    tests: Option<Vec<String>>,
+    max_in_flight: Option<usize>,
+    /// prune the releases that aren't listed, which puts with charts and a `source.namespace` do by default
+    sync: Option<bool>,
+    dry_run: Option<bool>,
+    show_diff: Option<bool>,
+    fail_on_diff: Option<bool>,
+    skip_unchanged: Option<bool>,
+    delete_options: Option<DeleteOptions>,
+    /// releases to delete, with or without any charts to deploy
+    deletes: Option<Vec<String>>,
+    /// `fail_fast` (the default), `continue` or `rollback_all` when a chart fails
+    on_failure: Option<OnFailure>,
+    /// a chart to package and upload to `source.chartmuseum`
+    publish: Option<PublishSpec>,
+    /// purge every release in the namespace whose last deploy failed
+    gc_failed: Option<bool>,
+    /// expand `$VAR` and `${VAR}` in release names and values from the step's environment
+    expand_env: Option<bool>,
+    /// `wait` for or `fail` on other deploys of the same release, nothing is locked by default
+    lock: Option<LockMode>,
+    /// how long a lock lasts should the build holding it die
+    lock_ttl_seconds: Option<u64>,
+    /// set `global.provenance` in every chart to annotations naming this build
+    provenance: Option<bool>,
+    /// a file holding the commit being deployed, e.g. `repo/.git/ref`, for the provenance
+    git_ref_file: Option<String>,
+    /// default for every chart's `backup`
+    backup: Option<bool>,
+    /// deploy the charts as scaled down canaries beside their primary releases
+    canary: Option<CanarySpec>,
+    /// deploy the charts to their primary releases and delete their canaries
+    promote: Option<bool>,
+    /// write how the put went to `summary.json`, for a notification resource
+    summary: Option<bool>,
+}
+
+/// How canaries are named and scaled, with `promote` only how they're named.
+#[derive(Deserialize)]
+struct CanarySpec {
+    /// appended to each release's name, `-canary` by default
+    suffix: Option<String>,
+    /// how many replicas a canary runs, 1 by default
+    replicas: Option<u64>,
+    /// the dotted path of the charts' replica count, `replicaCount` by default
+    replicas_key: Option<String>,
+}
+
+/// What happens to the other charts of a put when one fails.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+enum OnFailure {
+    /// don't start any more charts
+    #[serde(rename = "fail_fast")]
+    FailFast,
+    /// deploy everything that doesn't depend on the failed chart
+    #[serde(rename = "continue")]
+    Continue,
+    /// stop like `fail_fast`, then undo the charts that were upgraded
+    #[serde(rename = "rollback_all")]
+    RollbackAll,
+}
+
+#[derive(Deserialize)]
+struct PublishSpec {
+    /// the chart directory, relative to the sources
+    chart: String,
+    /// override the chart's version, e.g. `1.0.{{build_id}}`
+    version: Option<String>,
+    app_version: Option<String>,
+    /// push to one of `source.registries` instead of chartmuseum
+    oci: Option<bool>,
+    /// the host of the registry to push to, the first one by default
+    registry: Option<String>,
 }
 
+/// A chart to deploy and what to do around its upgrade.
+struct Target {
+    chart: Chart,
+    /// which of `source.targets` the chart goes to, if not our own cluster
+    target: Option<String>,
+    /// helm for the cluster the chart goes to
+    helm: Arc<Helm>,
+    options: UpgradeOptions,
+    /// how long to wait for the release's workloads to become ready, if at all
+    verify_rollout: Option<Duration>,
+    /// whether to run `helm test` once the release is up
+    test: bool,
+    /// whether lint warnings on a local chart count as failures
+    lint_strict: bool,
+    /// whether to print what the upgrade will change first
+    show_diff: bool,
+    /// whether any change at all fails the chart instead of being applied
+    fail_on_diff: bool,
+    /// whether to skip the upgrade if it would be a no-op
+    skip_unchanged: bool,
+    /// how the release is currently deployed, if it is
+    deployed: Option<Release>,
+    /// releases that have to be deployed first
+    depends_on: Vec<String>,
+    /// how long the whole deploy may take
+    deadline: Option<Duration>,
+    /// whether to roll back a release that misses its deadline
+    rollback_on_deadline: bool,
+    /// how to lock the release while deploying it, if at all
+    lock: Option<ReleaseLockSpec>,
+    /// the sources to back the deployed release up in before upgrading it, if at all
+    backup_to: Option<PathBuf>,
+    /// how long to wait for the chart's CRDs, if they're installed ahead of it
+    install_crds: Option<Duration>,
+    /// which color this is and what to route to it once it's up, for blue-green charts
+    blue_green: Option<BlueGreen>,
+}
+
+/// How a chart is deployed, when not by upgrading its release in place.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+enum Strategy {
+    /// deploy the color the service doesn't route to, then route it there
+    #[serde(rename = "blue_green")]
+    BlueGreen,
+}
+
+/// The color a blue-green chart is deployed as, and the service to route to it.
+#[derive(Clone)]
+struct BlueGreen {
+    service: String,
+    label: String,
+    color: &'static str,
+    /// the release of the color routed to until now, kept around to route back to
+    previous: Option<String>,
+}
+
+/// Who locks a release while deploying it, and what to do when it's taken.
+#[derive(Clone)]
+struct ReleaseLockSpec {
+    mode: LockMode,
+    holder: String,
+    ttl: Duration,
+}
+
+/// How a put went, compact enough for a chat message, e.g. from a webhook.
+#[derive(Serialize)]
+struct Summary {
+    /// the apiserver deployed to
+    cluster: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    namespace: Option<String>,
+    /// `failed` if any chart did
+    result: Outcome,
+    dry_run: bool,
+    charts: Vec<ChartSummary>,
+}
+
+#[derive(Serialize)]
+struct ChartSummary {
+    release: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    namespace: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    app_version: Option<String>,
+    /// the release's revision once the put was done with it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    revision: Option<u64>,
+    outcome: Outcome,
+    duration_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl Summary {
+    fn new(helm: &Helm, upgrades: &[UpgradeResult], dry_run: bool) -> Self {
+        // the revisions are only for the record, so they may go missing
+        let deployed = helm.list().unwrap_or_else(|e| {
+            logger::warn(format_args!("Could not list the releases for the summary: {}", e));
+            Vec::new()
+        });
+        Summary {
+            cluster: helm.server(),
+            namespace: helm.namespace().map(|namespace| namespace.to_string()),
+            result: if upgrades.iter().any(|u| u.outcome == Outcome::Failed) {
+                Outcome::Failed
+            } else {
+                Outcome::Succeeded
+            },
+            dry_run: dry_run,
+            charts: upgrades.iter().map(|upgrade| ChartSummary {
+                release: upgrade.release.clone(),
+                namespace: upgrade.namespace.clone(),
+                target: upgrade.target.clone(),
+                version: upgrade.version.clone(),
+                app_version: upgrade.app_version.clone(),
+                // only the releases on our own cluster were listed
+                revision: deployed.iter()
+                    .filter(|_| upgrade.target.is_none())
+                    .find(|r| r.name == upgrade.release
+                        && upgrade.namespace.as_ref().map(|n| *n == r.namespace).unwrap_or(true))
+                    .and_then(|r| r.revision),
+                outcome: upgrade.outcome,
+                duration_ms: upgrade.duration_ms,
+                error: upgrade.error.clone(),
+            }).collect(),
+        }
+    }
+}
+
+/// How a chart's deploy ended.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+enum Outcome {
+    #[serde(rename = "succeeded")]
+    Succeeded,
+    #[serde(rename = "failed")]
+    Failed,
+    /// not even tried, because of another chart failing
+    #[serde(rename = "skipped")]
+    Skipped,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Outcome::Succeeded => "succeeded",
+            Outcome::Failed => "failed",
+            Outcome::Skipped => "skipped",
+        })
+    }
+}
+
+#[derive(Serialize)]
+struct UpgradeResult {
+    release: String,
+    /// where the release went, when the source covers every namespace
+    #[serde(skip_serializing_if = "Option::is_none")]
+    namespace: Option<String>,
+    /// which of `source.targets` the release went to, if not the source's own cluster
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target: Option<String>,
+    outcome: Outcome,
+    /// the chart version deployed, with version ranges resolved
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<String>,
+    /// the app version of the chart
+    #[serde(skip_serializing_if = "Option::is_none")]
+    app_version: Option<String>,
+    /// what the chart says it is, from its Chart.yaml
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    /// whether helm waited for the release's resources to become ready
+    waited: bool,
+    /// whether we saw every workload of the release become ready
+    verified: bool,
+    /// whether the release's tests ran and passed
+    tested: bool,
+    /// whether the diff showed changes, if we diffed at all
+    changed: Option<bool>,
+    /// whether the upgrade was skipped because nothing changed
+    unchanged: bool,
+    /// whether resources were replaced with `--force`
+    forced: bool,
+    /// whether every pod was restarted with `--recreate-pods`
+    recreated_pods: bool,
+    /// whether the release was rolled back, after missing its deadline or with `rollback_all`
+    rolled_back: bool,
+    /// where the applied manifests were written, relative to the sources
+    #[serde(skip_serializing_if = "Option::is_none")]
+    manifest: Option<String>,
+    /// where the resolved values were written, relative to the sources, for deep-merged charts
+    #[serde(skip_serializing_if = "Option::is_none")]
+    values: Option<String>,
+    /// the release's rendered NOTES.txt, also written to `notes/<release>.txt`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    notes: Option<String>,
+    /// where what the release ran before the upgrade was backed up, relative to the sources
+    #[serde(skip_serializing_if = "Option::is_none")]
+    backup: Option<String>,
+    /// how long the deploy took, from locking the release to testing it
+    duration_ms: u64,
+    /// the color a blue-green chart's service routes to now
+    #[serde(skip_serializing_if = "Option::is_none")]
+    routed: Option<String>,
+    /// the release of the color it routed to before, to route back to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    previous: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl UpgradeResult {
+    fn new(target: &Target) -> Self {
+        UpgradeResult {
+            release: target.chart.release.clone(),
+            namespace: target.chart.namespace.clone(),
+            target: target.target.clone(),
+            outcome: Outcome::Succeeded,
+            version: None,
+            app_version: None,
+            description: None,
+            waited: false,
+            verified: false,
+            tested: false,
+            changed: None,
+            unchanged: false,
+            forced: false,
+            recreated_pods: false,
+            rolled_back: false,
+            manifest: None,
+            values: None,
+            notes: None,
+            backup: None,
+            duration_ms: 0,
+            routed: None,
+            previous: None,
+            error: None,
+        }
+    }
+
+    /// A chart that wasn't deployed, and why.
+    fn skipped(target: &Target, reason: &str) -> Self {
+        let mut result = UpgradeResult::new(target);
+        result.outcome = Outcome::Skipped;
+        result.error = Some(reason.to_string());
+        result
+    }
+}
+
+#[derive(Serialize)]
+struct TestResult {
+    release: String,
+    passed: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct DeleteResult {
+    release: String,
+    deleted: bool,
+    /// there was nothing to delete, with `ignore_missing`
+    already_absent: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct OutMetadata {
+    releases: Releases,
+    upgrades: Vec<UpgradeResult>,
+    tests: Vec<TestResult>,
+    /// the chart archive published, relative to the sources
+    #[serde(skip_serializing_if = "Option::is_none")]
+    published: Option<String>,
+    /// failed releases that were deleted by `gc_failed`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    collected: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    deletes: Vec<DeleteResult>,
+    /// nothing was actually changed, the upgrades were only rendered
+    dry_run: bool,
+    /// how long the put and each of its phases took, also written to `metrics.json`
+    metrics: Metrics,
+}
+
+/// How long a put took, to track deploy latency and find the slow charts.
+#[derive(Serialize)]
+struct Metrics {
+    /// the whole put, up to when the metrics were taken
+    duration_ms: u64,
+    /// setting up helm and the kube client
+    configure_ms: u64,
+    /// repo updates, fetches, upgrades, verifies and tests, in the order they ended
+    phases: Vec<Timing>,
+}
+
+impl Metrics {
+    fn new(helm: &Helm, clusters: &Clusters, configure_ms: u64, started: Instant) -> Self {
+        let mut phases = helm.timings();
+        for helm in clusters.values() {
+            phases.extend(helm.timings());
+        }
+        Metrics {
+            duration_ms: logger::millis(started.elapsed()),
+            configure_ms: configure_ms,
+            phases: phases,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::env;
+
+    fn version(digest: &str, resource_version: &str) -> Version {
+        Version { digest: digest.to_string(), resource_version: Some(resource_version.to_string()) }
+    }
+
+    // a cursor file of its own for each test, none there yet
+    fn cursor(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("helm-resource-{}-{}", name, process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn checks_watch_from_where_a_change_the_digest_missed_left_them() {
+        let cursor = cursor("missed-change");
+        let previous = version("abc", "10");
+        let digested = Cell::new(0);
+        let check = || check_version(Some(&previous), Some(cursor.as_path()),
+            |rv| rv == "10",
+            || { digested.set(digested.get() + 1); version("abc", "20") },
+            |digest, current| digest == current);
+
+        // something changed, but nothing the digest sees, so the version stays as it was
+        let reported = check();
+        assert_eq!((&reported.digest as &str, reported.resource_version), ("abc", Some("10".to_string())));
+        assert_eq!(digested.get(), 1);
+
+        // and the next check watches from after that change instead of digesting again
+        let reported = check();
+        assert_eq!(reported.resource_version, Some("10".to_string()));
+        assert_eq!(digested.get(), 1);
+        let _ = fs::remove_file(&cursor);
+    }
+
+    #[test]
+    fn checks_without_a_cursor_digest_again_after_a_change_the_digest_missed() {
+        let previous = version("abc", "10");
+        let digested = Cell::new(0);
+        let check = || check_version(Some(&previous), None,
+            |rv| rv == "10",
+            || { digested.set(digested.get() + 1); version("abc", "20") },
+            |digest, current| digest == current);
+
+        check();
+        check();
+        assert_eq!(digested.get(), 2);
+    }
+
+    #[test]
+    fn checks_report_new_digests_and_forget_old_cursors() {
+        let cursor = cursor("new-digest");
+        let previous = version("abc", "10");
+        fs::write(&cursor, r#"{"digest": "abc", "resource_version": "20"}"#).unwrap();
+
+        // nothing changed since the cursor
+        let reported = check_version(Some(&previous), Some(cursor.as_path()), |rv| rv != "20",
+            || panic!("nothing changed, there's nothing to digest"), |digest, current| digest == current);
+        assert_eq!(reported.digest, "abc");
+
+        // a cursor kept for another version isn't watched from
+        let reported = check_version(Some(&version("def", "30")), Some(cursor.as_path()), |rv| rv != "30",
+            || panic!("nothing changed, there's nothing to digest"), |digest, current| digest == current);
+        assert_eq!(reported.digest, "def");
+
+        let reported = check_version(Some(&previous), Some(cursor.as_path()), |_| true,
+            || version("def", "30"), |digest, current| digest == current);
+        assert_eq!((&reported.digest as &str, reported.resource_version), ("def", Some("30".to_string())));
+        let _ = fs::remove_file(&cursor);
+    }
+}
@@ -0,0 +1,258 @@
+use std::collections::HashMap;
+use super::error::HelmError;
+use super::logger::LogFormat;
+use super::secrets::SopsConfig;
+use super::serde_json::{
+    self,
+    Value,
+};
+use super::{
+    is_dns_label,
+    AwsConfig,
+    Chart,
+    Config,
+    DigestAlgorithm,
+    GcpConfig,
+    Impersonate,
+    InstallTiller,
+    ListBackend,
+    MergeStrategy,
+    Plugin,
+    Registry,
+    ReleaseLabels,
+    Repo,
+    StatusFilter,
+    TillerTls,
+    VersionSkew,
+};
+
+
+// a setter for an optional field that takes the value itself or an `Option`
+// of it, so optional settings can be passed straight through
+macro_rules! setter {
+    ($field:ident: $ty:ty) => {
+        pub fn $field<T: Into<Option<$ty>>>(mut self, $field: T) -> Self {
+            self.inner.$field = $field.into();
+            self
+        }
+    };
+}
+
+/// Builds a `Config`, see its fields for what each setting does.
+pub struct ConfigBuilder {
+    inner: Config,
+}
+
+impl ConfigBuilder {
+    pub fn new<'a, T: Into<Option<&'a str>>>(namespace: T) -> Self {
+        ConfigBuilder {
+            inner: Config {
+                namespace: namespace.into().map(|namespace| namespace.to_string()),
+                ..Config::default()
+            },
+        }
+    }
+
+    setter!(url: String);
+    setter!(username: String);
+    setter!(password: String);
+    setter!(token: String);
+    setter!(client_cert: String);
+    setter!(client_key: String);
+    setter!(in_cluster: bool);
+    setter!(kubeconfig: String);
+    setter!(context: String);
+    setter!(cluster: String);
+    setter!(user: String);
+    setter!(skip_tls_verify: bool);
+    setter!(ca_data: String);
+    setter!(ca_file: String);
+    setter!(release: String);
+    setter!(release_prefix: String);
+    setter!(release_suffix: String);
+    setter!(list_backend: ListBackend);
+    setter!(release_selector: HashMap<String, String>);
+    setter!(release_labels: ReleaseLabels);
+    setter!(protected_releases: Vec<String>);
+    setter!(plugins: Vec<Plugin>);
+    setter!(repos: Vec<Repo>);
+    setter!(sops: SopsConfig);
+    setter!(create_namespace: bool);
+    setter!(debug: bool);
+    setter!(log_format: LogFormat);
+    setter!(tiller_namespace: String);
+    setter!(install_tiller: InstallTiller);
+    setter!(tiller_tls: TillerTls);
+    setter!(impersonate: Impersonate);
+    setter!(registries: Vec<Registry>);
+    setter!(history_max: u32);
+    setter!(extra_args: Vec<String>);
+    setter!(env: HashMap<String, String>);
+    setter!(aws: AwsConfig);
+    setter!(gcp: GcpConfig);
+    setter!(api_connect_timeout: u64);
+    setter!(api_timeout: u64);
+    setter!(api_qps: f64);
+    setter!(api_burst: u32);
+    setter!(statuses: Vec<StatusFilter>);
+    setter!(detect_drift: bool);
+    setter!(fast_check: bool);
+    setter!(digest_algorithm: DigestAlgorithm);
+    setter!(keyring: String);
+    setter!(helm_bin: String);
+    setter!(required_version: String);
+    setter!(version_skew: VersionSkew);
+    setter!(skip_repo_update: bool);
+    setter!(cache_dir: String);
+    setter!(repo_cache_ttl: u64);
+
+    /// The config, if it passes `Config::validate`.
+    pub fn build(self) -> Result<Config, HelmError> {
+        try!(self.inner.validate());
+        Ok(self.inner)
+    }
+}
+
+/// Builds a `Chart`, see its fields for what each setting does.
+pub struct ChartBuilder {
+    inner: Chart,
+}
+
+impl ChartBuilder {
+    pub fn new(release: &str, name: &str) -> Self {
+        ChartBuilder {
+            inner: Chart {
+                release: release.to_string(),
+                name: name.to_string(),
+                version: None,
+                overrides: None,
+                path: None,
+                repo: None,
+                values_files: None,
+                values_json_files: None,
+                values_json: None,
+                secret_values_files: None,
+                secret_keys: None,
+                values_template: None,
+                template_vars: None,
+                verify: None,
+                devel: None,
+                merge_strategy: None,
+                namespace: None,
+            },
+        }
+    }
+
+    setter!(version: String);
+    setter!(overrides: HashMap<String, Value>);
+    setter!(path: String);
+    setter!(repo: String);
+    setter!(values_files: Vec<String>);
+    setter!(values_json_files: Vec<String>);
+    setter!(values_json: String);
+    setter!(secret_values_files: Vec<String>);
+    setter!(secret_keys: Vec<String>);
+    setter!(values_template: String);
+    setter!(template_vars: HashMap<String, String>);
+    setter!(verify: bool);
+    setter!(devel: bool);
+    setter!(merge_strategy: MergeStrategy);
+    setter!(namespace: String);
+
+    /// The chart, as long as helm would accept its release and chart names.
+    pub fn build(self) -> Result<Chart, HelmError> {
+        let mut problems = Vec::new();
+
+        // helm 2 release names end up in resource names, so they're DNS-1123 subdomains of at most 53
+        let release = &self.inner.release;
+        if release.is_empty() || release.len() > 53 {
+            problems.push("the release name has to be 1 to 53 characters long".to_string());
+        }
+        if !release.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '.')
+            || release.starts_with(|c: char| !c.is_ascii_alphanumeric())
+            || release.ends_with(|c: char| !c.is_ascii_alphanumeric())
+        {
+            problems.push("the release name may only hold lowercase letters, digits, `-` and `.`".to_string());
+        }
+        if self.inner.name.is_empty() {
+            problems.push("the chart name is empty".to_string());
+        }
+        if let Some(ref repo) = self.inner.repo {
+            if self.inner.path.is_some() {
+                problems.push("the chart comes from either a `repo` or a `path`, not both".to_string());
+            }
+            if repo.is_empty() || repo.contains('/') {
+                problems.push(format!("`{}` is not a repo name", repo));
+            }
+        }
+        if let Some(ref namespace) = self.inner.namespace {
+            if !is_dns_label(namespace) {
+                problems.push(format!("the namespace `{}` is not a valid DNS label", namespace));
+            }
+        }
+        if let Some(ref values_json) = self.inner.values_json {
+            if let Err(e) = serde_json::from_str::<serde_json::Map<String, Value>>(values_json) {
+                problems.push(format!("`values_json` is not a JSON object: {}", e));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(self.inner)
+        } else {
+            Err(HelmError::InvalidChart(self.inner.release, problems))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn release_problems(release: &str) -> Option<Vec<String>> {
+        match Chart::builder(release, "nginx").build() {
+            Ok(_) => None,
+            Err(HelmError::InvalidChart(_, problems)) => Some(problems),
+            Err(e) => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn accepts_dns_release_names() {
+        for release in &["web", "web-1", "api.v2", "a"] {
+            assert_eq!(release_problems(release), None, "`{}`", release);
+        }
+        assert_eq!(release_problems(&"a".repeat(53)), None);
+    }
+
+    #[test]
+    fn rejects_other_release_names() {
+        for release in &["", "Web", "-web", "web-", "web_1", "web 1"] {
+            assert!(release_problems(release).is_some(), "`{}`", release);
+        }
+        assert!(release_problems(&"a".repeat(54)).is_some());
+    }
+
+    #[test]
+    fn checks_the_rest_of_the_chart() {
+        let err = Chart::builder("web", "")
+            .namespace("Prod".to_string())
+            .values_json("[1, 2]".to_string())
+            .build();
+        match err {
+            Err(HelmError::InvalidChart(release, problems)) => {
+                assert_eq!(release, "web");
+                assert_eq!(problems.len(), 3, "{:?}", problems);
+            },
+            other => panic!("expected an invalid chart, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn config_takes_plain_http_only_without_tls_verification() {
+        let config = || Config::builder("prod")
+            .url("http://127.0.0.1:8080".to_string())
+            .token("token".to_string());
+        assert!(config().build().is_err());
+        assert!(config().skip_tls_verify(true).build().is_ok());
+    }
+}
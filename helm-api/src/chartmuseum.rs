@@ -0,0 +1,69 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use super::base64;
+use super::error::HelmError;
+use super::http;
+use super::mktemp::Temp;
+use super::secrets;
+
+
+/// Where packaged charts are published and how to log in there.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ChartMuseumConfig {
+    /// e.g. `https://charts.example.com`
+    pub url: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// PEM encoded CA to trust for chartmuseum's certificate
+    pub ca_data: Option<String>,
+}
+
+/// A client for a ChartMuseum's upload api.
+pub struct ChartMuseum {
+    url: String,
+    username: Option<String>,
+    password: Option<String>,
+    ca_cert: Option<Temp>,
+}
+
+impl ChartMuseum {
+    pub fn new(config: ChartMuseumConfig) -> Result<Self, HelmError> {
+        let ca_cert = match config.ca_data {
+            Some(ref ca_data) => Some(try!(secrets::private_file(ca_data.as_bytes()))),
+            None => None,
+        };
+
+        Ok(ChartMuseum {
+            url: config.url.trim_right_matches('/').to_string(),
+            username: config.username,
+            password: config.password,
+            ca_cert: ca_cert,
+        })
+    }
+
+    /// Upload the chart archive at `archive`, as made by `Helm::package`.
+    pub fn upload(&self, archive: &Path) -> Result<(), HelmError> {
+        let mut body = Vec::new();
+        try!(File::open(archive)
+            .and_then(|mut file| file.read_to_end(&mut body))
+            .map_err(|e| HelmError::from(e).context(format!("reading `{}`", archive.display()))));
+
+        let mut headers = vec!["Content-Type: application/octet-stream".to_string()];
+        if let Some(ref username) = self.username {
+            let credentials = format!("{}:{}", username, self.password.as_ref().map(|p| p as &str).unwrap_or(""));
+            headers.push(format!("Authorization: Basic {}", base64::encode(credentials.as_bytes())));
+        }
+        let ca_cert = self.ca_cert.as_ref().map(|ca_cert| ca_cert.to_path_buf());
+
+        let url = format!("{}/api/charts", self.url);
+        let (status, response) = try!(http::request("POST", &url, &headers, Some(&body),
+            ca_cert.as_ref().map(|path| path.as_path())));
+        if status < 200 || status >= 300 {
+            return Err(HelmError::ChartMuseum(format!("uploading `{}` returned {}: {}",
+                archive.display(), status, String::from_utf8_lossy(&response).trim())));
+        }
+
+        Ok(())
+    }
+}
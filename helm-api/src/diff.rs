@@ -0,0 +1,236 @@
+use super::serde_yaml;
+
+
+// lines of unchanged YAML around each change
+const CONTEXT: usize = 3;
+
+/// How the manifests of two revisions of a release differ, resource by resource.
+#[derive(Debug, Clone, Serialize)]
+pub struct ManifestDiff {
+    pub release: String,
+    pub from: u64,
+    pub to: u64,
+    pub resources: Vec<ResourceDiff>,
+}
+
+/// One resource that differs between the revisions.
+#[derive(Debug, Clone, Serialize)]
+pub struct ResourceDiff {
+    /// e.g. `Deployment/web`
+    pub resource: String,
+    pub change: ResourceChange,
+    /// the changed lines of its YAML, as a unified diff without file headers
+    pub diff: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum ResourceChange {
+    #[serde(rename = "added")]
+    Added,
+    #[serde(rename = "removed")]
+    Removed,
+    #[serde(rename = "changed")]
+    Changed,
+}
+
+impl ManifestDiff {
+    /// Compare the manifest `old` of revision `from` with `new` of revision `to`.
+    pub fn new(release: &str, from: u64, old: &str, to: u64, new: &str) -> Self {
+        let old = resources(old);
+        let new = resources(new);
+        let mut diffs = Vec::new();
+
+        for &(ref resource, ref yaml) in &new {
+            match old.iter().find(|&&(ref r, _)| r == resource) {
+                None => diffs.push(ResourceDiff {
+                    resource: resource.clone(),
+                    change: ResourceChange::Added,
+                    diff: unified("", yaml),
+                }),
+                Some(&(_, ref before)) if before != yaml => diffs.push(ResourceDiff {
+                    resource: resource.clone(),
+                    change: ResourceChange::Changed,
+                    diff: unified(before, yaml),
+                }),
+                Some(_) => (),
+            }
+        }
+        for &(ref resource, ref yaml) in &old {
+            if !new.iter().any(|&(ref r, _)| r == resource) {
+                diffs.push(ResourceDiff {
+                    resource: resource.clone(),
+                    change: ResourceChange::Removed,
+                    diff: unified(yaml, ""),
+                });
+            }
+        }
+
+        ManifestDiff {
+            release: release.to_string(),
+            from: from,
+            to: to,
+            resources: diffs,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.resources.is_empty()
+    }
+
+    /// The whole diff in unified format, each resource as if it were a file.
+    pub fn unified(&self) -> String {
+        self.resources.iter().map(|resource| {
+            let (from, to) = match resource.change {
+                ResourceChange::Added => ("/dev/null".to_string(), format!("b/{}", resource.resource)),
+                ResourceChange::Removed => (format!("a/{}", resource.resource), "/dev/null".to_string()),
+                ResourceChange::Changed =>
+                    (format!("a/{}", resource.resource), format!("b/{}", resource.resource)),
+            };
+            format!("--- {}\tREVISION {}\n+++ {}\tREVISION {}\n{}", from, self.from, to, self.to, resource.diff)
+        }).collect()
+    }
+}
+
+// just enough of a resource to name it
+#[derive(Deserialize)]
+struct Head {
+    kind: Option<String>,
+    metadata: Option<HeadMeta>,
+}
+
+#[derive(Deserialize)]
+struct HeadMeta {
+    name: Option<String>,
+}
+
+// the documents of a manifest by resource, e.g. `Deployment/web`, in order
+fn resources(manifest: &str) -> Vec<(String, String)> {
+    let mut documents = vec![String::new()];
+    for line in manifest.lines() {
+        if line.trim_right() == "---" {
+            documents.push(String::new());
+        } else {
+            let document = documents.last_mut().unwrap();
+            document.push_str(line);
+            document.push('\n');
+        }
+    }
+
+    let mut resources: Vec<(String, String)> = Vec::new();
+    for document in documents {
+        // helm leaves `# Source:` comments between the documents
+        if document.lines().all(|line| line.trim().is_empty() || line.trim_left().starts_with('#')) {
+            continue;
+        }
+        let head = serde_yaml::from_str::<Head>(&document).ok();
+        let kind = head.as_ref().and_then(|head| head.kind.clone()).unwrap_or_else(|| "Unknown".to_string());
+        let name = head.and_then(|head| head.metadata).and_then(|metadata| metadata.name)
+            .unwrap_or_else(|| "unknown".to_string());
+
+        // the same name twice only happens with broken charts, but tell them apart anyway
+        let mut resource = format!("{}/{}", kind, name);
+        let mut seen = 1;
+        while resources.iter().any(|&(ref r, _)| *r == resource) {
+            seen += 1;
+            resource = format!("{}/{} ({})", kind, name, seen);
+        }
+        resources.push((resource, document));
+    }
+    resources
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Edit {
+    Keep,
+    Delete,
+    Insert,
+}
+
+// the shortest way from `old` to `new` line by line, through their longest common subsequence
+fn edits<'a>(old: &[&'a str], new: &[&'a str]) -> Vec<(Edit, &'a str)> {
+    // the length of the longest common subsequence of every pair of suffixes
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let (mut i, mut j) = (0, 0);
+    let mut edits = Vec::new();
+    while i < old.len() && j < new.len() {
+        if old[i] == new[j] {
+            edits.push((Edit::Keep, old[i]));
+            i += 1;
+            j += 1;
+        } else if lcs[i + 1][j] >= lcs[i][j + 1] {
+            edits.push((Edit::Delete, old[i]));
+            i += 1;
+        } else {
+            edits.push((Edit::Insert, new[j]));
+            j += 1;
+        }
+    }
+    edits.extend(old[i..].iter().map(|line| (Edit::Delete, *line)));
+    edits.extend(new[j..].iter().map(|line| (Edit::Insert, *line)));
+    edits
+}
+
+fn is_change(edit: &(Edit, &str)) -> bool {
+    edit.0 != Edit::Keep
+}
+
+// the hunks of a unified diff from `old` to `new`
+fn unified(old: &str, new: &str) -> String {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+    let edits = edits(&old, &new);
+
+    let mut hunks = String::new();
+    let mut start = 0;
+    while let Some(first) = edits[start..].iter().position(is_change).map(|at| start + at) {
+        // changes close enough for their context to touch share a hunk
+        let mut end = first;
+        loop {
+            end += edits[end..].iter().take_while(|e| is_change(e)).count();
+            match edits[end..].iter().position(is_change) {
+                Some(gap) if gap <= 2 * CONTEXT => end += gap,
+                _ => break,
+            }
+        }
+        let from = first.saturating_sub(CONTEXT).max(start);
+        let to = (end + CONTEXT).min(edits.len());
+
+        let old_start = edits[..from].iter().filter(|&&(edit, _)| edit != Edit::Insert).count();
+        let new_start = edits[..from].iter().filter(|&&(edit, _)| edit != Edit::Delete).count();
+        let hunk = &edits[from..to];
+        let old_len = hunk.iter().filter(|&&(edit, _)| edit != Edit::Insert).count();
+        let new_len = hunk.iter().filter(|&&(edit, _)| edit != Edit::Delete).count();
+
+        hunks.push_str(&format!("@@ -{} +{} @@\n", range(old_start, old_len), range(new_start, new_len)));
+        for &(edit, line) in hunk {
+            hunks.push(match edit {
+                Edit::Keep => ' ',
+                Edit::Delete => '-',
+                Edit::Insert => '+',
+            });
+            hunks.push_str(line);
+            hunks.push('\n');
+        }
+        start = to;
+    }
+    hunks
+}
+
+// a hunk's range of lines, numbered from 1, an empty one naming the line before it
+fn range(start: usize, len: usize) -> String {
+    match len {
+        0 => format!("{},0", start),
+        1 => format!("{}", start + 1),
+        _ => format!("{},{}", start + 1, len),
+    }
+}
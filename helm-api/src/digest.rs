@@ -0,0 +1,112 @@
+use super::blake3;
+use super::md5;
+use super::sha2::{
+    Digest,
+    Sha256,
+};
+
+
+/// How the digest of a namespace is hashed. Digests other than md5 name
+/// their algorithm, e.g. `sha256:...`, so either kind can be told apart.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub enum DigestAlgorithm {
+    /// Unprefixed, what versions have always been.
+    #[serde(rename = "md5")]
+    Md5,
+    #[serde(rename = "sha256")]
+    Sha256,
+    #[serde(rename = "blake3")]
+    Blake3,
+}
+
+impl Default for DigestAlgorithm {
+    fn default() -> Self {
+        DigestAlgorithm::Md5
+    }
+}
+
+impl DigestAlgorithm {
+    /// The algorithm `digest` was taken with.
+    pub fn of(digest: &str) -> Self {
+        if digest.starts_with("sha256:") {
+            DigestAlgorithm::Sha256
+        } else if digest.starts_with("blake3:") {
+            DigestAlgorithm::Blake3
+        } else {
+            DigestAlgorithm::Md5
+        }
+    }
+}
+
+/// A digest being taken with one of the algorithms.
+pub enum Hasher {
+    Md5(md5::Context),
+    Sha256(Sha256),
+    Blake3(blake3::Hasher),
+}
+
+impl Hasher {
+    pub fn new(algorithm: DigestAlgorithm) -> Self {
+        match algorithm {
+            DigestAlgorithm::Md5 => Hasher::Md5(md5::Context::new()),
+            DigestAlgorithm::Sha256 => Hasher::Sha256(Sha256::default()),
+            DigestAlgorithm::Blake3 => Hasher::Blake3(blake3::Hasher::new()),
+        }
+    }
+
+    pub fn consume<T: AsRef<[u8]>>(&mut self, data: T) {
+        match *self {
+            Hasher::Md5(ref mut context) => context.consume(data),
+            Hasher::Sha256(ref mut sha256) => sha256.input(data.as_ref()),
+            Hasher::Blake3(ref mut blake3) => {
+                blake3.update(data.as_ref());
+            },
+        }
+    }
+
+    /// The digest in hex, prefixed with its algorithm unless that's md5.
+    pub fn finish(self) -> String {
+        match self {
+            Hasher::Md5(context) => format!("{:x}", context.compute()),
+            Hasher::Sha256(sha256) => format!("sha256:{}", sha256.result().iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect::<String>()),
+            Hasher::Blake3(blake3) => format!("blake3:{}", blake3.finalize().to_hex()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn digest(algorithm: DigestAlgorithm, data: &[&str]) -> String {
+        let mut hasher = Hasher::new(algorithm);
+        for data in data {
+            hasher.consume(data);
+        }
+        hasher.finish()
+    }
+
+    #[test]
+    fn hashes_with_each_algorithm() {
+        assert_eq!(digest(DigestAlgorithm::Md5, &[]), "d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(digest(DigestAlgorithm::Sha256, &[]),
+            "sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        assert_eq!(digest(DigestAlgorithm::Blake3, &[]),
+            "blake3:af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262");
+    }
+
+    #[test]
+    fn tells_the_algorithm_of_a_digest() {
+        for &algorithm in &[DigestAlgorithm::Md5, DigestAlgorithm::Sha256, DigestAlgorithm::Blake3] {
+            assert_eq!(DigestAlgorithm::of(&digest(algorithm, &["web", "nginx", "1.2.0"])), algorithm);
+        }
+    }
+
+    #[test]
+    fn consumes_data_in_order() {
+        assert_eq!(digest(DigestAlgorithm::Sha256, &["web", "nginx"]), digest(DigestAlgorithm::Sha256, &["webnginx"]));
+        assert!(digest(DigestAlgorithm::Md5, &["web", "nginx"]) != digest(DigestAlgorithm::Md5, &["nginx", "web"]));
+    }
+}
@@ -8,25 +8,140 @@ use super::url::ParseError;
 pub use super::rustache::RustacheError;
 pub use super::curl::Error as CurlError;
 pub use super::serde_yaml::Error as YamlError;
+pub use self::serde_json::Error as JsonError;
 pub use std::io::Error as IoError;
 
 #[derive(Debug)]
 pub enum HelmError {
     Io(IoError),
     FailedToCreateKubeConfig(RustacheError),
+    ValuesTemplate(String, RustacheError),
     Net(CurlError),
     CmdFailed(String),
     UrlParse(ParseError),
     Yaml(YamlError),
+    Json(JsonError),
     NoCaData,
+    BadCaFile(String),
+    MissingConfig(String),
+    InvalidConfig(Vec<String>),
+    InvalidChart(String, Vec<String>),
+    KubeConfig(String),
+    ProtectedRelease(String),
+    StuckRelease(String, String),
+    RolloutTimeout(String, Vec<String>),
+    HookTimeout(String, Vec<String>),
+    HookFailed(String, Vec<String>),
+    CrdsNotEstablished(String, Vec<String>),
+    DeadlineExceeded(String),
+    ReleaseLocked(String, String),
+    TestsFailed(String, Vec<String>),
+    LintFailed(String),
+    DiffDetected(String),
+    Vault(String),
+    ChartMuseum(String),
+    Notify(String),
+    KubeApi {
+        status: u32,
+        url: String,
+        body: String,
+    },
+    BadVersion(String),
+    NoMatchingVersion(String, String),
+    ChartNotFound(String, String, Option<String>),
+    WrongHelmVersion(String, String),
+    VersionSkew(String, String),
+    NamespaceNotFound(String, String),
     WrongKubeApiFormat(Map<String, Value>),
+    /// what we were doing when the error happened, e.g. "reading `ca.pem`"
+    Context(String, Box<HelmError>),
+}
+
+impl HelmError {
+    /// Say what we were doing when this error happened.
+    pub fn context<S: Into<String>>(self, context: S) -> Self {
+        HelmError::Context(context.into(), Box::new(self))
+    }
 }
 
 impl fmt::Display for HelmError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
+            &HelmError::Io(ref e) =>
+                f.write_fmt(format_args!("io error: {}", e)),
+            &HelmError::FailedToCreateKubeConfig(ref e) =>
+                f.write_fmt(format_args!("could not render kubeconfig: {:?}", e)),
+            &HelmError::Net(ref e) =>
+                f.write_fmt(format_args!("network error: {}", e)),
+            &HelmError::UrlParse(ref e) =>
+                f.write_fmt(format_args!("invalid url: {}", e)),
+            &HelmError::Yaml(ref e) =>
+                f.write_fmt(format_args!("invalid yaml: {}", e)),
+            &HelmError::Json(ref e) =>
+                f.write_fmt(format_args!("invalid json: {}", e)),
+            &HelmError::Context(ref context, ref cause) =>
+                f.write_fmt(format_args!("{}: {}", context, cause)),
             &HelmError::CmdFailed(ref cmd) =>
                 f.write_fmt(format_args!("could not run command `{}`", cmd)),
+            &HelmError::ProtectedRelease(ref release) =>
+                f.write_fmt(format_args!("refusing to delete protected release `{}`", release)),
+            &HelmError::StuckRelease(ref release, ref status) =>
+                f.write_fmt(format_args!("release `{}` is {}, it has to be rolled back or deleted first", release, status)),
+            &HelmError::RolloutTimeout(ref release, ref pending) =>
+                f.write_fmt(format_args!("release `{}` did not roll out in time, still waiting on: {}",
+                    release, pending.join(", "))),
+            &HelmError::HookTimeout(ref release, ref hooks) =>
+                f.write_fmt(format_args!("hooks of release `{}` did not finish in time: {}",
+                    release, hooks.join(", "))),
+            &HelmError::HookFailed(ref release, ref hooks) =>
+                f.write_fmt(format_args!("hooks of release `{}` failed: {}", release, hooks.join(", "))),
+            &HelmError::CrdsNotEstablished(ref release, ref crds) =>
+                f.write_fmt(format_args!("CRDs of release `{}` were not established in time: {}",
+                    release, crds.join(", "))),
+            &HelmError::DeadlineExceeded(ref release) =>
+                f.write_fmt(format_args!("release `{}` did not deploy before its deadline", release)),
+            &HelmError::ReleaseLocked(ref release, ref holder) =>
+                f.write_fmt(format_args!("release `{}` is being deployed by {}", release, holder)),
+            &HelmError::TestsFailed(ref release, ref pods) =>
+                f.write_fmt(format_args!("tests of release `{}` failed: {}", release, pods.join(", "))),
+            &HelmError::LintFailed(ref path) =>
+                f.write_fmt(format_args!("chart `{}` did not pass `helm lint`", path)),
+            &HelmError::DiffDetected(ref release) =>
+                f.write_fmt(format_args!("upgrading release `{}` would change the cluster", release)),
+            &HelmError::Vault(ref message) =>
+                f.write_fmt(format_args!("vault: {}", message)),
+            &HelmError::ChartMuseum(ref message) =>
+                f.write_fmt(format_args!("chartmuseum: {}", message)),
+            &HelmError::Notify(ref message) =>
+                f.write_fmt(format_args!("notifying: {}", message)),
+            &HelmError::KubeApi { status, ref url, ref body } =>
+                f.write_fmt(format_args!("kube API returned {} for `{}`: {}", status, url, body)),
+            &HelmError::BadVersion(ref version) =>
+                f.write_fmt(format_args!("could not parse version `{}`", version)),
+            &HelmError::NoMatchingVersion(ref chart, ref requirement) =>
+                f.write_fmt(format_args!("no version of chart `{}` matches `{}`", chart, requirement)),
+            &HelmError::ChartNotFound(ref repo, ref chart, Some(ref version)) =>
+                f.write_fmt(format_args!("chart `{}` version `{}` not found in repo `{}`", chart, version, repo)),
+            &HelmError::ChartNotFound(ref repo, ref chart, None) =>
+                f.write_fmt(format_args!("chart `{}` not found in repo `{}`", chart, repo)),
+            &HelmError::WrongHelmVersion(ref required, ref found) =>
+                f.write_fmt(format_args!("helm is version `{}` but `{}` is required", found, required)),
+            &HelmError::VersionSkew(ref client, ref server) =>
+                f.write_fmt(format_args!("helm client `{}` is incompatible with tiller `{}`", client, server)),
+            &HelmError::BadCaFile(ref path) =>
+                f.write_fmt(format_args!("`{}` is not a readable PEM CA bundle", path)),
+            &HelmError::InvalidConfig(ref problems) =>
+                f.write_fmt(format_args!("invalid source:\n  - {}", problems.join("\n  - "))),
+            &HelmError::InvalidChart(ref release, ref problems) =>
+                f.write_fmt(format_args!("invalid chart `{}`:\n  - {}", release, problems.join("\n  - "))),
+            &HelmError::MissingConfig(ref message) =>
+                f.write_fmt(format_args!("incomplete config: {}", message)),
+            &HelmError::KubeConfig(ref message) =>
+                f.write_fmt(format_args!("kubeconfig: {}", message)),
+            &HelmError::ValuesTemplate(ref path, ref e) =>
+                f.write_fmt(format_args!("could not render values template `{}`: {:?}", path, e)),
+            &HelmError::NamespaceNotFound(ref namespace, ref server) =>
+                f.write_fmt(format_args!("namespace `{}` does not exist on cluster `{}`", namespace, server)),
             &HelmError::WrongKubeApiFormat(ref object) =>
                 f.write_fmt(format_args!("could not parse api `{:?}`", object)),
             _ => write!(f, "{}", self.description()),
@@ -36,25 +151,56 @@ impl fmt::Display for HelmError {
 
 impl Error for HelmError {
     fn description(&self) -> &str {
-        match (self, self.cause()) {
+        match (self, self.source()) {
+            (&HelmError::Context(_, ref cause), _) => cause.description(),
             (_, Some(e)) => e.description(),
             (&HelmError::Io(_), None) => unreachable!(),
             (&HelmError::Net(_), None) => unreachable!(),
             (&HelmError::UrlParse(_), None) => unreachable!(),
             (&HelmError::Yaml(_), None) => unreachable!(),
+            (&HelmError::Json(_), None) => unreachable!(),
             (&HelmError::FailedToCreateKubeConfig(_), _) => "rustache templating error",
             (&HelmError::CmdFailed(ref cmd), _) => cmd,
             (&HelmError::WrongKubeApiFormat(_), _) => "could not parse k8s api",
+            (&HelmError::ProtectedRelease(_), _) => "refusing to delete a protected release",
+            (&HelmError::StuckRelease(..), _) => "release is stuck in a failed or pending state",
+            (&HelmError::RolloutTimeout(..), _) => "release did not roll out in time",
+            (&HelmError::HookTimeout(..), _) => "release hooks did not finish in time",
+            (&HelmError::HookFailed(..), _) => "release hooks failed",
+            (&HelmError::CrdsNotEstablished(..), _) => "release CRDs were not established in time",
+            (&HelmError::DeadlineExceeded(_), _) => "release did not deploy before its deadline",
+            (&HelmError::ReleaseLocked(..), _) => "release is locked by another deploy",
+            (&HelmError::TestsFailed(..), _) => "release tests failed",
+            (&HelmError::LintFailed(_), _) => "chart did not pass helm lint",
+            (&HelmError::DiffDetected(_), _) => "upgrade would change the cluster",
+            (&HelmError::Vault(ref message), _) => message,
+            (&HelmError::ChartMuseum(ref message), _) => message,
+            (&HelmError::Notify(ref message), _) => message,
+            (&HelmError::KubeApi { .. }, _) => "unexpected response from the kube API",
+            (&HelmError::BadVersion(_), _) => "could not parse version",
+            (&HelmError::NoMatchingVersion(..), _) => "no chart version matches the requirement",
+            (&HelmError::ChartNotFound(..), _) => "chart not found in repo",
+            (&HelmError::WrongHelmVersion(..), _) => "helm is not the required version",
+            (&HelmError::VersionSkew(..), _) => "helm client and tiller versions are incompatible",
+            (&HelmError::NamespaceNotFound(..), _) => "namespace does not exist",
+            (&HelmError::BadCaFile(_), _) => "ca_file is not a readable PEM CA bundle",
+            (&HelmError::InvalidConfig(_), _) => "invalid source",
+            (&HelmError::InvalidChart(..), _) => "invalid chart",
+            (&HelmError::MissingConfig(ref message), _) => message,
+            (&HelmError::KubeConfig(ref message), _) => message,
+            (&HelmError::ValuesTemplate(..), _) => "could not render values template",
             (&HelmError::NoCaData, _) => "No ca data given and skip_tls_verify = false",
         }
     }
 
-    fn cause(&self) -> Option<&::std::error::Error> {
+    fn source(&self) -> Option<&(Error + 'static)> {
         match *self {
             HelmError::Io(ref e) => Some(e),
             HelmError::Net(ref e) => Some(e),
             HelmError::UrlParse(ref e) => Some(e),
             HelmError::Yaml(ref e) => Some(e),
+            HelmError::Json(ref e) => Some(e),
+            HelmError::Context(_, ref cause) => Some(&**cause),
             _ => None,
         }
     }
@@ -89,3 +235,9 @@ impl From<YamlError> for HelmError {
         HelmError::Yaml(e)
     }
 }
+
+impl From<JsonError> for HelmError {
+    fn from(e: JsonError) -> Self {
+        HelmError::Json(e)
+    }
+}
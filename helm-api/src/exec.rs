@@ -0,0 +1,202 @@
+use std::io::Read;
+#[cfg(any(test, feature = "test-util"))]
+use std::os::unix::process::ExitStatusExt;
+#[cfg(any(test, feature = "test-util"))]
+use std::process::ExitStatus;
+use std::process::{
+    Command,
+    Output,
+    Stdio,
+};
+#[cfg(any(test, feature = "test-util"))]
+use std::sync::{
+    Arc,
+    Mutex,
+};
+use std::thread;
+use std::time::Duration;
+use super::error::HelmError;
+use super::logger;
+
+
+const SH_PATH: &'static str = "/bin/sh";
+
+/// Runs the shell commands `Helm` builds, e.g. `helm upgrade ...`.
+/// `Helm` uses a `ShellExecutor` unless given another with `Helm::set_executor`.
+pub trait CommandExecutor: Send + Sync {
+    /// Run `cmd` in a shell with `env` added to its environment, and wait for it to exit.
+    fn output(&self, cmd: &str, env: &[(String, String)]) -> Result<Output, HelmError>;
+
+    /// Like `output`, but call `watch` every `poll` while the command runs and
+    /// kill it as soon as `watch` fails, returning that error instead.
+    fn output_watched(&self, cmd: &str, env: &[(String, String)], _poll: Duration,
+        watch: &mut FnMut() -> Result<(), HelmError>) -> Result<Output, HelmError>
+    {
+        try!(watch());
+        self.output(cmd, env)
+    }
+}
+
+/// Runs commands for real, with `/bin/sh -c`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ShellExecutor;
+
+impl ShellExecutor {
+    fn shell(cmd: &str, env: &[(String, String)]) -> Command {
+        let mut shell = Command::new(SH_PATH);
+        shell
+            .envs(env.iter().map(|&(ref key, ref value)| (key, value)))
+            .arg("-c")
+            .arg(cmd);
+        shell
+    }
+}
+
+impl CommandExecutor for ShellExecutor {
+    fn output(&self, cmd: &str, env: &[(String, String)]) -> Result<Output, HelmError> {
+        ShellExecutor::shell(cmd, env).output().map_err(HelmError::from)
+    }
+
+    fn output_watched(&self, cmd: &str, env: &[(String, String)], poll: Duration,
+        watch: &mut FnMut() -> Result<(), HelmError>) -> Result<Output, HelmError>
+    {
+        let mut child = try!(ShellExecutor::shell(cmd, env)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn());
+
+        // drain the pipes as we go, the command blocks once they're full
+        let stdout = drain(child.stdout.take());
+        let stderr = drain(child.stderr.take());
+
+        let status;
+        loop {
+            if let Some(exited) = try!(child.try_wait()) {
+                status = exited;
+                break;
+            }
+            if let Err(e) = watch() {
+                let _ = child.kill();
+                let _ = child.wait();
+                logger::output(&stderr.join().unwrap_or_default());
+                return Err(e);
+            }
+            thread::sleep(poll);
+        }
+
+        Ok(Output {
+            status: status,
+            stdout: stdout.join().unwrap_or_default(),
+            stderr: stderr.join().unwrap_or_default(),
+        })
+    }
+}
+
+/// Records the commands it's given instead of running them, and answers
+/// with canned output. Clones share their records, so keep one around to
+/// look at what a `Helm` ran.
+#[cfg(any(test, feature = "test-util"))]
+#[derive(Debug, Default, Clone)]
+pub struct FakeExecutor {
+    invocations: Arc<Mutex<Vec<String>>>,
+    // command substring, exit code and stdout
+    responses: Arc<Mutex<Vec<(String, i32, String)>>>,
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl FakeExecutor {
+    pub fn new() -> Self {
+        FakeExecutor::default()
+    }
+
+    /// Answer commands containing `pattern` with `stdout`, exiting with `code`.
+    /// The first response that matches wins, other commands succeed without output.
+    pub fn respond(&self, pattern: &str, code: i32, stdout: &str) {
+        self.responses.lock().unwrap().push((pattern.to_string(), code, stdout.to_string()));
+    }
+
+    /// Every command run so far, in order.
+    pub fn invocations(&self) -> Vec<String> {
+        self.invocations.lock().unwrap().clone()
+    }
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl CommandExecutor for FakeExecutor {
+    fn output(&self, cmd: &str, _env: &[(String, String)]) -> Result<Output, HelmError> {
+        self.invocations.lock().unwrap().push(cmd.to_string());
+        let (code, stdout) = self.responses.lock().unwrap().iter()
+            .find(|&&(ref pattern, _, _)| cmd.contains(pattern as &str))
+            .map(|&(_, code, ref stdout)| (code, stdout.clone()))
+            .unwrap_or((0, String::new()));
+
+        Ok(Output {
+            // a wait status, the exit code is in its second byte
+            status: ExitStatus::from_raw(code << 8),
+            stdout: stdout.into_bytes(),
+            stderr: Vec::new(),
+        })
+    }
+}
+
+// read everything from a child's pipe on another thread
+fn drain<R>(pipe: Option<R>) -> thread::JoinHandle<Vec<u8>>
+where R: Read + Send + 'static,
+{
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut buf);
+        }
+        buf
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+    use super::*;
+
+    #[test]
+    fn shell_runs_commands_with_env() {
+        let env = vec![("GREETING".to_string(), "hello".to_string())];
+        let output = ShellExecutor.output("echo $GREETING; exit 3", &env).unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "hello\n");
+        assert_eq!(output.status.code(), Some(3));
+    }
+
+    #[test]
+    fn shell_kills_commands_once_watch_fails() {
+        let started = Instant::now();
+        let mut polls = 0;
+        let result = ShellExecutor.output_watched("sleep 30", &[], Duration::from_millis(10), &mut || {
+            polls += 1;
+            if polls < 3 { Ok(()) } else { Err(HelmError::MissingConfig("enough".to_string())) }
+        });
+        assert!(result.is_err());
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn fake_answers_with_the_first_matching_response() {
+        let fake = FakeExecutor::new();
+        fake.respond("get values", 0, "image: nginx");
+        fake.respond("get", 1, "");
+
+        let output = fake.output("helm get values web", &[]).unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"image: nginx".to_vec());
+        assert_eq!(fake.output("helm get manifest web", &[]).unwrap().status.code(), Some(1));
+        assert!(fake.output("helm list", &[]).unwrap().status.success());
+
+        assert_eq!(fake.invocations(), vec!["helm get values web", "helm get manifest web", "helm list"]);
+    }
+
+    #[test]
+    fn fake_clones_share_invocations() {
+        let fake = FakeExecutor::new();
+        let executor: Box<CommandExecutor> = Box::new(fake.clone());
+        executor.output("helm version", &[]).unwrap();
+        assert_eq!(fake.invocations(), vec!["helm version"]);
+    }
+}
@@ -0,0 +1,274 @@
+use std::collections::HashMap;
+use std::fs::{
+    self,
+    File,
+};
+use std::io::{
+    self,
+    BufRead,
+    BufReader,
+    Read,
+    Write,
+};
+use std::net::{
+    TcpListener,
+    TcpStream,
+};
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::sync::{
+    Arc,
+    Mutex,
+};
+use std::thread;
+use super::error::HelmError;
+use super::mktemp::Temp;
+use super::serde_json::{
+    self,
+    Value,
+};
+use super::{
+    Config,
+    ConfigBuilder,
+    WORKLOAD_KINDS,
+};
+
+
+// records its arguments, then prints the first response whose prefix they start with
+const HELM_SHIM: &'static str = r#"#!/bin/sh
+dir=$(dirname "$0")
+echo "$*" >> "$dir/invocations"
+for response in "$dir"/responses/*; do
+    [ -f "$response" ] || continue
+    prefix=$(head -n 1 "$response")
+    case "$*" in
+        "$prefix"*) tail -n +2 "$response"; exit 0;;
+    esac
+done
+"#;
+
+// what the shim answers `helm version` with unless told otherwise
+const HELM_VERSION: &'static str = "v2.16.12+g47f0b88";
+
+/// A tiny apiserver on localhost, answering GETs with canned listings.
+/// Label selectors and the like are ignored, every listing is served whole.
+/// It serves until the process exits.
+pub struct FakeApiserver {
+    url: String,
+    // listing bodies by path
+    routes: Arc<Mutex<HashMap<String, String>>>,
+}
+
+impl FakeApiserver {
+    pub fn start() -> Result<Self, HelmError> {
+        let listener = try!(TcpListener::bind("127.0.0.1:0"));
+        let url = format!("http://{}", try!(listener.local_addr()));
+        let routes = Arc::new(Mutex::new(HashMap::new()));
+
+        // discovery finds every workload in its newest group version
+        let mut groups: HashMap<&str, Vec<String>> = HashMap::new();
+        for &(kind, versions) in WORKLOAD_KINDS {
+            groups.entry(versions[0]).or_insert_with(Vec::new)
+                .push(format!(r#"{{"name": "{}"}}"#, kind));
+        }
+        for (group, resources) in groups {
+            routes.lock().unwrap().insert(format!("/{}", group),
+                format!(r#"{{"resources": [{}]}}"#, resources.join(", ")));
+        }
+
+        let served = routes.clone();
+        thread::spawn(move || for stream in listener.incoming() {
+            if let Ok(stream) = stream {
+                let _ = respond(stream, &served);
+            }
+        });
+
+        Ok(FakeApiserver {
+            url: url,
+            routes: routes,
+        })
+    }
+
+    pub fn url(&self) -> String {
+        self.url.clone()
+    }
+
+    /// Answer GETs of `path`, e.g. `/api/v1/namespaces`, with `body`.
+    pub fn serve(&self, path: &str, body: &str) {
+        self.routes.lock().unwrap().insert(path.to_string(), body.to_string());
+    }
+
+    /// Serve `items` as the listing of `kind` in `namespace`, e.g. `deployments`.
+    pub fn workloads(&self, namespace: &str, kind: &str, items: Vec<Value>) -> Result<(), HelmError> {
+        let &(_, groups) = try!(WORKLOAD_KINDS.iter().find(|&&(name, _)| name == kind)
+            .ok_or(HelmError::MissingConfig(format!("`{}` isn't a kind of workload", kind))));
+
+        let mut listing = serde_json::Map::new();
+        let mut metadata = serde_json::Map::new();
+        metadata.insert("resourceVersion".to_string(), Value::String("1".to_string()));
+        listing.insert("metadata".to_string(), Value::Object(metadata));
+        listing.insert("items".to_string(), Value::Array(items));
+
+        let path = format!("/{}/namespaces/{}/{}", groups[0], namespace, kind);
+        self.serve(&path, &try!(serde_json::to_string(&Value::Object(listing))));
+        Ok(())
+    }
+}
+
+// answer one request and hang up
+fn respond(mut stream: TcpStream, routes: &Mutex<HashMap<String, String>>) -> io::Result<()> {
+    let mut request_line = String::new();
+    {
+        let mut reader = BufReader::new(try!(stream.try_clone()));
+        try!(reader.read_line(&mut request_line));
+        // skip the headers, nothing we serve depends on them
+        loop {
+            let mut header = String::new();
+            if try!(reader.read_line(&mut header)) == 0 || header.trim().is_empty() {
+                break;
+            }
+        }
+    }
+
+    let path = request_line.split(' ').nth(1).unwrap_or("/");
+    let path = path.split('?').next().unwrap_or(path);
+    let (status, body) = match routes.lock().unwrap().get(path) {
+        Some(body) => ("200 OK", body.clone()),
+        None => ("404 Not Found", "{}".to_string()),
+    };
+
+    stream.write_fmt(format_args!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, body.len(), body))
+}
+
+/// A stand-in for the helm cli that records its arguments and prints canned
+/// output, succeeding whatever it's asked to do. It's removed when dropped.
+pub struct FakeHelm {
+    dir: Temp,
+    responses: Mutex<usize>,
+}
+
+impl FakeHelm {
+    pub fn install() -> Result<Self, HelmError> {
+        let dir = try!(Temp::new_dir());
+        let helm = FakeHelm {
+            dir: dir,
+            responses: Mutex::new(0),
+        };
+        try!(fs::create_dir(helm.dir.to_path_buf().join("responses")));
+
+        let path = helm.path();
+        try!(File::create(&path).and_then(|mut shim| shim.write_all(HELM_SHIM.as_bytes())));
+        try!(fs::set_permissions(&path, fs::Permissions::from_mode(0o700)));
+
+        try!(helm.respond("version", HELM_VERSION));
+        Ok(helm)
+    }
+
+    /// The shim itself, to use as `Config::helm_bin`.
+    pub fn path(&self) -> PathBuf {
+        self.dir.to_path_buf().join("helm")
+    }
+
+    /// Print `stdout` when the arguments start with `prefix`, e.g. `list`.
+    /// Earlier responses win over later ones.
+    pub fn respond(&self, prefix: &str, stdout: &str) -> Result<(), HelmError> {
+        let mut responses = self.responses.lock().unwrap();
+        let path = self.dir.to_path_buf().join("responses").join(format!("{:04}", *responses));
+        try!(File::create(path).and_then(|mut response| {
+            response.write_fmt(format_args!("{}\n{}\n", prefix, stdout))
+        }));
+        *responses += 1;
+        Ok(())
+    }
+
+    /// The arguments of every run so far, in order.
+    pub fn invocations(&self) -> Result<Vec<String>, HelmError> {
+        let mut invocations = String::new();
+        match File::open(self.dir.to_path_buf().join("invocations")) {
+            Ok(mut file) => try!(file.read_to_string(&mut invocations)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(HelmError::from(e)),
+        };
+        Ok(invocations.lines().map(|line| line.to_string()).collect())
+    }
+}
+
+/// A config for `Helm::configure` that talks to `apiserver` over plain http and
+/// runs `helm` to deploy into `namespace`, which the apiserver will say exists.
+/// Anything else can be set on top.
+pub fn config(apiserver: &FakeApiserver, helm: &FakeHelm, namespace: &str) -> ConfigBuilder {
+    apiserver.serve(&format!("/api/v1/namespaces/{}", namespace),
+        &format!(r#"{{"metadata": {{"name": "{}"}}}}"#, namespace));
+
+    Config::builder(namespace)
+        .url(apiserver.url())
+        .username("fixture".to_string())
+        .password("fixture".to_string())
+        .skip_tls_verify(true)
+        .helm_bin(helm.path().to_string_lossy().into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{
+        Chart,
+        Helm,
+        UpgradeOptions,
+    };
+
+    // a deployment of `release` in `prod`, labelled the way tiller does
+    fn deployment(release: &str, chart: &str) -> Value {
+        serde_json::from_str(&format!(r#"{{"metadata": {{"name": "{0}", "namespace": "prod",
+            "labels": {{"heritage": "Tiller", "release": "{0}", "chart": "{1}"}}}}}}"#, release, chart)).unwrap()
+    }
+
+    #[test]
+    fn lists_releases_from_the_apiserver() {
+        let apiserver = FakeApiserver::start().unwrap();
+        let fake_helm = FakeHelm::install().unwrap();
+        apiserver.workloads("prod", "deployments", vec![deployment("web", "nginx-1.2.0")]).unwrap();
+        let helm = Helm::configure(config(&apiserver, &fake_helm, "prod").build().unwrap()).unwrap();
+
+        let releases = helm.list().unwrap();
+        assert_eq!(releases.len(), 1);
+        assert_eq!(releases[0].name, "web");
+        assert_eq!(releases[0].chart, "nginx");
+        assert_eq!(releases[0].version, Some("1.2.0".to_string()));
+        assert_eq!(releases[0].namespace, "prod");
+        // listing never needs the cli
+        assert!(fake_helm.invocations().unwrap().is_empty());
+    }
+
+    #[test]
+    fn digest_follows_the_deployed_charts() {
+        let apiserver = FakeApiserver::start().unwrap();
+        let fake_helm = FakeHelm::install().unwrap();
+        apiserver.workloads("prod", "deployments", vec![deployment("web", "nginx-1.2.0")]).unwrap();
+        let helm = Helm::configure(config(&apiserver, &fake_helm, "prod").build().unwrap()).unwrap();
+
+        let digest = helm.digest().unwrap();
+        assert_eq!(helm.digest().unwrap(), digest);
+
+        apiserver.workloads("prod", "deployments", vec![deployment("web", "nginx-1.3.0")]).unwrap();
+        assert!(helm.digest().unwrap() != digest);
+    }
+
+    #[test]
+    fn upgrades_with_the_helm_cli() {
+        let apiserver = FakeApiserver::start().unwrap();
+        let fake_helm = FakeHelm::install().unwrap();
+        let helm = Helm::configure(config(&apiserver, &fake_helm, "prod").build().unwrap()).unwrap();
+
+        let chart_dir = Temp::new_dir().unwrap();
+        let path = chart_dir.to_path_buf().to_string_lossy().into_owned();
+        let chart = Chart::builder("web", "nginx").path(path.clone()).build().unwrap();
+        helm.upgrade(&chart, &UpgradeOptions::default()).unwrap();
+
+        let invocations = fake_helm.invocations().unwrap();
+        let upgrade = format!("upgrade --namespace prod --install web {}", path);
+        assert!(invocations.contains(&upgrade), "no `{}` in {:?}", upgrade, invocations);
+    }
+}
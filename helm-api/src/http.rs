@@ -0,0 +1,44 @@
+use std::path::Path;
+use super::error::HelmError;
+use super::curl::easy::{
+    Easy,
+    List,
+};
+
+
+/// Make a one-off HTTP request outside of the kube API, returning the status
+/// code and body. `ca_cert` is trusted in addition to the system's CAs.
+pub fn request(method: &str, url: &str, headers: &[String], body: Option<&[u8]>, ca_cert: Option<&Path>)
+    -> Result<(u32, Vec<u8>), HelmError>
+{
+    let mut handle = Easy::new();
+
+    try!(handle.url(url));
+    try!(handle.custom_request(method));
+
+    let mut header_list = List::new();
+    for header in headers {
+        try!(header_list.append(header));
+    }
+    try!(handle.http_headers(header_list));
+
+    if let Some(body) = body {
+        try!(handle.post_fields_copy(body));
+    }
+    if let Some(ca_cert) = ca_cert {
+        try!(handle.cainfo(ca_cert));
+    }
+
+    let mut buf = Vec::new();
+    {
+        let mut transfer = handle.transfer();
+        try!(transfer.write_function(|data| {
+            buf.extend_from_slice(data);
+            Ok(data.len())
+        }));
+        try!(transfer.perform()
+            .map_err(|e| HelmError::from(e).context(format!("requesting `{} {}`", method, url))));
+    }
+
+    Ok((try!(handle.response_code()), buf))
+}
@@ -0,0 +1,897 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+#[cfg(any(test, feature = "test-util"))]
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{
+    Duration,
+    Instant,
+};
+use super::error::HelmError;
+use super::logger;
+use super::Impersonate;
+use super::serde::{
+    Deserialize,
+    Serialize,
+};
+use super::serde_json;
+use super::curl::easy::{
+    Easy,
+    List,
+};
+use super::url::{
+    Url,
+    ParseError,
+};
+
+
+const PAGE_LIMIT: &'static str = "500";
+
+// how long a watch for changes stays open, nothing that happened
+// before it was opened takes longer than this to arrive
+const WATCH_TIMEOUT_SECS: &'static str = "1";
+
+// how long a request may take unless configured otherwise, so a black-holed
+// apiserver fails the step instead of hanging it
+pub const DEFAULT_CONNECT_TIMEOUT_SECS: u64 = 10;
+pub const DEFAULT_TIMEOUT_SECS: u64 = 60;
+
+// how much of an error response to keep, they can be whole html pages
+const MAX_ERROR_BODY: usize = 1024;
+
+pub const NAMESPACES: Resource = Resource { group: "api/v1", name: "namespaces" };
+pub const CONFIG_MAPS: Resource = Resource { group: "api/v1", name: "configmaps" };
+pub const SERVICES: Resource = Resource { group: "api/v1", name: "services" };
+pub const CUSTOM_RESOURCE_DEFINITIONS: Resource =
+    Resource { group: "apis/apiextensions.k8s.io/v1", name: "customresourcedefinitions" };
+// what charts for clusters older than 1.16 still ship their CRDs as
+pub const CUSTOM_RESOURCE_DEFINITIONS_V1BETA1: Resource =
+    Resource { group: "apis/apiextensions.k8s.io/v1beta1", name: "customresourcedefinitions" };
+
+
+/// A kind of resource served by the apiserver, e.g. `apps/v1beta1` statefulsets.
+#[derive(Debug, Clone, Copy)]
+pub struct Resource {
+    /// path of the api group version, e.g. `apis/extensions/v1beta1` or `api/v1`
+    pub group: &'static str,
+    /// plural name of the resource, e.g. `deployments`
+    pub name: &'static str,
+}
+
+/// How requests to the apiserver are authenticated.
+pub enum Auth {
+    Basic {
+        username: String,
+        password: String,
+    },
+    Bearer(String),
+    ClientCert {
+        cert: PathBuf,
+        key: PathBuf,
+    },
+    /// leave authenticating to helm, e.g. for auth provider plugins
+    None,
+}
+
+/// How long to wait for a connection, and for a whole request including it.
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    pub connect: Duration,
+    pub request: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            connect: Duration::from_secs(DEFAULT_CONNECT_TIMEOUT_SECS),
+            request: Duration::from_secs(DEFAULT_TIMEOUT_SECS),
+        }
+    }
+}
+
+/// Sends requests to the apiserver. `Client` makes every call through one
+/// of these, so tests can answer them with canned responses instead.
+pub trait KubeClient: Send + Sync {
+    /// Make an authenticated request, returning the status code and body.
+    fn request(&self, method: &str, url: &Url, body: Option<&[u8]>) -> Result<(u32, String), HelmError>;
+}
+
+/// Talks to a real apiserver with curl.
+pub struct CurlClient {
+    auth: Auth,
+    ca_cert: Option<PathBuf>,
+    skip_tls_verify: bool,
+    timeouts: Timeouts,
+    impersonate: Option<Impersonate>,
+    // kept between requests so its connections and TLS sessions are reused
+    handle: Mutex<Easy>,
+}
+
+impl CurlClient {
+    /// Authenticate with `auth`, trusting `ca_cert` if one is given,
+    /// skipping TLS verification entirely if asked to or trusting the
+    /// system's CAs otherwise.
+    pub fn new(auth: Auth, ca_cert: Option<PathBuf>, skip_tls_verify: bool, timeouts: Timeouts) -> Self {
+        CurlClient {
+            auth: auth,
+            ca_cert: ca_cert,
+            skip_tls_verify: skip_tls_verify,
+            timeouts: timeouts,
+            impersonate: None,
+            handle: Mutex::new(Easy::new()),
+        }
+    }
+
+    /// Make every request as someone else, with the `Impersonate-*` headers.
+    pub fn impersonating(mut self, impersonate: Option<Impersonate>) -> Self {
+        self.impersonate = impersonate;
+        self
+    }
+}
+
+impl KubeClient for CurlClient {
+    fn request(&self, method: &str, url: &Url, body: Option<&[u8]>) -> Result<(u32, String), HelmError> {
+        // only one request at a time can use the handle, the options
+        // are reset for each while its connection cache is kept
+        let mut handle = self.handle.lock().unwrap();
+        handle.reset();
+
+        try!(handle.url(url.as_str()));
+        try!(handle.custom_request(method));
+        try!(handle.connect_timeout(self.timeouts.connect));
+        try!(handle.timeout(self.timeouts.request));
+        // listings of big namespaces run to megabytes of JSON, curl inflates them for us
+        try!(handle.accept_encoding("gzip"));
+
+        let mut headers = List::new();
+
+        match self.auth {
+            Auth::Basic { ref username, ref password } => {
+                try!(handle.username(username));
+                try!(handle.password(password));
+            },
+            Auth::Bearer(ref token) => {
+                try!(headers.append(&format!("Authorization: Bearer {}", token)));
+            },
+            Auth::ClientCert { ref cert, ref key } => {
+                try!(handle.ssl_cert(cert));
+                try!(handle.ssl_key(key));
+            },
+            Auth::None => (),
+        }
+
+        if let Some(ref impersonate) = self.impersonate {
+            try!(headers.append(&format!("Impersonate-User: {}", impersonate.user)));
+            for group in &impersonate.groups {
+                try!(headers.append(&format!("Impersonate-Group: {}", group)));
+            }
+        }
+
+        if let Some(ref ca_cert_path) = self.ca_cert {
+            try!(handle.cainfo(ca_cert_path));
+        } else if self.skip_tls_verify {
+            try!(handle.ssl_verify_peer(false));
+        }
+
+        if let Some(body) = body {
+            try!(headers.append("Content-Type: application/json"));
+            try!(handle.post_fields_copy(body));
+        }
+        try!(handle.http_headers(headers));
+
+        let started = Instant::now();
+        let mut buf = Vec::new();
+        {
+            let mut transfer = handle.transfer();
+            try!(transfer.write_function(|data| {
+                buf.extend_from_slice(data);
+                Ok(data.len())
+            }));
+            try!(transfer.perform());
+        }
+
+        let status = try!(handle.response_code());
+        logger::debug(format_args!("{} {} returned {} with {} bytes after {}.",
+            method, url, status, buf.len(), logger::duration(started.elapsed())));
+
+        Ok((status, String::from_utf8_lossy(&buf).into_owned()))
+    }
+}
+
+/// Answers requests with canned responses instead of asking an apiserver,
+/// e.g. fixture JSON of a namespace's deployments. Clones share their
+/// responses and records, so keep one around to look at what was asked.
+#[cfg(any(test, feature = "test-util"))]
+#[derive(Debug, Default, Clone)]
+pub struct MockKubeClient {
+    requests: Arc<Mutex<Vec<String>>>,
+    // method, url substring, status code and body
+    responses: Arc<Mutex<Vec<(String, String, u32, String)>>>,
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl MockKubeClient {
+    pub fn new() -> Self {
+        MockKubeClient::default()
+    }
+
+    /// Answer `method` requests whose url contains `pattern` with `status` and `body`.
+    /// The first response that matches wins, anything else is a 404.
+    pub fn respond(&self, method: &str, pattern: &str, status: u32, body: &str) {
+        self.responses.lock().unwrap()
+            .push((method.to_string(), pattern.to_string(), status, body.to_string()));
+    }
+
+    /// Every request made so far, as `METHOD url`, in order.
+    pub fn requests(&self) -> Vec<String> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl KubeClient for MockKubeClient {
+    fn request(&self, method: &str, url: &Url, _body: Option<&[u8]>) -> Result<(u32, String), HelmError> {
+        self.requests.lock().unwrap().push(format!("{} {}", method, url));
+        Ok(self.responses.lock().unwrap().iter()
+            .find(|&&(ref m, ref pattern, _, _)| m == method && url.as_str().contains(pattern as &str))
+            .map(|&(_, _, status, ref body)| (status, body.clone()))
+            .unwrap_or((404, String::new())))
+    }
+}
+
+/// Spaces out requests to the apiserver so many pipelines polling the same cluster
+/// stay under its priority and fairness limits, a token bucket of `burst` requests
+/// refilling at `qps` a second.
+pub struct RateLimiter {
+    qps: f64,
+    burst: f64,
+    // the tokens left and when we last counted them
+    bucket: Mutex<(f64, Instant)>,
+}
+
+impl RateLimiter {
+    pub fn new(qps: f64, burst: u32) -> Self {
+        let burst = burst.max(1) as f64;
+        RateLimiter {
+            qps: qps,
+            burst: burst,
+            bucket: Mutex::new((burst, Instant::now())),
+        }
+    }
+
+    /// Wait for our turn to send a request.
+    pub fn acquire(&self) {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            let elapsed = now.duration_since(bucket.1);
+            let refilled = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+            // the tokens may go negative, queueing requests behind one another
+            let tokens = (bucket.0 + refilled * self.qps).min(self.burst) - 1.0;
+            *bucket = (tokens, now);
+            if tokens >= 0.0 { 0.0 } else { -tokens / self.qps }
+        };
+        if wait > 0.0 {
+            logger::debug(format_args!("Throttling apiserver requests for {:.2}s.", wait));
+            thread::sleep(Duration::from_millis((wait * 1000.0).ceil() as u64));
+        }
+    }
+}
+
+/// A small kubernetes API client, just big enough for what helm-resource needs.
+pub struct Client {
+    server: Url,
+    transport: Box<KubeClient>,
+    limiter: Option<RateLimiter>,
+}
+
+impl Client {
+    /// Talk to the apiserver at `server` through `transport`.
+    pub fn new(server: &str, transport: Box<KubeClient>) -> Result<Self, HelmError> {
+        Ok(Client {
+            server: try!(Url::parse(server)),
+            transport: transport,
+            limiter: None,
+        })
+    }
+
+    /// Send at most as many requests as `limiter` allows, or as many as we like without one.
+    pub fn rate_limited(mut self, limiter: Option<RateLimiter>) -> Self {
+        self.limiter = limiter;
+        self
+    }
+
+    /// The url of the apiserver.
+    pub fn server(&self) -> &Url {
+        &self.server
+    }
+
+    /// Send every request through `transport` from now on.
+    pub fn set_transport(&mut self, transport: Box<KubeClient>) {
+        self.transport = transport;
+    }
+
+    // the server's url with `path` appended to it
+    fn url_for(&self, path: &[&str]) -> Result<Url, HelmError> {
+        let mut url = self.server.clone();
+        try!(url.path_segments_mut().map(|mut segments| {
+            segments
+                .pop_if_empty()
+                .extend(path);
+        })
+        .map_err(|_| HelmError::UrlParse(
+            ParseError::RelativeUrlWithCannotBeABaseBase)));
+        Ok(url)
+    }
+
+    /// The url of all `resource`s in `namespace`.
+    pub fn collection_url(&self, resource: Resource, namespace: &str) -> Result<Url, HelmError> {
+        let mut path: Vec<&str> = resource.group.split('/').collect();
+        path.extend(&["namespaces", namespace, resource.name]);
+        self.url_for(&path)
+    }
+
+    /// The url of the `name`d `resource` in `namespace`, or of one of its
+    /// subresources such as a pod's `log`.
+    pub fn object_url(&self, resource: Resource, namespace: &str, name: &str, subresource: Option<&str>)
+        -> Result<Url, HelmError>
+    {
+        let mut path: Vec<&str> = resource.group.split('/').collect();
+        path.extend(&["namespaces", namespace, resource.name, name]);
+        path.extend(subresource);
+        self.url_for(&path)
+    }
+
+    /// The url of all `resource`s that aren't namespaced, e.g. namespaces themselves,
+    /// or of those that are in every namespace.
+    pub fn cluster_collection_url(&self, resource: Resource) -> Result<Url, HelmError> {
+        let mut path: Vec<&str> = resource.group.split('/').collect();
+        path.push(resource.name);
+        self.url_for(&path)
+    }
+
+    /// The url of all `resource`s in `namespace`, or in every namespace without one.
+    pub fn listing_url(&self, resource: Resource, namespace: Option<&str>) -> Result<Url, HelmError> {
+        match namespace {
+            Some(namespace) => self.collection_url(resource, namespace),
+            None => self.cluster_collection_url(resource),
+        }
+    }
+
+    /// The url of the `name`d `resource` that isn't namespaced.
+    pub fn cluster_object_url(&self, resource: Resource, name: &str) -> Result<Url, HelmError> {
+        let mut path: Vec<&str> = resource.group.split('/').collect();
+        path.extend(&[resource.name, name]);
+        self.url_for(&path)
+    }
+
+    /// GET `url` and deserialize the JSON response.
+    pub fn get<D>(&self, url: &Url) -> Result<D, HelmError>
+    where D: Deserialize,
+    {
+        let body = try!(self.get_text(url));
+        serde_json::from_str::<D>(body.trim())
+            .map_err(|e| HelmError::from(e).context(format!("parsing the response of `{}`", url)))
+    }
+
+    /// GET `url` and return the response body as text, e.g. for pod logs.
+    /// Anything but a 2xx response is an error.
+    pub fn get_text(&self, url: &Url) -> Result<String, HelmError> {
+        match try!(self.request("GET", url, None)) {
+            (status, body) if status >= 200 && status < 300 => Ok(body),
+            (status, body) => Err(api_error(status, url, body)),
+        }
+    }
+
+    /// Whether the object at `url` exists.
+    pub fn exists(&self, url: &Url) -> Result<bool, HelmError> {
+        match try!(self.request("GET", url, None)) {
+            (200, _) => Ok(true),
+            (404, _) => Ok(false),
+            (status, body) => Err(api_error(status, url, body)),
+        }
+    }
+
+    /// POST `object` to the collection at `url`, returning whether it was
+    /// created or already existed.
+    pub fn create<S>(&self, url: &Url, object: &S) -> Result<bool, HelmError>
+    where S: Serialize,
+    {
+        let body = try!(serde_json::to_vec(object));
+        match try!(self.request("POST", url, Some(&body))) {
+            (200, _) | (201, _) | (202, _) => Ok(true),
+            (409, _) => Ok(false),
+            (status, body) => Err(api_error(status, url, body)),
+        }
+    }
+
+    /// PUT `object` in place of the object at `url`, which it has to name the
+    /// `resourceVersion` of.
+    pub fn replace<S>(&self, url: &Url, object: &S) -> Result<(), HelmError>
+    where S: Serialize,
+    {
+        let body = try!(serde_json::to_vec(object));
+        match try!(self.request("PUT", url, Some(&body))) {
+            (200, _) | (201, _) => Ok(()),
+            (status, body) => Err(api_error(status, url, body)),
+        }
+    }
+
+    /// DELETE the object at `url`, returning whether it existed.
+    pub fn delete(&self, url: &Url) -> Result<bool, HelmError> {
+        match try!(self.request("DELETE", url, None)) {
+            (200, _) | (202, _) => Ok(true),
+            (404, _) => Ok(false),
+            (status, body) => Err(api_error(status, url, body)),
+        }
+    }
+
+    /// DELETE `url` as long as it's still at `resource_version`, returning
+    /// whether it was deleted, rather than changed or gone in the meantime.
+    pub fn delete_unchanged(&self, url: &Url, resource_version: Option<&str>) -> Result<bool, HelmError> {
+        let body = try!(serde_json::to_vec(&DeleteOptions {
+            kind: "DeleteOptions",
+            api_version: "v1",
+            preconditions: Preconditions {
+                resource_version: resource_version.map(|version| version.to_string()),
+            },
+        }));
+        match try!(self.request("DELETE", url, Some(&body))) {
+            (200, _) | (202, _) => Ok(true),
+            (404, _) | (409, _) => Ok(false),
+            (status, body) => Err(api_error(status, url, body)),
+        }
+    }
+
+    /// Make an authenticated request, returning the status code and body.
+    pub fn request(&self, method: &str, url: &Url, body: Option<&[u8]>) -> Result<(u32, String), HelmError> {
+        if let Some(ref limiter) = self.limiter {
+            limiter.acquire();
+        }
+        self.transport.request(method, url, body)
+            .map_err(|e| e.context(format!("requesting `{} {}`", method, url)))
+    }
+
+    /// The names of the resources the api group version `group` serves,
+    /// e.g. `deployments` for `apis/apps/v1`, or `None` if it isn't served at all.
+    pub fn group_resources(&self, group: &str) -> Result<Option<Vec<String>>, HelmError> {
+        let path: Vec<&str> = group.split('/').collect();
+        let url = try!(self.url_for(&path));
+        match try!(self.request("GET", &url, None)) {
+            (status, body) if status >= 200 && status < 300 => {
+                let list: ApiResourceList = try!(serde_json::from_str(body.trim())
+                    .map_err(|e| HelmError::from(e).context(format!("parsing the response of `{}`", url))));
+                Ok(Some(list.resources.into_iter().map(|resource| resource.name).collect()))
+            },
+            (404, _) => Ok(None),
+            (status, body) => Err(api_error(status, &url, body)),
+        }
+    }
+
+    /// The `resourceVersion` of the listing of `resource` in `namespace`,
+    /// to ask `changed_since` about later.
+    pub fn resource_version(&self, resource: Resource, namespace: Option<&str>, label_selector: &str)
+        -> Result<Option<String>, HelmError>
+    {
+        let mut url = try!(self.listing_url(resource, namespace));
+        url.query_pairs_mut()
+            .append_pair("labelSelector", label_selector)
+            .append_pair("limit", "1");
+        let list: DeploymentList = try!(self.get(&url));
+        Ok(list.metadata.and_then(|metadata| metadata.resource_version))
+    }
+
+    /// Whether any `resource` in `namespace` matching `label_selector` was
+    /// added, changed or deleted since `resource_version`, found by watching
+    /// from it. A version the apiserver no longer remembers counts as a change.
+    pub fn changed_since(&self, resource: Resource, namespace: Option<&str>, label_selector: &str,
+        resource_version: &str) -> Result<bool, HelmError>
+    {
+        let mut url = try!(self.listing_url(resource, namespace));
+        url.query_pairs_mut()
+            .append_pair("labelSelector", label_selector)
+            .append_pair("watch", "true")
+            .append_pair("resourceVersion", resource_version)
+            .append_pair("allowWatchBookmarks", "true")
+            .append_pair("timeoutSeconds", WATCH_TIMEOUT_SECS);
+
+        match try!(self.request("GET", &url, None)) {
+            // bookmarks only move the version along, every other event is a change,
+            // including the `ERROR` an expired version is reported as
+            (status, body) if status >= 200 && status < 300 => Ok(body.lines()
+                .filter(|line| !line.trim().is_empty())
+                .any(|line| serde_json::from_str::<WatchEvent>(line)
+                    .map(|event| event.kind != "BOOKMARK")
+                    .unwrap_or(true))),
+            // the cluster doesn't serve this api group version, so nothing of it changed
+            (404, _) => Ok(false),
+            (410, _) => Ok(true),
+            (status, body) => Err(api_error(status, &url, body)),
+        }
+    }
+
+    /// List every `resource` in `namespace`, or all of them, matching `label_selector`,
+    /// following continue tokens until the listing is exhausted.
+    pub fn list(&self, resource: Resource, namespace: Option<&str>, label_selector: &str)
+        -> Result<Vec<Deployment>, HelmError>
+    {
+        let url = try!(self.listing_url(resource, namespace));
+        let mut items = Vec::new();
+        let mut continue_token: Option<String> = None;
+
+        loop {
+            // ask for one page at a time, resuming where the last one ended
+            let mut page_url = url.clone();
+            {
+                let mut query = page_url.query_pairs_mut();
+                query.append_pair("labelSelector", label_selector);
+                query.append_pair("limit", PAGE_LIMIT);
+                if let Some(ref token) = continue_token {
+                    query.append_pair("continue", token);
+                }
+            }
+
+            let page: DeploymentList = match self.get(&page_url) {
+                Ok(page) => page,
+                // the cluster doesn't serve this api group version at all, e.g. `batch/v2alpha1`
+                Err(HelmError::KubeApi { status: 404, .. }) => return Ok(items),
+                Err(e) => return Err(e),
+            };
+            items.extend(page.items);
+
+            // an empty or missing continue token means this was the last page
+            continue_token = page.metadata
+                .and_then(|metadata| metadata.continue_token)
+                .and_then(|token| if token.is_empty() { None } else { Some(token) });
+
+            if continue_token.is_none() {
+                return Ok(items);
+            }
+        }
+    }
+}
+
+/// A page of a kubernetes list response, e.g. `GET .../deployments`.
+///
+/// Every workload kind helm can create has the same metadata layout, so the
+/// daemonset, statefulset, job and cronjob listings deserialize into this too.
+#[derive(Debug, Deserialize)]
+pub struct DeploymentList {
+    pub metadata: Option<ListMeta>,
+    #[serde(default)]
+    pub items: Vec<Deployment>,
+}
+
+// what an api group version serves, e.g. `GET /apis/apps/v1`
+#[derive(Debug, Deserialize)]
+struct ApiResourceList {
+    #[serde(default)]
+    resources: Vec<ApiResource>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiResource {
+    name: String,
+}
+
+// one line of a watch response
+#[derive(Debug, Deserialize)]
+struct WatchEvent {
+    #[serde(rename = "type")]
+    kind: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ListMeta {
+    #[serde(rename = "continue")]
+    pub continue_token: Option<String>,
+    #[serde(rename = "resourceVersion")]
+    pub resource_version: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Deployment {
+    pub metadata: ObjectMeta,
+    pub spec: Option<WorkloadSpec>,
+    pub status: Option<WorkloadStatus>,
+}
+
+impl Deployment {
+    /// Whether the controller has rolled out the latest spec and every
+    /// desired replica is ready. Kinds without replicas are always ready.
+    pub fn is_ready(&self) -> bool {
+        let desired = match self.spec.as_ref().and_then(|spec| spec.replicas) {
+            Some(desired) => desired,
+            None => return true,
+        };
+        let status = match self.status {
+            Some(ref status) => status,
+            None => return false,
+        };
+        let observed = match (status.observed_generation, self.metadata.generation) {
+            (Some(observed), Some(generation)) => observed >= generation,
+            _ => true,
+        };
+
+        observed && status.ready_replicas.unwrap_or(0) >= desired
+    }
+
+    /// Whether the status has condition `condition_type`, e.g. a job's `Failed`.
+    pub fn has_condition(&self, condition_type: &str) -> bool {
+        self.status.as_ref()
+            .map(|status| status.conditions.iter()
+                .any(|condition| condition.condition_type == condition_type && condition.status == "True"))
+            .unwrap_or(false)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WorkloadSpec {
+    pub replicas: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WorkloadStatus {
+    #[serde(rename = "observedGeneration")]
+    pub observed_generation: Option<u64>,
+    #[serde(rename = "readyReplicas")]
+    pub ready_replicas: Option<u64>,
+    /// how many pods of a job completed successfully
+    pub succeeded: Option<u64>,
+    #[serde(default)]
+    pub conditions: Vec<Condition>,
+}
+
+/// Any object, when all we care about is its metadata.
+#[derive(Debug, Deserialize)]
+pub struct Object {
+    pub metadata: ObjectMeta,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ObjectMeta {
+    pub name: String,
+    pub uid: Option<String>,
+    pub namespace: Option<String>,
+    pub generation: Option<u64>,
+    #[serde(rename = "resourceVersion")]
+    pub resource_version: Option<String>,
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+    #[serde(default)]
+    pub annotations: HashMap<String, String>,
+}
+
+/// A custom resource definition, as far as telling whether it's served yet.
+#[derive(Debug, Deserialize)]
+pub struct CustomResourceDefinition {
+    pub status: Option<CrdStatus>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CrdStatus {
+    #[serde(default)]
+    pub conditions: Vec<Condition>,
+}
+
+/// One condition of an object's status, e.g. a CRD being `Established`.
+#[derive(Debug, Deserialize)]
+pub struct Condition {
+    #[serde(rename = "type")]
+    pub condition_type: String,
+    pub status: String,
+}
+
+impl CustomResourceDefinition {
+    /// Whether the apiserver serves the resources it defines.
+    pub fn is_established(&self) -> bool {
+        self.status.as_ref()
+            .map(|status| status.conditions.iter()
+                .any(|condition| condition.condition_type == "Established" && condition.status == "True"))
+            .unwrap_or(false)
+    }
+}
+
+// an unexpected response, with just enough of its body to tell what went wrong
+fn api_error(status: u32, url: &Url, body: String) -> HelmError {
+    let mut body = body.trim().to_string();
+    if body.len() > MAX_ERROR_BODY {
+        let mut end = MAX_ERROR_BODY;
+        while !body.is_char_boundary(end) {
+            end -= 1;
+        }
+        body.truncate(end);
+        body.push_str("...");
+    }
+    HelmError::KubeApi {
+        status: status,
+        url: url.to_string(),
+        body: body,
+    }
+}
+
+/// The body of a request creating a namespace.
+#[derive(Debug, Serialize)]
+pub struct Namespace {
+    #[serde(rename = "apiVersion")]
+    pub api_version: &'static str,
+    pub kind: &'static str,
+    pub metadata: NewObjectMeta,
+}
+
+impl Namespace {
+    pub fn new(name: &str) -> Self {
+        Namespace {
+            api_version: "v1",
+            kind: "Namespace",
+            metadata: NewObjectMeta {
+                name: name.to_string(),
+                annotations: HashMap::new(),
+            },
+        }
+    }
+}
+
+/// The body of a request creating an empty configmap, e.g. a release's lock.
+#[derive(Debug, Serialize)]
+pub struct ConfigMap {
+    #[serde(rename = "apiVersion")]
+    pub api_version: &'static str,
+    pub kind: &'static str,
+    pub metadata: NewObjectMeta,
+}
+
+impl ConfigMap {
+    pub fn new(name: &str, annotations: HashMap<String, String>) -> Self {
+        ConfigMap {
+            api_version: "v1",
+            kind: "ConfigMap",
+            metadata: NewObjectMeta {
+                name: name.to_string(),
+                annotations: annotations,
+            },
+        }
+    }
+}
+
+/// The metadata of an object we're about to create.
+#[derive(Debug, Serialize)]
+pub struct NewObjectMeta {
+    pub name: String,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub annotations: HashMap<String, String>,
+}
+
+// the body of a DELETE that only goes through if nobody changed the object since
+#[derive(Debug, Serialize)]
+struct DeleteOptions {
+    kind: &'static str,
+    #[serde(rename = "apiVersion")]
+    api_version: &'static str,
+    preconditions: Preconditions,
+}
+
+#[derive(Debug, Serialize)]
+struct Preconditions {
+    #[serde(rename = "resourceVersion", skip_serializing_if = "Option::is_none")]
+    resource_version: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEPLOYMENTS: Resource = Resource { group: "apis/apps/v1", name: "deployments" };
+
+    fn client(mock: &MockKubeClient) -> Client {
+        Client::new("https://127.0.0.1:6443", Box::new(mock.clone())).unwrap()
+    }
+
+    fn deployment(json: &str) -> Deployment {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn ready_once_every_replica_of_the_latest_spec_is() {
+        assert!(deployment(r#"{"metadata": {"name": "web", "generation": 2}, "spec": {"replicas": 2},
+            "status": {"observedGeneration": 2, "readyReplicas": 2}}"#).is_ready());
+        // the controller hasn't seen the latest spec yet
+        assert!(!deployment(r#"{"metadata": {"name": "web", "generation": 3}, "spec": {"replicas": 2},
+            "status": {"observedGeneration": 2, "readyReplicas": 2}}"#).is_ready());
+        assert!(!deployment(r#"{"metadata": {"name": "web", "generation": 2}, "spec": {"replicas": 2},
+            "status": {"observedGeneration": 2, "readyReplicas": 1}}"#).is_ready());
+        assert!(!deployment(r#"{"metadata": {"name": "web"}, "spec": {"replicas": 1}}"#).is_ready());
+        // e.g. a daemonset
+        assert!(deployment(r#"{"metadata": {"name": "agent"}, "spec": {}}"#).is_ready());
+    }
+
+    #[test]
+    fn has_only_true_conditions() {
+        let job = deployment(r#"{"metadata": {"name": "migrate"}, "status": {"conditions": [
+            {"type": "Failed", "status": "True"}, {"type": "Complete", "status": "False"}]}}"#);
+        assert!(job.has_condition("Failed"));
+        assert!(!job.has_condition("Complete"));
+        assert!(!job.has_condition("Suspended"));
+        assert!(!deployment(r#"{"metadata": {"name": "migrate"}}"#).has_condition("Failed"));
+    }
+
+    #[test]
+    fn lists_every_page() {
+        let mock = MockKubeClient::new();
+        mock.respond("GET", "continue=next", 200, r#"{"metadata": {"continue": ""},
+            "items": [{"metadata": {"name": "api"}}]}"#);
+        mock.respond("GET", "/namespaces/prod/deployments", 200, r#"{"metadata": {"continue": "next"},
+            "items": [{"metadata": {"name": "web"}}]}"#);
+
+        let names: Vec<String> = client(&mock).list(DEPLOYMENTS, Some("prod"), "heritage=Tiller").unwrap()
+            .into_iter().map(|deployment| deployment.metadata.name).collect();
+        assert_eq!(names, vec!["web", "api"]);
+        assert_eq!(mock.requests().len(), 2);
+        assert!(mock.requests()[0].contains("labelSelector=heritage%3DTiller"));
+    }
+
+    #[test]
+    fn lists_nothing_of_unserved_kinds() {
+        let mock = MockKubeClient::new();
+        assert!(client(&mock).list(DEPLOYMENTS, None, "").unwrap().is_empty());
+    }
+
+    #[test]
+    fn bookmarks_are_no_change() {
+        let mock = MockKubeClient::new();
+        mock.respond("GET", "watch=true", 200, "{\"type\": \"BOOKMARK\", \"object\": {}}\n\n");
+        assert!(!client(&mock).changed_since(DEPLOYMENTS, Some("prod"), "", "41").unwrap());
+        assert!(mock.requests()[0].contains("resourceVersion=41"));
+    }
+
+    #[test]
+    fn other_events_are_changes() {
+        for body in &[
+            "{\"type\": \"BOOKMARK\", \"object\": {}}\n{\"type\": \"MODIFIED\", \"object\": {}}\n",
+            "{\"type\": \"ERROR\", \"object\": {\"code\": 410}}\n",
+            "not json\n",
+        ] {
+            let mock = MockKubeClient::new();
+            mock.respond("GET", "watch=true", 200, body);
+            assert!(client(&mock).changed_since(DEPLOYMENTS, Some("prod"), "", "41").unwrap(), "{}", body);
+        }
+    }
+
+    #[test]
+    fn expired_versions_are_changes_and_unserved_kinds_are_not() {
+        let expired = MockKubeClient::new();
+        expired.respond("GET", "watch=true", 410, "");
+        assert!(client(&expired).changed_since(DEPLOYMENTS, None, "", "41").unwrap());
+
+        let unserved = MockKubeClient::new();
+        assert!(!client(&unserved).changed_since(DEPLOYMENTS, None, "", "41").unwrap());
+
+        let forbidden = MockKubeClient::new();
+        forbidden.respond("GET", "watch=true", 403, "forbidden");
+        assert!(client(&forbidden).changed_since(DEPLOYMENTS, None, "", "41").is_err());
+    }
+
+    #[test]
+    fn rate_limiter_lets_a_burst_through_then_spaces_requests_out() {
+        let limiter = RateLimiter::new(10.0, 2);
+        let started = Instant::now();
+        limiter.acquire();
+        limiter.acquire();
+        assert!(started.elapsed() < Duration::from_millis(50));
+
+        // a token every 100ms from here on
+        limiter.acquire();
+        limiter.acquire();
+        assert!(started.elapsed() >= Duration::from_millis(180));
+    }
+
+    #[test]
+    fn rate_limited_clients_still_answer() {
+        let mock = MockKubeClient::new();
+        mock.respond("GET", "/apis/apps/v1", 200, r#"{"resources": [{"name": "deployments"}]}"#);
+        let client = client(&mock).rate_limited(Some(RateLimiter::new(100.0, 1)));
+        assert_eq!(client.group_resources("apis/apps/v1").unwrap(), Some(vec!["deployments".to_string()]));
+        assert_eq!(client.group_resources("apis/batch/v1").unwrap(), None);
+    }
+}
@@ -0,0 +1,227 @@
+use std::collections::BTreeMap;
+use super::error::HelmError;
+use super::serde_yaml::{
+    self,
+    Value,
+};
+use super::Impersonate;
+
+
+// the names of the only cluster, user and context of a kubeconfig we generate
+const CLUSTER_NAME: &'static str = "default_cluster";
+const USER_NAME: &'static str = "default_user";
+const CONTEXT_NAME: &'static str = "default_context";
+
+/// The context we add to a kubeconfig to pair a cluster and user picked on their own.
+pub const SELECTED_CONTEXT: &'static str = "helm-resource";
+
+/// The parts of a kubeconfig file we need to reach the apiserver ourselves,
+/// or to write one for helm.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct KubeConfig {
+    #[serde(rename = "apiVersion", skip_serializing_if = "Option::is_none")]
+    pub api_version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kind: Option<String>,
+    #[serde(default)]
+    pub clusters: Vec<NamedCluster>,
+    #[serde(default)]
+    pub contexts: Vec<NamedContext>,
+    #[serde(default)]
+    pub users: Vec<NamedUser>,
+    #[serde(rename = "current-context", skip_serializing_if = "Option::is_none")]
+    pub current_context: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NamedCluster {
+    pub name: String,
+    pub cluster: Cluster,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Cluster {
+    pub server: String,
+    /// base64 PEM
+    #[serde(rename = "certificate-authority-data", skip_serializing_if = "Option::is_none")]
+    pub certificate_authority_data: Option<String>,
+    #[serde(rename = "certificate-authority", skip_serializing_if = "Option::is_none")]
+    pub certificate_authority: Option<String>,
+    #[serde(rename = "insecure-skip-tls-verify", default, skip_serializing_if = "is_false")]
+    pub insecure_skip_tls_verify: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NamedContext {
+    pub name: String,
+    pub context: Context,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Context {
+    pub cluster: String,
+    pub user: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NamedUser {
+    pub name: String,
+    pub user: User,
+}
+
+/// The credentials of a user. Auth plugins and exec credentials are left
+/// to helm, the apiserver calls we make ourselves go unauthenticated then.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct User {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    /// base64 PEM
+    #[serde(rename = "client-certificate-data", skip_serializing_if = "Option::is_none")]
+    pub client_certificate_data: Option<String>,
+    /// base64 PEM
+    #[serde(rename = "client-key-data", skip_serializing_if = "Option::is_none")]
+    pub client_key_data: Option<String>,
+    /// the user to impersonate
+    #[serde(rename = "as", skip_serializing_if = "Option::is_none")]
+    pub impersonate: Option<String>,
+    #[serde(rename = "as-groups", default, skip_serializing_if = "Vec::is_empty")]
+    pub impersonate_groups: Vec<String>,
+}
+
+impl KubeConfig {
+    /// A kubeconfig of just `cluster` and `user`, deploying to `namespace`.
+    pub fn single(cluster: Cluster, user: User, namespace: Option<&str>) -> Self {
+        KubeConfig {
+            api_version: Some("v1".to_string()),
+            kind: Some("Config".to_string()),
+            clusters: vec![NamedCluster {
+                name: CLUSTER_NAME.to_string(),
+                cluster: cluster,
+            }],
+            contexts: vec![NamedContext {
+                name: CONTEXT_NAME.to_string(),
+                context: Context {
+                    cluster: CLUSTER_NAME.to_string(),
+                    user: USER_NAME.to_string(),
+                    namespace: namespace.map(|namespace| namespace.to_string()),
+                },
+            }],
+            users: vec![NamedUser {
+                name: USER_NAME.to_string(),
+                user: user,
+            }],
+            current_context: Some(CONTEXT_NAME.to_string()),
+        }
+    }
+
+    pub fn parse(raw: &str) -> Result<Self, HelmError> {
+        serde_yaml::from_str(raw)
+            .map_err(|e| HelmError::from(e).context("parsing the kubeconfig"))
+    }
+
+    pub fn to_yaml(&self) -> Result<String, HelmError> {
+        serde_yaml::to_string(self)
+            .map_err(|e| HelmError::from(e).context("writing the kubeconfig"))
+    }
+
+    /// The `context` given, or the current context.
+    pub fn context(&self, context: Option<&str>) -> Result<&Context, HelmError> {
+        let name = match context.or(self.current_context.as_ref().map(|c| c as &str)) {
+            Some(name) => name,
+            None => return Err(HelmError::KubeConfig("no current-context is set".to_string())),
+        };
+        match self.contexts.iter().find(|c| c.name == name) {
+            Some(context) => Ok(&context.context),
+            None => Err(HelmError::KubeConfig(format!("no context named `{}`", name))),
+        }
+    }
+
+    /// The names of the cluster and user to use: `cluster` and `user` when
+    /// given, or else those of the `context` given or of the current context.
+    pub fn select(&self, context: Option<&str>, cluster: Option<&str>, user: Option<&str>)
+        -> Result<(String, String), HelmError>
+    {
+        if let (Some(cluster), Some(user)) = (cluster, user) {
+            return Ok((cluster.to_string(), user.to_string()));
+        }
+        let context = try!(self.context(context));
+        Ok((cluster.unwrap_or(&context.cluster).to_string(), user.unwrap_or(&context.user).to_string()))
+    }
+
+    /// The cluster and user called `cluster` and `user`.
+    pub fn resolve(&self, cluster: &str, user: &str) -> Result<(&Cluster, &User), HelmError> {
+        let found_cluster = match self.clusters.iter().find(|c| c.name == cluster) {
+            Some(found) => &found.cluster,
+            None => return Err(HelmError::KubeConfig(format!("no cluster named `{}`", cluster))),
+        };
+        let found_user = match self.users.iter().find(|u| u.name == user) {
+            Some(found) => &found.user,
+            None => return Err(HelmError::KubeConfig(format!("no user named `{}`", user))),
+        };
+        Ok((found_cluster, found_user))
+    }
+}
+
+/// `raw` with a `SELECTED_CONTEXT` of `cluster` and `user` added, deploying to `namespace`,
+/// so that helm can be pointed at them.
+pub fn add_context(raw: &str, cluster: &str, user: &str, namespace: Option<&str>) -> Result<String, HelmError> {
+    let key = |name: &str| Value::String(name.to_string());
+    let mut kube_config: Value = try!(serde_yaml::from_str(raw)
+        .map_err(|e| HelmError::from(e).context("parsing the kubeconfig")));
+
+    let mut context = BTreeMap::new();
+    context.insert(key("cluster"), key(cluster));
+    context.insert(key("user"), key(user));
+    if let Some(namespace) = namespace {
+        context.insert(key("namespace"), key(namespace));
+    }
+    let mut named = BTreeMap::new();
+    named.insert(key("name"), key(SELECTED_CONTEXT));
+    named.insert(key("context"), Value::Mapping(context));
+
+    if let Value::Mapping(ref mut kube_config) = kube_config {
+        let contexts = kube_config.entry(key("contexts")).or_insert_with(|| Value::Sequence(Vec::new()));
+        match *contexts {
+            Value::Sequence(ref mut contexts) => contexts.push(Value::Mapping(named)),
+            _ => *contexts = Value::Sequence(vec![Value::Mapping(named)]),
+        }
+    }
+    serde_yaml::to_string(&kube_config).map_err(HelmError::from)
+}
+
+/// `raw` with kubectl's `as` and `as-groups` added to the user named `user`,
+/// so that helm acts as whoever `impersonate` says.
+pub fn impersonate(raw: &str, user: &str, impersonate: &Impersonate) -> Result<String, HelmError> {
+    let key = |name: &str| Value::String(name.to_string());
+    let mut kube_config: Value = try!(serde_yaml::from_str(raw)
+        .map_err(|e| HelmError::from(e).context("parsing the kubeconfig")));
+
+    if let Value::Mapping(ref mut kube_config) = kube_config {
+        if let Some(&mut Value::Sequence(ref mut users)) = kube_config.get_mut(&key("users")) {
+            for named in users.iter_mut() {
+                let named = match *named {
+                    Value::Mapping(ref mut named) if named.get(&key("name")) == Some(&key(user)) => named,
+                    _ => continue,
+                };
+                if let Some(&mut Value::Mapping(ref mut fields)) = named.get_mut(&key("user")) {
+                    fields.insert(key("as"), key(&impersonate.user));
+                    if !impersonate.groups.is_empty() {
+                        fields.insert(key("as-groups"),
+                            Value::Sequence(impersonate.groups.iter().map(|group| key(group)).collect()));
+                    }
+                }
+            }
+        }
+    }
+    serde_yaml::to_string(&kube_config).map_err(HelmError::from)
+}
+
+fn is_false(value: &bool) -> bool {
+    !*value
+}
@@ -5,300 +5,4206 @@ extern crate serde_json;
 extern crate serde_yaml;
 extern crate curl;
 extern crate md5;
+extern crate sha2;
+extern crate blake3;
 extern crate mktemp;
 extern crate base64;
 extern crate url;
+extern crate semver;
 
+mod builder;
+mod chartmuseum;
+mod diff;
+mod digest;
 mod error;
+mod exec;
+#[cfg(any(test, feature = "test-fixtures"))]
+pub mod fixtures;
+mod http;
+mod kube;
+mod kubeconfig;
+mod lock;
+pub mod logger;
+mod notify;
+mod redact;
+mod repo;
+mod secrets;
+pub mod values;
+mod vault;
 
-use std::collections::HashMap;
-use self::error::HelmError;
-use self::serde::Deserialize;
-use self::serde_json::{
-    Map,
-    Value,
+use std::collections::{
+    BTreeMap,
+    HashMap,
+    HashSet,
 };
-use self::curl::easy::Easy;
-use self::md5::Context;
+pub use self::builder::{
+    ChartBuilder,
+    ConfigBuilder,
+};
+pub use self::chartmuseum::{
+    ChartMuseum,
+    ChartMuseumConfig,
+};
+pub use self::diff::{
+    ManifestDiff,
+    ResourceChange,
+    ResourceDiff,
+};
+pub use self::digest::DigestAlgorithm;
+use self::digest::Hasher;
+pub use self::error::HelmError;
+pub use self::exec::{
+    CommandExecutor,
+    ShellExecutor,
+};
+#[cfg(any(test, feature = "test-util"))]
+pub use self::exec::FakeExecutor;
+use self::kube::{
+    Auth,
+    CurlClient,
+    Resource,
+    Timeouts,
+};
+pub use self::kube::KubeClient;
+#[cfg(any(test, feature = "test-util"))]
+pub use self::kube::MockKubeClient;
+use self::kubeconfig::KubeConfig;
+pub use self::lock::{
+    LockMode,
+    ReleaseLock,
+};
+use self::logger::{
+    Level,
+    LogFormat,
+    Phase,
+    Timing,
+    Timings,
+};
+pub use self::notify::{
+    Notifier,
+    NotifyConfig,
+};
+use self::repo::RepoIndex;
+pub use self::repo::ChartVersion;
+use self::secrets::{
+    private_file,
+    ShreddedFile,
+    Sops,
+};
+pub use self::secrets::SopsConfig;
+pub use self::vault::{
+    AppRole,
+    Vault,
+    VaultConfig,
+};
+use self::serde_json::Value;
 use self::mktemp::Temp;
-use self::url::{
-    Url,
-    ParseError,
+use self::semver::{
+    Version,
+    VersionReq,
 };
 use self::rustache::{
     HashBuilder,
     Render,
 };
+use std::env;
+use std::fmt;
 use std::io::{
+    Read,
     Write,
+};
+use std::fs::{
     self,
+    File,
+};
+use std::os::unix::fs::{
+    symlink,
+    PermissionsExt,
 };
-use std::fs::File;
-use std::process::Command;
+use std::path::{
+    Path,
+    PathBuf,
+};
+use std::process::{
+    Command,
+    Output,
+};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{
+    Duration,
+    Instant,
+};
+
+
+// where kubernetes mounts the pod's service account, for `in_cluster`
+const SERVICE_ACCOUNT_DIR: &'static str = "/var/run/secrets/kubernetes.io/serviceaccount";
+
+// how long a repo index in `cache_dir` is fresh for
+const DEFAULT_REPO_CACHE_TTL_SECS: u64 = 300;
+
+// how long the one request of a fast check may take unless configured otherwise,
+// so a webhook-driven check answers within a couple of seconds or fails
+const FAST_CHECK_CONNECT_TIMEOUT_SECS: u64 = 1;
+const FAST_CHECK_TIMEOUT_SECS: u64 = 3;
+
+// what serves repos with `s3://` and `gs://` urls, by the name they install as
+const HELM_S3_PLUGIN: (&'static str, &'static str) = ("s3", "https://github.com/hypnoglow/helm-s3.git");
+const HELM_GCS_PLUGIN: (&'static str, &'static str) = ("gcs", "https://github.com/hayorov/helm-gcs.git");
+
+// how long to use an ECR token for, a little under the 12 hours it lasts
+const ECR_TOKEN_SECS: u64 = 11 * 60 * 60;
+
+// how often to check whether the CRDs installed ahead of a chart are served yet
+const CRD_POLL_SECS: u64 = 2;
+
+// the repo charts without a path come from
+const DEFAULT_REPO: &'static str = "stable";
+
+// the line helm 3 heads `helm get values` with
+const USER_VALUES_HEADER: &'static str = "USER-SUPPLIED VALUES:";
+
+// every kind of workload a release can be made of, with the api group
+// versions that served it over the years, newest first
+const WORKLOAD_KINDS: &'static [(&'static str, &'static [&'static str])] = &[
+    ("deployments", &["apis/apps/v1", "apis/apps/v1beta2", "apis/extensions/v1beta1"]),
+    ("daemonsets", &["apis/apps/v1", "apis/apps/v1beta2", "apis/extensions/v1beta1"]),
+    ("statefulsets", &["apis/apps/v1", "apis/apps/v1beta2", "apis/apps/v1beta1"]),
+    ("jobs", &["apis/batch/v1"]),
+    ("cronjobs", &["apis/batch/v1", "apis/batch/v1beta1", "apis/batch/v2alpha1"]),
+];
+
+// the workloads that have replicas we can wait on
+const ROLLOUT_KINDS: &'static [&'static str] = &["deployments", "statefulsets"];
+
+const PODS: Resource = Resource { group: "api/v1", name: "pods" };
+
+const JOBS: Resource = Resource { group: "apis/batch/v1", name: "jobs" };
+
+// where tiller keeps release revisions by default
+const CONFIGMAPS: Resource = Resource { group: "api/v1", name: "configmaps" };
+
+// where helm 3 keeps release revisions
+const SECRETS: Resource = Resource { group: "api/v1", name: "secrets" };
+
+// how a generation of helm, or a cluster of its own mind, labels the workloads of its releases
+#[derive(Debug, Clone)]
+struct LabelConvention {
+    // the labels every workload of a release has
+    selector: Vec<(String, String)>,
+    release: String,
+    release_annotation: Option<String>,
+    chart: String,
+}
 
+impl LabelConvention {
+    // helm 2, deployed through tiller, and helm 3
+    fn defaults() -> Vec<LabelConvention> {
+        vec![
+            LabelConvention {
+                selector: vec![("heritage".to_string(), "Tiller".to_string())],
+                release: "release".to_string(),
+                release_annotation: None,
+                chart: "chart".to_string(),
+            },
+            LabelConvention {
+                selector: vec![("app.kubernetes.io/managed-by".to_string(), "Helm".to_string())],
+                release: "app.kubernetes.io/instance".to_string(),
+                release_annotation: Some("meta.helm.sh/release-name".to_string()),
+                chart: "helm.sh/chart".to_string(),
+            },
+        ]
+    }
+
+    // helm 3's, with whatever the source says instead
+    fn custom(selector: Option<HashMap<String, String>>, labels: ReleaseLabels) -> LabelConvention {
+        let mut selector: Vec<(String, String)> = match selector {
+            Some(selector) => selector.into_iter().collect(),
+            None => vec![("app.kubernetes.io/managed-by".to_string(), "Helm".to_string())],
+        };
+        // the same selector every time, whatever order the labels came in
+        selector.sort();
+        LabelConvention {
+            selector: selector,
+            // helm 3 annotates what it deploys either way, unless the release is told apart by a label
+            release_annotation: if labels.release.is_none() {
+                Some("meta.helm.sh/release-name".to_string())
+            } else {
+                None
+            },
+            release: labels.release.unwrap_or_else(|| "app.kubernetes.io/instance".to_string()),
+            chart: labels.chart.unwrap_or_else(|| "helm.sh/chart".to_string()),
+        }
+    }
+
+    // whether `labels` has every label of the selector
+    fn selects(&self, labels: &HashMap<String, String>) -> bool {
+        self.selector.iter().all(|&(ref label, ref value)| labels.get(label) == Some(value))
+    }
+}
 
-const KUBE_CONFIG: &'static str = include_str!("../templates/kube-config.mo");
-const SH_PATH: &'static str = "/bin/sh";
+// how long to wait between checks on a rollout
+const ROLLOUT_POLL_SECS: u64 = 5;
 
+// how long to wait for hook jobs helm left running, without a `hook_timeout`
+const DEFAULT_HOOK_WAIT_SECS: u64 = 300;
 
-#[derive(Debug, Serialize, Deserialize)]
+
+/// A chart deployed, or to be deployed, as a release.
+/// Build one with `Chart::builder`, fields may be added at any time.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[non_exhaustive]
 pub struct Chart {
     pub release: String,
     pub name: String,
     pub version: Option<String>,
     pub overrides: Option<HashMap<String, Value>>,
+    /// deploy the chart from this directory instead of a repo,
+    /// or from an OCI registry with an `oci://` reference
+    pub path: Option<String>,
+    /// the repo the chart comes from without a `path`, e.g. one of `Config::repos`, `stable` by default
+    pub repo: Option<String>,
+    /// plain values files, applied before any of the other values
+    pub values_files: Option<Vec<String>>,
+    /// values files in JSON, e.g. written by tools that don't speak YAML, applied after `values_files`
+    pub values_json_files: Option<Vec<String>>,
+    /// a JSON object of values, applied just before the overrides
+    pub values_json: Option<String>,
+    /// sops encrypted values files, decrypted just for the upgrade
+    pub secret_values_files: Option<Vec<String>>,
+    /// dotted paths of overrides to mask in the logs, e.g. `db.password`
+    pub secret_keys: Option<Vec<String>>,
+    /// a mustache values file, rendered with `template_vars` just for the upgrade
+    pub values_template: Option<String>,
+    pub template_vars: Option<HashMap<String, String>>,
+    /// refuse to deploy the chart unless its provenance checks out against the keyring
+    pub verify: Option<bool>,
+    /// consider prerelease versions too, e.g. `1.2.0-rc.1` (`--devel`)
+    pub devel: Option<bool>,
+    /// how the values sources are layered, `helm` by default
+    pub merge_strategy: Option<MergeStrategy>,
+    /// where to deploy the release, only for sources without a namespace
+    pub namespace: Option<String>,
 }
 
-pub type Charts = Vec<Chart>;
+impl Chart {
+    pub fn builder(release: &str, name: &str) -> ChartBuilder {
+        ChartBuilder::new(release, name)
+    }
 
-pub struct Helm {
-    namespace: String,
-    server: String,
-    username: String,
-    password: String,
-    kube_config: Temp,
-    kube_ca_cert: Option<Temp>,
+    // the registry host of a chart referenced as `oci://host/repository/name`
+    fn oci_host(&self) -> Option<&str> {
+        self.path.as_ref()
+            .and_then(|path| if path.starts_with("oci://") { Some(&path["oci://".len()..]) } else { None })
+            .and_then(|reference| reference.split('/').next())
+    }
+
+    // the repo the chart comes from, unless it has a path
+    fn repo(&self) -> &str {
+        self.repo.as_ref().map(|repo| repo as &str).unwrap_or(DEFAULT_REPO)
+    }
+
+    // what to hand helm to find the chart
+    fn reference(&self) -> String {
+        match self.path {
+            Some(ref path) => path.clone(),
+            None => format!("{}/{}", self.repo(), self.name),
+        }
+    }
 }
 
-pub struct Config {
-    pub url: String,
-    pub username: String,
-    pub password: String,
+pub type Charts = Vec<Chart>;
+
+/// A release as it's deployed, as opposed to a `Chart` that's to be deployed.
+#[derive(Debug, Clone, Serialize)]
+pub struct Release {
+    /// the release's name, without the source's prefix and suffix
+    pub name: String,
+    pub chart: String,
+    pub version: Option<String>,
+    pub app_version: Option<String>,
+    /// with the name, what identifies the release across namespaces
     pub namespace: String,
-    pub skip_tls_verify: Option<bool>,
-    pub ca_data: Option<String>,
+    pub revision: Option<u64>,
+    pub status: Option<ReleaseStatus>,
+    /// when the latest revision was made, as helm reports it
+    pub updated: Option<String>,
 }
 
-impl Helm {
-    pub fn configure(config: Config) -> Result<Self, HelmError> {
-        // check invariants
-        if config.ca_data.is_none() && !config.skip_tls_verify.unwrap_or(false) {
-            return Err(HelmError::NoCaData);
+pub type Releases = Vec<Release>;
+
+/// One revision of a release, as `helm history` tells it.
+#[derive(Debug, Clone, Serialize)]
+pub struct Revision {
+    pub revision: u64,
+    pub chart: String,
+    pub version: Option<String>,
+    pub app_version: Option<String>,
+    pub status: ReleaseStatus,
+    pub description: Option<String>,
+    /// when the revision was made, as helm reports it
+    pub updated: Option<String>,
+}
+
+/// What a chart says about itself in its Chart.yaml, and the values it defaults to.
+#[derive(Debug, Clone, Serialize)]
+pub struct ChartInfo {
+    pub name: String,
+    pub version: String,
+    pub app_version: Option<String>,
+    pub description: Option<String>,
+    pub home: Option<String>,
+    pub keywords: Vec<String>,
+    /// the chart's values.yaml
+    pub values: Value,
+}
+
+// the parts of a Chart.yaml we hand on
+#[derive(Deserialize)]
+struct ChartYaml {
+    name: String,
+    version: String,
+    #[serde(rename = "appVersion")]
+    app_version: Option<String>,
+    description: Option<String>,
+    home: Option<String>,
+    keywords: Option<Vec<String>>,
+}
+
+/// What a release is actually running, e.g. after deploying an unpinned chart.
+#[derive(Debug, Clone, Serialize)]
+pub struct ReleaseVersion {
+    pub chart: String,
+    pub version: Option<String>,
+    pub app_version: Option<String>,
+}
+
+/// The state of a release's latest revision.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum ReleaseStatus {
+    #[serde(rename = "deployed")]
+    Deployed,
+    #[serde(rename = "failed")]
+    Failed,
+    #[serde(rename = "pending-install")]
+    PendingInstall,
+    #[serde(rename = "pending-upgrade")]
+    PendingUpgrade,
+    #[serde(rename = "pending-rollback")]
+    PendingRollback,
+    #[serde(rename = "superseded")]
+    Superseded,
+    #[serde(rename = "deleting")]
+    Deleting,
+    #[serde(rename = "deleted")]
+    Deleted,
+    #[serde(rename = "unknown")]
+    Unknown,
+}
+
+impl ReleaseStatus {
+    /// Parse a status the way either helm prints it, e.g. `PENDING_UPGRADE` or `pending-upgrade`.
+    pub fn parse(status: &str) -> Self {
+        match &status.to_lowercase().replace('_', "-") as &str {
+            "deployed" => ReleaseStatus::Deployed,
+            "failed" => ReleaseStatus::Failed,
+            "pending-install" => ReleaseStatus::PendingInstall,
+            "pending-upgrade" => ReleaseStatus::PendingUpgrade,
+            "pending-rollback" => ReleaseStatus::PendingRollback,
+            "superseded" => ReleaseStatus::Superseded,
+            "deleting" | "uninstalling" => ReleaseStatus::Deleting,
+            "deleted" | "uninstalled" => ReleaseStatus::Deleted,
+            _ => ReleaseStatus::Unknown,
         }
+    }
 
-        // we'll store this config file for helm to use
-        let kube_config_path = try!(Temp::new_file());
-        let mut kube_config_file = try!(File::create(&kube_config_path));
-        let base_64_ca_data = config.ca_data
-            .as_ref()
-            .map(|c| base64::encode(c.trim().as_bytes()));
+    /// Whether helm refuses to upgrade a release in this state.
+    pub fn is_stuck(&self) -> bool {
+        match *self {
+            ReleaseStatus::Failed
+            | ReleaseStatus::PendingInstall
+            | ReleaseStatus::PendingUpgrade
+            | ReleaseStatus::PendingRollback => true,
+            _ => false,
+        }
+    }
+}
 
-        // generate k8s config file so helm can connect to our server
-        try!(HashBuilder::new()
-            .insert("skip_tls_verify", config.skip_tls_verify.unwrap_or(false))
-            .insert("url", &config.url as &str)
-            .insert("namespace", &config.namespace as &str)
-            .insert("username", &config.username as &str)
-            .insert("password", &config.password as &str)
-            .insert("ca_data", base_64_ca_data.as_ref().map(|s| s as &str).unwrap_or(""))
-            .render(KUBE_CONFIG, &mut kube_config_file));
+impl fmt::Display for ReleaseStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            ReleaseStatus::Deployed => "deployed",
+            ReleaseStatus::Failed => "failed",
+            ReleaseStatus::PendingInstall => "pending-install",
+            ReleaseStatus::PendingUpgrade => "pending-upgrade",
+            ReleaseStatus::PendingRollback => "pending-rollback",
+            ReleaseStatus::Superseded => "superseded",
+            ReleaseStatus::Deleting => "deleting",
+            ReleaseStatus::Deleted => "deleted",
+            ReleaseStatus::Unknown => "unknown",
+        })
+    }
+}
 
-        // make sure we wrote the file
-        try!(kube_config_file.flush());
+/// Which releases `Helm::list` returns, by the state of their latest revision.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub enum StatusFilter {
+    #[serde(rename = "deployed")]
+    Deployed,
+    #[serde(rename = "failed")]
+    Failed,
+    /// every release, including pending and deleted ones
+    #[serde(rename = "all")]
+    All,
+}
 
-        // create a file to store the ca data for the kubes api
-        let ca_cert_path = if let Some(ref ca_data) = config.ca_data {
-            let ca_cert_path = try!(Temp::new_file());
-            let mut ca_cert_file = try!(File::create(&ca_cert_path));
-            try!(ca_cert_file.write_all(ca_data.as_bytes()));
-            try!(ca_cert_file.flush());
-            Some(ca_cert_path)
-        } else {
-            None
-        };
+impl StatusFilter {
+    // the `helm list` flag for this filter
+    fn flag(&self) -> &'static str {
+        match *self {
+            StatusFilter::Deployed => "--deployed",
+            StatusFilter::Failed => "--failed",
+            StatusFilter::All => "--all",
+        }
+    }
+}
 
-        let helm = Helm {
-            namespace: config.namespace,
-            server: config.url,
-            username: config.username,
-            password: config.password,
-            kube_config: kube_config_path,
-            kube_ca_cert: ca_cert_path,
-        };
+/// What to do about a release a crashed deploy left failed or pending.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub enum Recover {
+    /// go back to the last revision that deployed, or delete a release that never did
+    #[serde(rename = "rollback")]
+    Rollback,
+    /// purge the release so it's installed from scratch
+    #[serde(rename = "delete")]
+    Delete,
+    /// leave it alone and fail the deploy
+    #[serde(rename = "fail")]
+    Fail,
+}
 
-        // init helm
-        try!(helm.run("helm init --client-only 1>&2"));
+/// Knobs for `Helm::upgrade`, see `UpgradeOptions::default()` for the defaults.
+#[derive(Debug, Clone)]
+pub struct UpgradeOptions {
+    /// install the release if it doesn't exist yet (`--install`)
+    pub install: bool,
+    /// wait until all pods, PVCs and services are ready before returning (`--wait`)
+    pub wait: bool,
+    /// seconds to wait for any individual kubernetes operation (`--timeout`)
+    pub timeout: Option<u64>,
+    /// render the release and print it without changing the cluster (`--dry-run --debug`)
+    pub dry_run: bool,
+    /// pipe the rendered manifests through this before applying them (`--post-renderer`)
+    pub post_renderer: Option<PostRenderer>,
+    /// don't run the chart's upgrade and install hooks (`--no-hooks`)
+    pub no_hooks: bool,
+    /// seconds any one hook job may run before the upgrade is killed. helm only
+    /// has the one `--timeout` for hooks and waiting both, so we enforce this ourselves
+    pub hook_timeout: Option<u64>,
+    /// watch the release's hook jobs, copying their pods' logs to stderr, failing as soon as
+    /// one fails and waiting for any helm left running
+    pub watch_hooks: bool,
+    /// start from the release's current values instead of the chart's (`--reuse-values`)
+    pub reuse_values: bool,
+    /// start from the chart's values, whatever the release had (`--reset-values`)
+    pub reset_values: bool,
+    /// shown next to the revision in `helm history` (`--description`)
+    pub description: Option<String>,
+    /// replace resources that can't be patched, e.g. on immutable field changes (`--force`)
+    pub force: bool,
+    /// restart every pod of the release (`--recreate-pods`)
+    pub recreate_pods: bool,
+    /// leave the chart's `crds/` directory alone, e.g. when they're managed elsewhere
+    /// or were installed first with `install_crds` (`--skip-crds`, helm 3 only)
+    pub skip_crds: bool,
+    /// check the release isn't stuck before upgrading it, and if it is, what to do
+    pub recover: Option<Recover>,
+    /// more arguments for flags we don't wrap, appended after `Config::extra_args`
+    pub extra_args: Vec<String>,
+    /// kill the upgrade if it's still running at this point
+    pub deadline: Option<Instant>,
+}
 
-        // update helm repos
-        try!(helm.run("helm repo update"));
+/// An executable the rendered manifests are piped through, e.g. a kustomize wrapper.
+/// It reads the manifests on stdin and writes the patched ones to stdout.
+#[derive(Debug, Clone)]
+pub struct PostRenderer {
+    pub path: String,
+    pub args: Vec<String>,
+}
 
-        Ok(helm)
+impl Default for UpgradeOptions {
+    fn default() -> Self {
+        UpgradeOptions {
+            install: true,
+            wait: false,
+            timeout: None,
+            dry_run: false,
+            post_renderer: None,
+            no_hooks: false,
+            hook_timeout: None,
+            watch_hooks: false,
+            reuse_values: false,
+            reset_values: false,
+            description: None,
+            force: false,
+            recreate_pods: false,
+            skip_crds: false,
+            recover: None,
+            extra_args: Vec::new(),
+            deadline: None,
+        }
     }
+}
 
-    fn run(&self, cmd: &str) -> Result<String, HelmError> {
-        // log the command we're running
-        try!(io::stderr().write(format!("Running `{}`.\n", cmd).as_bytes()));
+/// Knobs for `Helm::delete`, all off by default but `ignore_missing`.
+#[derive(Debug, Deserialize)]
+pub struct DeleteOptions {
+    /// also remove the release history so the name can be reused (`--purge`),
+    /// which helm 3 always does
+    #[serde(default)]
+    pub purge: bool,
+    /// seconds to wait for any individual kubernetes operation (`--timeout`)
+    pub timeout: Option<u64>,
+    /// don't run the release's delete hooks (`--no-hooks`)
+    #[serde(default)]
+    pub no_hooks: bool,
+    /// a release that's already gone counts as deleted instead of failing,
+    /// e.g. for teardowns re-run after a partial one
+    #[serde(default = "ignore_missing_default")]
+    pub ignore_missing: bool,
+}
 
-        let output = try!(Command::new(SH_PATH)
-            .env("KUBECONFIG", &self.kube_config.to_path_buf().to_string_lossy().into_owned())
-            .arg("-c")
-            .arg(cmd)
-            .output());
+impl Default for DeleteOptions {
+    fn default() -> Self {
+        DeleteOptions {
+            purge: false,
+            timeout: None,
+            no_hooks: false,
+            ignore_missing: true,
+        }
+    }
+}
 
-        // log things to stderr since stdout is reserved
-        try!(io::stderr().write(&output.stdout));
-        try!(io::stderr().write(&output.stderr));
-        try!(io::stderr().flush());
+fn ignore_missing_default() -> bool {
+    DeleteOptions::default().ignore_missing
+}
 
-        if !output.status.success() {
-            return Err(HelmError::CmdFailed(cmd.to_string()));
+/// A helm plugin to install while configuring, e.g. helm-diff.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Plugin {
+    /// anything `helm plugin install` accepts, usually a git url
+    pub url: String,
+    pub version: Option<String>,
+}
+
+/// AWS credentials for the helm-s3 plugin, for repos with `s3://` urls.
+/// Without keys the plugin falls back to the worker's IAM role.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AwsConfig {
+    pub access_key_id: Option<String>,
+    pub secret_access_key: Option<String>,
+    /// for temporary credentials
+    pub session_token: Option<String>,
+    /// the region of the buckets
+    pub region: Option<String>,
+}
+
+/// GCP credentials for the helm-gcs plugin, for repos with `gs://` urls.
+/// Without a key the plugin falls back to the worker's default credentials.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct GcpConfig {
+    /// the JSON key of a service account that can read the buckets
+    pub service_account_key: Option<String>,
+}
+
+/// How to install tiller while configuring, for clusters that don't run it yet.
+#[derive(Debug, Clone, Deserialize)]
+pub struct InstallTiller {
+    /// the service account tiller runs as
+    pub service_account: Option<String>,
+    /// block until the tiller deployment is ready
+    #[serde(default)]
+    pub wait: bool,
+}
+
+/// PEM encoded client credentials for a tiller that only accepts TLS.
+#[derive(Debug, Clone, Deserialize)]
+pub struct TillerTls {
+    pub cert: String,
+    pub key: String,
+    /// verify tiller's certificate against this CA
+    pub ca_cert: Option<String>,
+}
+
+/// Someone to act as instead of the user the credentials belong to.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Impersonate {
+    pub user: String,
+    #[serde(default)]
+    pub groups: Vec<String>,
+}
+
+/// A chart repository to add while configuring, and the credentials to read it.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Repo {
+    pub name: String,
+    pub url: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// a PEM client certificate for repos behind mutual TLS, with `key_data`
+    pub cert_data: Option<String>,
+    pub key_data: Option<String>,
+    /// the PEM CA certificate the repo's serving certificate is signed by
+    pub ca_data: Option<String>,
+}
+
+impl Repo {
+    fn has_tls(&self) -> bool {
+        self.cert_data.is_some() || self.key_data.is_some() || self.ca_data.is_some()
+    }
+}
+
+/// A chart repository helm knows about, as listed by `helm repo list`.
+#[derive(Debug, Clone, Serialize)]
+pub struct RepoEntry {
+    pub name: String,
+    pub url: String,
+}
+
+/// An OCI registry charts can be pushed to and deployed from, and how to log in there.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Registry {
+    /// e.g. `registry.example.com`
+    pub host: String,
+    /// where under the host charts go, e.g. `team/charts`
+    pub repository: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// log in to ECR with a token for the `aws` credentials, or the worker's IAM role
+    pub ecr: Option<EcrLogin>,
+    /// log in to ACR as a service principal
+    pub acr: Option<AcrLogin>,
+}
+
+/// Logging in to an ECR registry, with a token the aws cli gets for us.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct EcrLogin {
+    /// the registry's region, taken from its host by default
+    pub region: Option<String>,
+}
+
+/// Logging in to an ACR registry as a service principal.
+#[derive(Debug, Clone, Deserialize)]
+pub struct AcrLogin {
+    pub client_id: String,
+    pub client_secret: String,
+}
+
+/// How a chart's values files, templates and overrides are combined.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub enum MergeStrategy {
+    /// Hand helm one `--values` per source and let it layer them.
+    #[serde(rename = "helm")]
+    Helm,
+    /// Merge every source ourselves, objects recursively and lists replaced,
+    /// and hand helm the one resolved file.
+    #[serde(rename = "deep")]
+    Deep,
+}
+
+/// What to do when the helm client and tiller are different minor versions.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub enum VersionSkew {
+    #[serde(rename = "fail")]
+    Fail,
+    #[serde(rename = "warn")]
+    Warn,
+    #[serde(rename = "ignore")]
+    Ignore,
+}
+
+/// The labels that tell which release a workload belongs to and which chart
+/// it's from, for clusters whose charts don't label it like helm does.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ReleaseLabels {
+    /// holds the release name, `app.kubernetes.io/instance` by default
+    pub release: Option<String>,
+    /// holds `<chart>-<version>`, `helm.sh/chart` by default
+    pub chart: Option<String>,
+}
+
+/// Where `Helm::list` gets its view of the deployed releases from.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub enum ListBackend {
+    /// Scrape the labels of the workloads in the namespace (needs kube API read access).
+    #[serde(rename = "kube")]
+    Kube,
+    /// Ask tiller through `helm list --output json`.
+    #[serde(rename = "helm")]
+    Helm,
+}
+
+// one page of `helm list --output json`
+#[derive(Debug, Deserialize)]
+struct HelmReleaseList {
+    #[serde(rename = "Next")]
+    next: Option<String>,
+    #[serde(rename = "Releases")]
+    releases: Vec<HelmReleaseEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+struct HelmReleaseEntry {
+    #[serde(rename = "Name")]
+    name: String,
+    #[serde(rename = "Chart")]
+    chart: String,
+    #[serde(rename = "Namespace")]
+    namespace: String,
+    #[serde(rename = "AppVersion")]
+    app_version: Option<String>,
+    #[serde(rename = "Status")]
+    status: Option<String>,
+    #[serde(rename = "Revision")]
+    revision: Option<u64>,
+    #[serde(rename = "Updated")]
+    updated: Option<String>,
+}
+
+// one release in helm 3's `helm list --output json`, which is a bare list
+#[derive(Debug, Deserialize)]
+struct Helm3ReleaseEntry {
+    name: String,
+    chart: String,
+    namespace: String,
+    app_version: Option<String>,
+    status: Option<String>,
+    // helm 3 prints it as a string
+    revision: Option<String>,
+    updated: Option<String>,
+}
+
+impl From<Helm3ReleaseEntry> for HelmReleaseEntry {
+    fn from(r: Helm3ReleaseEntry) -> Self {
+        HelmReleaseEntry {
+            name: r.name,
+            chart: r.chart,
+            namespace: r.namespace,
+            app_version: r.app_version,
+            status: r.status,
+            revision: r.revision.and_then(|revision| revision.parse().ok()),
+            updated: r.updated,
         }
+    }
+}
+
+// the latest revision of a release, as tiller or helm 3 recorded it
+struct ReleaseRecord {
+    revision: u64,
+    status: ReleaseStatus,
+    updated: Option<String>,
+}
+
+// one revision in `helm history --output json`
+#[derive(Debug, Deserialize)]
+struct HelmRevision {
+    revision: u64,
+    status: String,
+    #[serde(default)]
+    chart: String,
+    app_version: Option<String>,
+    description: Option<String>,
+    updated: Option<String>,
+}
+
+// the temp files behind the tls flags of tiller commands
+struct TillerTlsFiles {
+    cert: Temp,
+    key: ShreddedFile,
+    ca_cert: Option<Temp>,
+}
+
+// the files helm reads a repo's TLS certificates from, for as long as it may use the repo
+struct RepoTlsFiles {
+    _cert: Option<Temp>,
+    _key: Option<ShreddedFile>,
+    _ca_cert: Option<Temp>,
+}
+
+// everything the helm cli needs before it can run commands
+struct CliSetup {
+    required_version: Option<String>,
+    install_tiller: Option<InstallTiller>,
+    version_skew: VersionSkew,
+    plugins: Vec<Plugin>,
+    repos: Vec<Repo>,
+    update_repos: bool,
+}
+
+// the temp files behind the values flags of a command
+#[derive(Default)]
+struct ValuesFiles {
+    resolved: Option<Temp>,
+    template: Option<Temp>,
+    json: Option<Temp>,
+    overrides: Option<Temp>,
+    secrets: Vec<ShreddedFile>,
+}
+
+pub struct Helm {
+    helm_bin: String,
+    kube_context: Option<String>,
+    namespace: String,
+    kube: kube::Client,
+    release: Option<String>,
+    release_prefix: String,
+    release_suffix: String,
+    list_backend: ListBackend,
+    // how to find the workloads of releases and tell them apart
+    label_conventions: Vec<LabelConvention>,
+    protected_releases: Vec<String>,
+    sops: Sops,
+    tiller_namespace: Option<String>,
+    tiller_tls: Option<TillerTlsFiles>,
+    repo_tls: Mutex<Vec<RepoTlsFiles>>,
+    registries: Vec<Registry>,
+    // when our login to each registry host expires, if ever
+    registry_logins: Mutex<HashMap<String, Option<Instant>>>,
+    history_max: Option<u32>,
+    extra_args: Vec<String>,
+    env: HashMap<String, String>,
+    aws: Option<AwsConfig>,
+    // the service account key helm-gcs reads
+    gcp_key: Option<ShreddedFile>,
+    statuses: Vec<StatusFilter>,
+    detect_drift: bool,
+    // digest only helm 3's release records, in a single listing
+    fast_check: bool,
+    digest_algorithm: DigestAlgorithm,
+    // the keyring charts are verified against, and the file we dearmored it to if it was inline
+    keyring: Option<PathBuf>,
+    _keyring_file: Option<Temp>,
+    // how to set up the helm cli, until it has been
+    cli_setup: Mutex<Option<CliSetup>>,
+    // whether the repo indexes have been refreshed yet
+    repos_updated: Mutex<bool>,
+    // local charts whose dependencies are already in place
+    dependencies_ready: Mutex<HashSet<String>>,
+    // the workloads in the group versions this cluster serves them in, once we asked
+    workloads: Mutex<Option<Vec<Resource>>>,
+    // the namespace of each release we've looked up or deployed, without a namespace of our own
+    release_namespaces: Mutex<HashMap<String, String>>,
+    // how long each phase took, for the metrics of a deploy
+    timings: Timings,
+    // helm's local state, ours alone and removed when we're dropped
+    helm_home: Temp,
+    // where the repo indexes and chart archives outlive us, if anywhere
+    cache_dir: Option<PathBuf>,
+    repo_cache_ttl: Duration,
+    kube_config: Temp,
+    // the ca and client certificates our kube client reads
+    kube_files: Vec<Temp>,
+    // what actually runs helm and friends
+    executor: Box<CommandExecutor>,
+}
+
+/// How to reach the cluster and drive helm.
+/// Build one with `Config::builder`, fields may be added at any time.
+#[derive(Default)]
+#[non_exhaustive]
+pub struct Config {
+    pub url: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// a bearer token, e.g. a service account's, instead of a username and password
+    pub token: Option<String>,
+    /// a PEM client certificate to authenticate with, along with `client_key`
+    pub client_cert: Option<String>,
+    /// the PEM private key of `client_cert`
+    pub client_key: Option<String>,
+    /// use the service account of the pod we run in, and its cluster unless `url` is set
+    pub in_cluster: Option<bool>,
+    /// a complete kubeconfig, as YAML or base64, instead of the url and credentials
+    pub kubeconfig: Option<String>,
+    /// the context of `kubeconfig` to use instead of its current-context
+    pub context: Option<String>,
+    /// the cluster of `kubeconfig` to use, instead of the context's
+    pub cluster: Option<String>,
+    /// the user of `kubeconfig` to use, instead of the context's
+    pub user: Option<String>,
+    /// where releases are deployed, or every namespace if left out
+    pub namespace: Option<String>,
+    pub skip_tls_verify: Option<bool>,
+    pub ca_data: Option<String>,
+    /// a PEM CA bundle already on disk, instead of `ca_data`
+    pub ca_file: Option<String>,
+    pub release: Option<String>,
+    pub list_backend: Option<ListBackend>,
+    /// labels every workload of a release has, e.g. `app.kubernetes.io/managed-by: Helm`.
+    /// Setting this or `release_labels` replaces the labels of helm 2 and 3
+    pub release_selector: Option<HashMap<String, String>>,
+    /// the labels to read the release and chart of a workload from
+    pub release_labels: Option<ReleaseLabels>,
+    /// names or globs (`*`, `?`) of releases that must never be deleted
+    pub protected_releases: Option<Vec<String>>,
+    /// prepended to every release name, e.g. `pr-123-`
+    pub release_prefix: Option<String>,
+    /// appended to every release name, e.g. `-staging`
+    pub release_suffix: Option<String>,
+    /// helm plugins to install before running any other command
+    pub plugins: Option<Vec<Plugin>>,
+    /// chart repositories to add next to `stable`, e.g. private ones
+    pub repos: Option<Vec<Repo>>,
+    /// key material for decrypting charts' `secret_values_files`
+    pub sops: Option<SopsConfig>,
+    /// create the namespace if it doesn't exist yet
+    pub create_namespace: Option<bool>,
+    /// log full command lines, api calls and timings
+    pub debug: Option<bool>,
+    /// `text` by default, or `json` for one object per line
+    pub log_format: Option<LogFormat>,
+    /// the namespace tiller runs in, if it isn't `kube-system`
+    pub tiller_namespace: Option<String>,
+    /// install (or upgrade) tiller instead of only initializing the client
+    pub install_tiller: Option<InstallTiller>,
+    /// client credentials for a tiller secured with mTLS
+    pub tiller_tls: Option<TillerTls>,
+    /// act as another user, and groups, for helm and our own API calls
+    pub impersonate: Option<Impersonate>,
+    /// OCI registries charts can be pushed to and deployed from
+    pub registries: Option<Vec<Registry>>,
+    /// how many revisions of each release to keep around, all of them by default
+    pub history_max: Option<u32>,
+    /// arguments appended to every upgrade as they are, e.g. `["--atomic"]`
+    pub extra_args: Option<Vec<String>>,
+    /// environment variables for helm and its plugins, e.g. `AWS_REGION` for helm-s3.
+    /// `KUBECONFIG` and helm's home directories are always ours
+    pub env: Option<HashMap<String, String>>,
+    /// credentials for repos in S3
+    pub aws: Option<AwsConfig>,
+    /// credentials for repos in GCS
+    pub gcp: Option<GcpConfig>,
+    /// seconds to wait for a connection to the apiserver, 10 by default
+    pub api_connect_timeout: Option<u64>,
+    /// seconds any one apiserver request may take, 60 by default
+    pub api_timeout: Option<u64>,
+    /// apiserver requests a second at most, on average, unlimited by default
+    pub api_qps: Option<f64>,
+    /// apiserver requests that may go out at once before `api_qps` kicks in, as many as `api_qps` by default
+    pub api_burst: Option<u32>,
+    /// only list releases in these states. Failed releases may have no workloads
+    /// to find, so filtering always lists through helm
+    pub statuses: Option<Vec<StatusFilter>>,
+    /// make the digest change when a release's workloads are edited by hand, too
+    pub detect_drift: Option<bool>,
+    /// digest only helm 3's release records, with one listing and tight timeouts,
+    /// for checks run often or by webhooks. The namespace isn't checked for either
+    pub fast_check: Option<bool>,
+    /// what the digest is hashed with, `md5` by default
+    pub digest_algorithm: Option<DigestAlgorithm>,
+    /// the public keys signed charts are verified against, ASCII-armored or a path to a keyring
+    pub keyring: Option<String>,
+    /// the helm executable to run, `helm` on the `PATH` by default
+    pub helm_bin: Option<String>,
+    /// a semver requirement the helm client must satisfy, e.g. `~2.5`
+    pub required_version: Option<String>,
+    /// how to treat a tiller of another minor version, `warn` by default
+    pub version_skew: Option<VersionSkew>,
+    /// only refresh the repos once an unpinned chart needs them
+    pub skip_repo_update: Option<bool>,
+    /// a directory, e.g. a mounted volume, to keep helm's repo indexes and chart archives in between runs
+    pub cache_dir: Option<String>,
+    /// seconds a repo index in `cache_dir` is used before it's downloaded again, 300 by default
+    pub repo_cache_ttl: Option<u64>,
+}
 
-        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+impl Config {
+    /// A config for `namespace`, or `None` for every namespace at once.
+    pub fn builder<'a, T: Into<Option<&'a str>>>(namespace: T) -> ConfigBuilder {
+        ConfigBuilder::new(namespace)
     }
 
-    fn kube_api<D>(&self, url: &str) -> Result<D, HelmError>
-    where D: Deserialize,
-    {
-        let mut handle = Easy::new();
+    /// Check the whole config up front, reporting every problem at once
+    /// rather than failing on the first one deep inside a helm command.
+    pub fn validate(&self) -> Result<(), HelmError> {
+        let mut problems = Vec::new();
 
-        try!(handle.url(&url));
-        try!(handle.username(&self.username));
-        try!(handle.password(&self.password));
+        if let Some(ref namespace) = self.namespace {
+            if !is_dns_label(namespace) {
+                problems.push(format!("`namespace` `{}` is not a valid DNS label", namespace));
+            }
+        }
+        if self.create_namespace.unwrap_or(false) && self.namespace.is_none() {
+            problems.push("`create_namespace` needs a `namespace`".to_string());
+        }
+        if let Some(ref tiller_namespace) = self.tiller_namespace {
+            if !is_dns_label(tiller_namespace) {
+                problems.push(format!("`tiller_namespace` `{}` is not a valid DNS label", tiller_namespace));
+            }
+        }
 
-        if let Some(ref ca_cert_path) = self.kube_ca_cert {
-            try!(handle.cainfo(ca_cert_path));
-        } else {
-            try!(handle.ssl_verify_peer(false));
+        match self.kubeconfig {
+            Some(ref kubeconfig) => {
+                // the kubeconfig brings its own server and credentials
+                for &(field, set) in &[
+                    ("url", self.url.is_some()),
+                    ("username", self.username.is_some()),
+                    ("password", self.password.is_some()),
+                    ("token", self.token.is_some()),
+                    ("client_cert", self.client_cert.is_some()),
+                    ("client_key", self.client_key.is_some()),
+                    ("in_cluster", self.in_cluster.unwrap_or(false)),
+                    ("ca_data", self.ca_data.is_some()),
+                    ("ca_file", self.ca_file.is_some()),
+                ] {
+                    if set {
+                        problems.push(format!("`{}` can't be combined with `kubeconfig`", field));
+                    }
+                }
+
+                let context = self.context.as_ref().map(|c| c as &str);
+                let cluster = self.cluster.as_ref().map(|c| c as &str);
+                let user = self.user.as_ref().map(|u| u as &str);
+                if let Err(e) = KubeConfig::parse(&decode_kube_config(kubeconfig))
+                    .and_then(|kube_config| kube_config.select(context, cluster, user)
+                        .and_then(|(cluster, user)| kube_config.resolve(&cluster, &user).map(|_| ())))
+                {
+                    problems.push(format!("`kubeconfig` is unusable: {}", e));
+                }
+            },
+            None => {
+                let in_cluster = self.in_cluster.unwrap_or(false);
+                if self.url.is_none() && !in_cluster {
+                    problems.push("`url` is required without a `kubeconfig` or `in_cluster`".to_string());
+                }
+
+                // credentials come in pairs, and we need at least one way to log in
+                for &(field, other, set, other_set) in &[
+                    ("username", "password", self.username.is_some(), self.password.is_some()),
+                    ("client_cert", "client_key", self.client_cert.is_some(), self.client_key.is_some()),
+                ] {
+                    if set != other_set {
+                        problems.push(format!("`{}` and `{}` must be set together", field, other));
+                    }
+                }
+                if self.username.is_none() && self.token.is_none() && self.client_cert.is_none() && !in_cluster {
+                    problems.push("one of `username` and `password`, `token`, `client_cert` and `client_key`, \
+                        `in_cluster` or `kubeconfig` is required".to_string());
+                }
+                if let Some(ref cert) = self.client_cert {
+                    if !cert.contains("-----BEGIN CERTIFICATE-----") {
+                        problems.push("`client_cert` is not a PEM certificate".to_string());
+                    }
+                }
+                if let Some(ref key) = self.client_key {
+                    if !key.contains("PRIVATE KEY-----") {
+                        problems.push("`client_key` is not a PEM private key".to_string());
+                    }
+                }
+                if self.context.is_some() {
+                    problems.push("`context` can only select from a `kubeconfig`".to_string());
+                }
+
+                // plain http is as good as https that isn't verified, e.g. a local proxy
+                let skip_tls_verify = self.skip_tls_verify.unwrap_or(false);
+                if let Some(ref server) = self.url {
+                    match url::Url::parse(server) {
+                        Ok(ref parsed) if parsed.scheme() == "https" => (),
+                        Ok(ref parsed) if parsed.scheme() == "http" && skip_tls_verify => (),
+                        Ok(_) => problems.push(format!("`url` `{}` is not https, or http with `skip_tls_verify`",
+                            server)),
+                        Err(e) => problems.push(format!("`url` `{}` does not parse: {}", server, e)),
+                    }
+                }
+
+                match (&self.ca_data, &self.ca_file) {
+                    (&Some(_), &Some(_)) => problems.push("`ca_data` and `ca_file` are mutually exclusive".to_string()),
+                    (&Some(ref ca_data), &None) => if !ca_data.contains("-----BEGIN CERTIFICATE-----") {
+                        problems.push("`ca_data` is not a PEM certificate".to_string());
+                    },
+                    (&None, &Some(ref ca_file)) => if let Err(e) = read_ca_file(ca_file) {
+                        problems.push(format!("`ca_file`: {}", e));
+                    },
+                    (&None, &None) => if !skip_tls_verify && !in_cluster {
+                        problems.push("one of `ca_data` or `ca_file` is required unless `skip_tls_verify` is set"
+                            .to_string());
+                    },
+                }
+            },
         }
 
-        let mut buf = Vec::new();
-        {
-            let mut transfer = handle.transfer();
-            try!(transfer.write_function(|data| {
-                buf.extend_from_slice(data);
-                Ok(data.len())
-            }));
-            try!(transfer.perform());
+        if let Some(ref tls) = self.tiller_tls {
+            for &(field, pem) in &[("tiller_tls.cert", Some(&tls.cert)), ("tiller_tls.ca_cert", tls.ca_cert.as_ref())] {
+                if let Some(pem) = pem {
+                    if !pem.contains("-----BEGIN CERTIFICATE-----") {
+                        problems.push(format!("`{}` is not a PEM certificate", field));
+                    }
+                }
+            }
+            if !tls.key.contains("PRIVATE KEY-----") {
+                problems.push("`tiller_tls.key` is not a PEM private key".to_string());
+            }
         }
 
-        match serde_json::from_str::<D>(String::from_utf8_lossy(&buf).trim()) {
-            Ok(v) => Ok(v),
-            Err(_) => unimplemented!(),
+        if self.release_selector.as_ref().map(|selector| selector.is_empty()).unwrap_or(false) {
+            problems.push("`release_selector` needs at least one label, or it would select every workload"
+                .to_string());
         }
-    }
 
-    pub fn list(&self) -> Result<Vec<Chart>, HelmError> {
-        // get the api endpoint
-        let mut deployments_api = try!(Url::parse(&self.server));
-        try!(deployments_api.path_segments_mut().map(|mut segments| {
-            segments
-                .extend("apis/extensions/v1beta1/namespaces".split('/'))
-                .push(&self.namespace)
-                .push("deployments");
-        })
-        .map_err(|_| HelmError::UrlParse(
-            ParseError::RelativeUrlWithCannotBeABaseBase)));
-
-        let deployments: Map<String, Value> = try!(self.kube_api(&deployments_api.into_string()));
-
-        Ok(deployments
-            .get("items")
-            .and_then(Value::as_array)
-            .map_or(Vec::new(), |items| {
-                items.iter()
-                    .map(Value::as_object).filter_map(|i| i)
-                    .map(|o| o.get("metadata")).filter_map(|i| i)
-                    .map(Value::as_object).filter_map(|i| i)
-                    .filter(|metadata| {
-                        metadata
-                            .get("namespace")
-                            .and_then(Value::as_str)
-                            .map(|n| n == self.namespace)
-                            .unwrap_or(false)
-                    })
-                    .map(|o| o.get("labels")).filter_map(|i| i)
-                    .map(Value::as_object).filter_map(|i| i)
-                    .filter(|labels| {
-                        labels
-                            .get("heritage")
-                            .and_then(Value::as_str)
-                            .map(|n| n == "Tiller")
-                            .unwrap_or(false)
-                    })
-                    .map(|labels| {
-                        labels.get("release")
-                            .and_then(Value::as_str)
-                            .and_then(|release| {
-                            labels.get("chart")
-                                .and_then(Value::as_str)
-                                .map(|c| c.rsplitn(2, '-'))
-                                .and_then(|mut split| {
-                                    split.next().and_then(|version| {
-                                        split.last().map(|chart_name| {
-                                            Chart {
-                                                release: release.to_string(),
-                                                name: chart_name.to_string(),
-                                                version: Some(version.to_string()),
-                                                overrides: None,
-                                            }
-                                        })
-                                    })
-                                })
-                        })
-                    })
-                    .filter_map(|i| i)
-                    .collect()
-            }))
-    }
+        if self.repo_cache_ttl.is_some() && self.cache_dir.is_none() {
+            problems.push("`repo_cache_ttl` needs a `cache_dir`".to_string());
+        }
 
-    pub fn digest(&self) -> Result<String, HelmError> {
-        let mut hash = Context::new();
-        for chart in try!(self.list()) {
-            hash.consume(chart.release);
-            hash.consume(chart.name);
-            if let Some(version) = chart.version {
-                hash.consume(version);
+        if let Some(qps) = self.api_qps {
+            if !(qps > 0.0) {
+                problems.push(format!("`api_qps` `{}` must be more than 0", qps));
             }
         }
-        Ok(format!("{:x}", hash.compute()))
-    }
+        if self.api_burst.is_some() && self.api_qps.is_none() {
+            problems.push("`api_burst` needs an `api_qps`".to_string());
+        }
 
-    pub fn upgrade(&self, chart: &Chart) -> Result<(), HelmError> {
-        let mut cmd = vec![];
+        // a fast check only reads what helm 3 records of the latest revisions
+        if self.fast_check.unwrap_or(false) {
+            if self.detect_drift.unwrap_or(false) {
+                problems.push("`fast_check` can't `detect_drift`, that takes listing every workload".to_string());
+            }
+            if self.statuses.is_some() {
+                problems.push("`fast_check` can't filter by `statuses`".to_string());
+            }
+            if self.tiller_namespace.is_some() || self.install_tiller.is_some() || self.tiller_tls.is_some() {
+                problems.push("`fast_check` only works with helm 3, not tiller".to_string());
+            }
+        }
 
-        // start of the command
-        cmd.push(format!("helm upgrade -i --namespace {}", self.namespace));
+        for repo in self.repos.iter().flat_map(|repos| repos.iter()) {
+            if repo.cert_data.is_some() != repo.key_data.is_some() {
+                problems.push(format!("repo `{}` needs both `cert_data` and `key_data`", repo.name));
+            }
+            for &(field, pem) in &[("cert_data", repo.cert_data.as_ref()), ("ca_data", repo.ca_data.as_ref())] {
+                if let Some(pem) = pem {
+                    if !pem.contains("-----BEGIN CERTIFICATE-----") {
+                        problems.push(format!("repo `{}` `{}` is not a PEM certificate", repo.name, field));
+                    }
+                }
+            }
+            if let Some(ref key) = repo.key_data {
+                if !key.contains("PRIVATE KEY-----") {
+                    problems.push(format!("repo `{}` `key_data` is not a PEM private key", repo.name));
+                }
+            }
+        }
 
-        if let Some(ref version) = chart.version {
-            cmd.push(format!("--version {}", version));
+        for registry in self.registries.iter().flat_map(|registries| registries.iter()) {
+            let logins = [registry.username.is_some(), registry.ecr.is_some(), registry.acr.is_some()];
+            if logins.iter().filter(|&&login| login).count() > 1 {
+                problems.push(format!("registry `{}` takes only one of `username`, `ecr` and `acr`", registry.host));
+            }
         }
 
-        let overrides_file = if let Some(ref overrides) = chart.overrides {
-            let override_path = try!(Temp::new_file());
+        if let Some(ref aws) = self.aws {
+            if aws.access_key_id.is_some() != aws.secret_access_key.is_some() {
+                problems.push("`aws.access_key_id` and `aws.secret_access_key` must be set together".to_string());
+            }
+        }
+        if let Some(key) = self.gcp.as_ref().and_then(|gcp| gcp.service_account_key.as_ref()) {
+            if serde_json::from_str::<Value>(key).is_err() {
+                problems.push("`gcp.service_account_key` is not JSON".to_string());
+            }
+        }
 
-            // set values file flag
-            cmd.push(format!("--values {}",
-                override_path.to_path_buf().to_string_lossy().into_owned()));
+        if let Some(ref required) = self.required_version {
+            if VersionReq::parse(required).is_err() {
+                problems.push(format!("`required_version` `{}` is not a semver requirement", required));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(HelmError::InvalidConfig(problems))
+        }
+    }
+}
 
-            // write the overrides to the file
-            let mut overrides_file = try!(File::create(&override_path));
-            try!(serde_yaml::to_writer(&mut overrides_file, overrides));
-            try!(overrides_file.flush());
+impl Helm {
+    pub fn configure(config: Config) -> Result<Self, HelmError> {
+        logger::set_debug(config.debug.unwrap_or(false));
+        logger::set_format(config.log_format.unwrap_or(LogFormat::Text));
 
-            // log values used
-            try!(io::stderr().write_fmt(format_args!("Using values:\n{}\n",
-                try!(serde_yaml::to_string(overrides)))));
+        // we'll store this config file for helm to use, it holds credentials so only we can read it
+        let kube_config_path = try!(private_file(&[]));
+        let mut kube_files = Vec::new();
 
-            Some(override_path)
+        // the client we use to read cluster state directly
+        let fast_check = config.fast_check.unwrap_or(false);
+        let (connect_timeout, request_timeout) = if fast_check {
+            (FAST_CHECK_CONNECT_TIMEOUT_SECS, FAST_CHECK_TIMEOUT_SECS)
         } else {
-            None
+            (kube::DEFAULT_CONNECT_TIMEOUT_SECS, kube::DEFAULT_TIMEOUT_SECS)
+        };
+        let timeouts = Timeouts {
+            connect: Duration::from_secs(config.api_connect_timeout.unwrap_or(connect_timeout)),
+            request: Duration::from_secs(config.api_timeout.unwrap_or(request_timeout)),
         };
+        let kube = match config.kubeconfig {
+            Some(ref kubeconfig) => {
+                let raw = decode_kube_config(kubeconfig);
+                try!(Helm::write_kube_config(&raw, &config, &kube_config_path, &mut kube_files, timeouts))
+            },
+            None => if config.context.is_some() || config.cluster.is_some() || config.user.is_some() {
+                return Err(HelmError::MissingConfig(
+                    "`context`, `cluster` and `user` can only select from a kubeconfig".to_string()));
+            } else {
+                try!(Helm::render_kube_config(&config, &kube_config_path, &mut kube_files, timeouts))
+            },
+        };
+        let kube = kube.rate_limited(config.api_qps.map(|qps| {
+            kube::RateLimiter::new(qps, config.api_burst.unwrap_or(qps.ceil() as u32))
+        }));
 
-        // end of the command
-        cmd.push(format!("{} stable/{}", chart.release, chart.name));
+        // make sure there's a namespace to deploy into, unless we deploy anywhere.
+        // a fast check makes do with its one request
+        let check_namespace = !fast_check || config.create_namespace.unwrap_or(false);
+        if let (&Some(ref namespace), true) = (&config.namespace, check_namespace) {
+            let namespace_url = try!(kube.cluster_object_url(kube::NAMESPACES, namespace));
+            if config.create_namespace.unwrap_or(false) {
+                if !try!(kube.exists(&namespace_url)) {
+                    logger::info(format_args!("Creating namespace `{}`.", namespace));
+                    let namespaces_url = try!(kube.cluster_collection_url(kube::NAMESPACES));
+                    try!(kube.create(&namespaces_url, &kube::Namespace::new(namespace)));
+                }
+            } else {
+                // say so now, rather than with whatever tiller makes of it halfway through a deploy
+                match kube.exists(&namespace_url) {
+                    Ok(true) => (),
+                    Ok(false) => return Err(HelmError::NamespaceNotFound(
+                        namespace.clone(), kube.server().to_string())),
+                    // not everyone may read namespaces, helm finds out soon enough
+                    Err(e) => logger::debug(format_args!("Could not check namespace `{}` exists: {}",
+                        namespace, e)),
+                }
+            }
+        }
 
-        try!(self.run(&cmd.join(" ")).map(|_| { () }));
+        // helm only reads binary keyrings
+        let (keyring, keyring_file) = match config.keyring {
+            Some(ref keyring) if keyring.contains("-----BEGIN PGP PUBLIC KEY BLOCK-----") => {
+                let keyring_file = try!(dearmor_keyring(keyring));
+                (Some(keyring_file.to_path_buf()), Some(keyring_file))
+            },
+            Some(ref keyring) => (Some(PathBuf::from(keyring)), None),
+            None => (None, None),
+        };
 
-        // cleanup resources
-        if let Some(mut file) = overrides_file {
-            file.release();
+        // helm's home is new every time, only its caches are shared through the cache dir
+        let helm_home = try!(Temp::new_dir());
+        let cache_dir = config.cache_dir.map(PathBuf::from);
+        if let Some(ref cache_dir) = cache_dir {
+            try!(link_cache_dir(&helm_home.to_path_buf(), cache_dir));
         }
-        Ok(())
+
+        Ok(Helm {
+            helm_bin: config.helm_bin.unwrap_or("helm".to_string()),
+            // helm gets a context of its own for a cluster and user picked separately
+            kube_context: if config.cluster.is_some() || config.user.is_some() {
+                Some(kubeconfig::SELECTED_CONTEXT.to_string())
+            } else {
+                config.context
+            },
+            namespace: config.namespace,
+            kube: kube,
+            release: config.release,
+            release_prefix: config.release_prefix.unwrap_or_default(),
+            release_suffix: config.release_suffix.unwrap_or_default(),
+            list_backend: config.list_backend.unwrap_or(ListBackend::Kube),
+            label_conventions: match (config.release_selector, config.release_labels) {
+                (None, None) => LabelConvention::defaults(),
+                (selector, labels) => vec![LabelConvention::custom(selector, labels.unwrap_or_default())],
+            },
+            protected_releases: config.protected_releases.unwrap_or_default(),
+            sops: try!(Sops::configure(config.sops)),
+            tiller_namespace: config.tiller_namespace,
+            tiller_tls: match config.tiller_tls {
+                Some(tls) => Some(TillerTlsFiles {
+                    cert: try!(private_file(tls.cert.as_bytes())),
+                    key: try!(ShreddedFile::new(tls.key.as_bytes())),
+                    ca_cert: match tls.ca_cert {
+                        Some(ca_cert) => Some(try!(private_file(ca_cert.as_bytes()))),
+                        None => None,
+                    },
+                }),
+                None => None,
+            },
+            repo_tls: Mutex::new(Vec::new()),
+            registries: config.registries.unwrap_or_default(),
+            registry_logins: Mutex::new(HashMap::new()),
+            history_max: config.history_max,
+            extra_args: config.extra_args.unwrap_or_default(),
+            env: config.env.unwrap_or_default(),
+            aws: config.aws,
+            gcp_key: match config.gcp.and_then(|gcp| gcp.service_account_key) {
+                Some(key) => Some(try!(ShreddedFile::new(key.as_bytes()))),
+                None => None,
+            },
+            statuses: config.statuses.unwrap_or_default(),
+            detect_drift: config.detect_drift.unwrap_or(false),
+            fast_check: fast_check,
+            digest_algorithm: config.digest_algorithm.unwrap_or_default(),
+            keyring: keyring,
+            _keyring_file: keyring_file,
+            // the helm cli is only set up once a command needs it, check usually doesn't
+            cli_setup: Mutex::new(Some(CliSetup {
+                required_version: config.required_version,
+                install_tiller: config.install_tiller,
+                version_skew: config.version_skew.unwrap_or(VersionSkew::Warn),
+                plugins: config.plugins.unwrap_or_default(),
+                repos: config.repos.unwrap_or_default(),
+                update_repos: !config.skip_repo_update.unwrap_or(false),
+            })),
+            repos_updated: Mutex::new(false),
+            dependencies_ready: Mutex::new(HashSet::new()),
+            workloads: Mutex::new(None),
+            release_namespaces: Mutex::new(HashMap::new()),
+            timings: Timings::default(),
+            helm_home: helm_home,
+            cache_dir: cache_dir,
+            repo_cache_ttl: Duration::from_secs(config.repo_cache_ttl.unwrap_or(DEFAULT_REPO_CACHE_TTL_SECS)),
+            kube_config: kube_config_path,
+            kube_files: kube_files,
+            executor: Box::new(ShellExecutor),
+        })
+    }
+
+    // write a kubeconfig for our credentials and a client that uses the same ones
+    fn render_kube_config(config: &Config, path: &Temp, files: &mut Vec<Temp>, timeouts: Timeouts)
+        -> Result<kube::Client, HelmError>
+    {
+        let in_cluster = config.in_cluster.unwrap_or(false);
+        let url = match (&config.url, in_cluster) {
+            (&Some(ref url), _) => url.clone(),
+            (&None, true) => try!(in_cluster_url()),
+            (&None, false) => try!(required(&config.url, "url")).to_string(),
+        };
+        let token = match (&config.token, in_cluster) {
+            (&Some(ref token), _) => Some(token.clone()),
+            (&None, true) => Some(try!(read_service_account("token")).trim().to_string()),
+            (&None, false) => None,
+        };
+
+        // the ca may be inline or in a file of the container
+        let ca_data = match (&config.ca_data, &config.ca_file) {
+            (&Some(ref ca_data), _) => Some(ca_data.clone()),
+            (&None, &Some(ref ca_file)) => Some(try!(read_ca_file(ca_file))),
+            (&None, &None) if in_cluster => Some(try!(read_service_account("ca.crt"))),
+            (&None, &None) => None,
+        };
+
+        // check invariants
+        if ca_data.is_none() && !config.skip_tls_verify.unwrap_or(false) {
+            return Err(HelmError::NoCaData);
+        }
+
+        let base_64 = |pem: &Option<String>| pem.as_ref()
+            .map(|pem| base64::encode(pem.trim().as_bytes()));
+
+        // generate k8s config file so helm can connect to our server
+        let kube_config = KubeConfig::single(kubeconfig::Cluster {
+            server: url.clone(),
+            certificate_authority_data: base_64(&ca_data),
+            insecure_skip_tls_verify: config.skip_tls_verify.unwrap_or(false),
+            ..kubeconfig::Cluster::default()
+        }, kubeconfig::User {
+            username: config.username.clone(),
+            password: config.password.clone(),
+            token: token.clone(),
+            client_certificate_data: base_64(&config.client_cert),
+            client_key_data: base_64(&config.client_key),
+            impersonate: config.impersonate.as_ref().map(|impersonate| impersonate.user.clone()),
+            impersonate_groups: config.impersonate.as_ref()
+                .map(|impersonate| impersonate.groups.clone())
+                .unwrap_or_default(),
+        }, config.namespace.as_ref().map(|n| n as &str));
+
+        let mut kube_config_file = try!(File::create(path));
+        try!(kube_config_file.write_all(try!(kube_config.to_yaml()).as_bytes()));
+
+        // make sure we wrote the file
+        try!(kube_config_file.flush());
+
+        // create a file to store the ca data for the kubes api
+        let ca_cert_path = if let Some(ref ca_data) = ca_data {
+            let ca_cert_path = try!(private_file(ca_data.as_bytes()));
+            let path = ca_cert_path.to_path_buf();
+            files.push(ca_cert_path);
+            Some(path)
+        } else {
+            None
+        };
+
+        // the same preference as for kubeconfigs: token, then basic auth, then certificates
+        let auth = if let Some(token) = token {
+            Auth::Bearer(token)
+        } else if let (&Some(ref username), &Some(ref password)) = (&config.username, &config.password) {
+            Auth::Basic {
+                username: username.clone(),
+                password: password.clone(),
+            }
+        } else if let (&Some(ref cert), &Some(ref key)) = (&config.client_cert, &config.client_key) {
+            let cert = try!(private_file(cert.as_bytes()));
+            let key = try!(private_file(key.as_bytes()));
+            let auth = Auth::ClientCert {
+                cert: cert.to_path_buf(),
+                key: key.to_path_buf(),
+            };
+            files.push(cert);
+            files.push(key);
+            auth
+        } else {
+            return Err(HelmError::MissingConfig(
+                "one of `username` and `password`, `token`, `client_cert` and `client_key` or `in_cluster` \
+                is required without a kubeconfig".to_string()));
+        };
+
+        let transport = CurlClient::new(auth, ca_cert_path, config.skip_tls_verify.unwrap_or(false), timeouts);
+        kube::Client::new(&url, Box::new(transport.impersonating(config.impersonate.clone())))
+    }
+
+    // write a kubeconfig we were given, verbatim unless we have to point helm at a
+    // cluster and user of it, and a client for the chosen cluster and user
+    fn write_kube_config(raw: &str, config: &Config, path: &Temp, files: &mut Vec<Temp>, timeouts: Timeouts)
+        -> Result<kube::Client, HelmError>
+    {
+        let impersonate = config.impersonate.as_ref();
+        let kube_config = try!(KubeConfig::parse(raw));
+        let (cluster_name, user_name) = try!(kube_config.select(config.context.as_ref().map(|c| c as &str),
+            config.cluster.as_ref().map(|c| c as &str), config.user.as_ref().map(|u| u as &str)));
+        let (cluster, user) = try!(kube_config.resolve(&cluster_name, &user_name));
+
+        let mut raw = raw.to_string();
+        if config.cluster.is_some() || config.user.is_some() {
+            raw = try!(kubeconfig::add_context(&raw, &cluster_name, &user_name,
+                config.namespace.as_ref().map(|n| n as &str)));
+        }
+        // helm impersonates through the kubeconfig, we do with headers
+        if let Some(impersonate) = impersonate {
+            raw = try!(kubeconfig::impersonate(&raw, &user_name, impersonate));
+        }
+        try!(File::create(path)
+            .and_then(|mut kube_config_file| {
+                try!(kube_config_file.write_all(raw.as_bytes()));
+                kube_config_file.flush()
+            })
+            .map_err(|e| HelmError::from(e).context("writing the kubeconfig")));
+
+        // curl wants the ca and client certificates in files
+        let ca_cert = match (&cluster.certificate_authority_data, &cluster.certificate_authority) {
+            (&Some(ref data), _) => {
+                let ca_cert = try!(private_file(&try!(decode_base64(data, "certificate-authority-data"))));
+                let path = ca_cert.to_path_buf();
+                files.push(ca_cert);
+                Some(path)
+            },
+            (&None, &Some(ref path)) => Some(PathBuf::from(path)),
+            (&None, &None) => None,
+        };
+
+        let auth = if let Some(ref token) = user.token {
+            Auth::Bearer(token.clone())
+        } else if let (&Some(ref username), &Some(ref password)) = (&user.username, &user.password) {
+            Auth::Basic {
+                username: username.clone(),
+                password: password.clone(),
+            }
+        } else if let (&Some(ref cert), &Some(ref key)) = (&user.client_certificate_data, &user.client_key_data) {
+            let cert = try!(private_file(&try!(decode_base64(cert, "client-certificate-data"))));
+            let key = try!(private_file(&try!(decode_base64(key, "client-key-data"))));
+            let auth = Auth::ClientCert {
+                cert: cert.to_path_buf(),
+                key: key.to_path_buf(),
+            };
+            files.push(cert);
+            files.push(key);
+            auth
+        } else {
+            Auth::None
+        };
+
+        let transport = CurlClient::new(auth, ca_cert, cluster.insecure_skip_tls_verify, timeouts);
+        kube::Client::new(&cluster.server, Box::new(transport.impersonating(impersonate.cloned())))
+    }
+
+    // set up the helm cli, once, before the first command that needs it
+    fn ensure_cli(&self) -> Result<(), HelmError> {
+        let mut pending = self.cli_setup.lock().unwrap();
+        if let Some(ref setup) = *pending {
+            try!(self.setup_cli(setup));
+        }
+        *pending = None;
+        Ok(())
+    }
+
+    fn setup_cli(&self, setup: &CliSetup) -> Result<(), HelmError> {
+        // make sure the helm we're about to drive is one we understand
+        if let Some(ref required) = setup.required_version {
+            let requirement = try!(VersionReq::parse(required)
+                .map_err(|_| HelmError::BadVersion(required.clone())));
+            let version = try!(self.client_version());
+            if !requirement.matches(&version) {
+                return Err(HelmError::WrongHelmVersion(required.clone(), version.to_string()));
+            }
+        }
+
+        // init helm, standing up tiller as well if we were asked to
+        match setup.install_tiller {
+            Some(ref install) => {
+                // `helm init` has no client tls flags, only the namespace applies
+                let mut cmd = vec![self.helm_command("init --upgrade")];
+                if let Some(ref namespace) = self.tiller_namespace {
+                    cmd.push(format!("--tiller-namespace {}", namespace));
+                }
+                if let Some(ref service_account) = install.service_account {
+                    cmd.push(format!("--service-account {}", service_account));
+                }
+                if install.wait {
+                    cmd.push("--wait".to_string());
+                }
+                if let Some(history_max) = self.history_max {
+                    cmd.push(format!("--history-max {}", history_max));
+                }
+                cmd.push("1>&2".to_string());
+                try!(self.exec(&cmd.join(" ")));
+            },
+            None => {
+                try!(self.exec(&format!("{} 1>&2", self.helm_command("init --client-only"))));
+            },
+        }
+
+        // helm and tiller refuse to talk across minor versions mid-upgrade, find out now
+        match setup.version_skew {
+            VersionSkew::Ignore => (),
+            VersionSkew::Fail => try!(self.check_version_skew()),
+            VersionSkew::Warn => if let Err(e) = self.check_version_skew() {
+                logger::warn(format_args!("{}", e));
+            },
+        }
+
+        // install the plugins the rest of the commands might rely on
+        for plugin in &setup.plugins {
+            let mut cmd = format!("{} {}", self.helm_command("plugin install"), plugin.url);
+            if let Some(ref version) = plugin.version {
+                cmd.push_str(&format!(" --version {}", version));
+            }
+            try!(self.exec(&cmd));
+        }
+
+        // repos in buckets need a plugin, unless it came with the helm we were given
+        for &(scheme, (plugin, url)) in &[("s3://", HELM_S3_PLUGIN), ("gs://", HELM_GCS_PLUGIN)] {
+            if setup.repos.iter().any(|repo| repo.url.starts_with(scheme)) {
+                let installed = try!(self.exec(&self.helm_command("plugin list")));
+                if !installed.lines().any(|line| line.split_whitespace().next() == Some(plugin)) {
+                    logger::info(format_args!("Installing the `{}` plugin for the {} repos.", plugin, scheme));
+                    try!(self.exec(&format!("{} {}", self.helm_command("plugin install"), url)));
+                }
+            }
+        }
+
+        // repos left in the cache dir by an earlier run keep their index while it's fresh
+        let cached = if self.cache_dir.is_some() { self.list_repos().unwrap_or_default() } else { Vec::new() };
+        for repo in &setup.repos {
+            match cached.iter().find(|entry| entry.name == repo.name) {
+                // the certificate files of the last run are gone, so those are added again
+                Some(entry) if entry.url == repo.url && !repo.has_tls() && self.index_fresh(&repo.name) => {
+                    logger::debug(format_args!("Using the cached index of repo `{}`.", repo.name));
+                    continue;
+                },
+                // newer helm 3s refuse to add a repo over another of the same name
+                Some(_) => try!(self.exec(&format!("{} {}", self.helm_command("repo remove"),
+                    shell_quote(&repo.name)))),
+                None => (),
+            }
+            try!(self.add_repo(repo));
+        }
+
+        // update helm repos, unless we can wait for a chart that needs it
+        if setup.update_repos {
+            if self.indexes_fresh() {
+                logger::debug(format_args!("The cached repo indexes are fresh, not updating them."));
+            } else {
+                try!(self.exec(&self.helm_command("repo update")));
+            }
+            *self.repos_updated.lock().unwrap() = true;
+        }
+
+        Ok(())
+    }
+
+    fn run(&self, cmd: &str) -> Result<String, HelmError> {
+        try!(self.ensure_cli());
+        self.exec(cmd)
+    }
+
+    // run a command and hand back its output even if it failed
+    fn run_unchecked(&self, cmd: &str) -> Result<Output, HelmError> {
+        try!(self.ensure_cli());
+        self.exec_unchecked(cmd)
+    }
+
+    // run a command without echoing its output, which may hold secrets
+    fn run_quiet(&self, cmd: &str) -> Result<String, HelmError> {
+        try!(self.ensure_cli());
+        let output = try!(self.spawn(cmd, false));
+        checked_stdout(cmd, output)
+    }
+
+    // run a command whether or not the cli is set up yet
+    fn exec(&self, cmd: &str) -> Result<String, HelmError> {
+        let output = try!(self.exec_unchecked(cmd));
+        checked_stdout(cmd, output)
+    }
+
+    fn exec_unchecked(&self, cmd: &str) -> Result<Output, HelmError> {
+        self.spawn(cmd, true)
+    }
+
+    /// Make kube API requests with `client` from now on, e.g. a `MockKubeClient` in tests.
+    pub fn set_kube_client(&mut self, client: Box<KubeClient>) {
+        self.kube.set_transport(client);
+    }
+
+    /// Run commands with `executor` from now on, e.g. a `FakeExecutor` in tests.
+    pub fn set_executor(&mut self, executor: Box<CommandExecutor>) {
+        self.executor = executor;
+    }
+
+    fn spawn(&self, cmd: &str, echo_stdout: bool) -> Result<Output, HelmError> {
+        let head = self.log_command(cmd);
+        let started = Instant::now();
+
+        let output = try!(self.executor.output(cmd, &self.command_env())
+            .map_err(|e| e.context(format!("running `{}`", head))));
+
+        self.log_exit(cmd, &output, started.elapsed(), echo_stdout);
+        Ok(output)
+    }
+
+    // run a command, calling `watch` every `poll` while it runs and killing
+    // the command as soon as `watch` fails
+    fn spawn_watched<W>(&self, cmd: &str, poll: Duration, mut watch: W) -> Result<Output, HelmError>
+    where W: FnMut() -> Result<(), HelmError>,
+    {
+        self.log_command(cmd);
+        let started = Instant::now();
+
+        let output = try!(self.executor.output_watched(cmd, &self.command_env(), poll, &mut watch));
+        self.log_exit(cmd, &output, started.elapsed(), true);
+        Ok(output)
+    }
+
+    // the environment to run commands in, with helm pointed at our kubeconfig and home
+    fn command_env(&self) -> Vec<(String, String)> {
+        // keep helm 2 and helm 3 state away from other builds in this container
+        let helm_home = self.helm_home.to_path_buf();
+        let path = |dir: &str| helm_home.join(dir).to_string_lossy().into_owned();
+
+        // ours come last, so they win over any of the same name
+        let mut env: Vec<(String, String)> = self.env.iter()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        env.push(("KUBECONFIG".to_string(), self.kube_config.to_path_buf().to_string_lossy().into_owned()));
+        env.push(("HELM_HOME".to_string(), helm_home.to_string_lossy().into_owned()));
+        env.push(("HELM_CACHE_HOME".to_string(), path("cache")));
+        env.push(("HELM_CONFIG_HOME".to_string(), path("config")));
+        env.push(("HELM_DATA_HOME".to_string(), path("data")));
+        if self.cache_dir.is_some() {
+            // helm 3 keeps the repos with the rest of its config, keep them with their indexes instead
+            env.push(("HELM_REPOSITORY_CONFIG".to_string(), helm_home.join("repository")
+                .join("repositories.yaml").to_string_lossy().into_owned()));
+        }
+        // helm 3 before 3.8 hides its registry commands behind this
+        env.push(("HELM_EXPERIMENTAL_OCI".to_string(), "1".to_string()));
+        if let Some(ref aws) = self.aws {
+            for &(key, value) in &[
+                ("AWS_ACCESS_KEY_ID", &aws.access_key_id),
+                ("AWS_SECRET_ACCESS_KEY", &aws.secret_access_key),
+                ("AWS_SESSION_TOKEN", &aws.session_token),
+                ("AWS_REGION", &aws.region),
+                ("AWS_DEFAULT_REGION", &aws.region),
+            ] {
+                if let Some(ref value) = *value {
+                    env.push((key.to_string(), value.clone()));
+                }
+            }
+        }
+        if let Some(ref key) = self.gcp_key {
+            env.push(("GOOGLE_APPLICATION_CREDENTIALS".to_string(), key.path().to_string_lossy().into_owned()));
+        }
+        env
+    }
+
+    // log the command we're about to run, in full only when debugging and never
+    // with credentials, returning the short form
+    fn log_command(&self, cmd: &str) -> String {
+        let head = command_head(cmd);
+        logger::info(format_args!("Running `{}`.", head));
+        logger::debug(format_args!("Full command: `{}`", redact::command(cmd)));
+        head
+    }
+
+    fn log_exit(&self, cmd: &str, output: &Output, elapsed: Duration, echo_stdout: bool) {
+        let head = command_head(cmd);
+
+        // json logs always get the outcome, it's what they're aggregated for
+        logger::event(if logger::json_enabled() { Level::Info } else { Level::Debug },
+            format_args!("`{}` exited with {} after {}.", head, output.status, logger::duration(elapsed)),
+            vec![
+                ("command", Value::String(redact::command(cmd))),
+                ("exit_code", output.status.code().map(|c| Value::I64(c as i64)).unwrap_or(Value::Null)),
+                ("duration_ms", Value::U64(logger::millis(elapsed))),
+            ]);
+
+        // log things to stderr since stdout is reserved, helm's chatter only when debugging
+        if echo_stdout && logger::debug_enabled() {
+            logger::output(&output.stdout);
+        }
+        logger::output(&output.stderr);
+    }
+
+    // refresh the repo indexes, at most once
+    fn update_repos(&self) -> Result<(), HelmError> {
+        // setting up the cli may update the repos itself
+        try!(self.ensure_cli());
+
+        let mut updated = self.repos_updated.lock().unwrap();
+        if !*updated {
+            if self.indexes_fresh() {
+                logger::debug(format_args!("The cached repo indexes are fresh, not updating them."));
+            } else {
+                let phase = self.phase("repo update", None);
+                try!(self.exec(&self.helm_command("repo update")));
+                phase.succeeded();
+            }
+            *updated = true;
+        }
+        Ok(())
+    }
+
+    // whether the index of `repo` in the cache dir is recent enough to use as it is
+    fn index_fresh(&self, repo: &str) -> bool {
+        self.cache_dir.is_some() && fs::metadata(self.repo_index_path(repo))
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .and_then(|modified| modified.elapsed().ok())
+            .map(|age| age < self.repo_cache_ttl)
+            .unwrap_or(false)
+    }
+
+    // whether every repo's index in the cache dir is, so `helm repo update` can be skipped
+    fn indexes_fresh(&self) -> bool {
+        if self.cache_dir.is_none() {
+            return false;
+        }
+        match self.list_repos() {
+            // helm 2 never updates its `local` repo
+            Ok(repos) => repos.iter().any(|repo| repo.name != "local")
+                && repos.iter().all(|repo| repo.name == "local" || self.index_fresh(&repo.name)),
+            Err(_) => false,
+        }
+    }
+
+    // `helm repo add`, whether or not the cli is set up yet
+    fn add_repo(&self, repo: &Repo) -> Result<(), HelmError> {
+        let mut cmd = vec![self.helm_command("repo add")];
+        if let Some(ref username) = repo.username {
+            cmd.push(format!("--username {}", shell_quote(username)));
+        }
+        if let Some(ref password) = repo.password {
+            cmd.push(format!("--password {}", shell_quote(password)));
+        }
+
+        // helm remembers the paths and reads them whenever it talks to the repo again
+        let files = RepoTlsFiles {
+            _cert: match repo.cert_data {
+                Some(ref cert) => {
+                    let file = try!(private_file(cert.as_bytes()));
+                    cmd.push(format!("--cert-file {}", shell_quote(&file.to_path_buf().to_string_lossy())));
+                    Some(file)
+                },
+                None => None,
+            },
+            _key: match repo.key_data {
+                Some(ref key) => {
+                    let file = try!(ShreddedFile::new(key.as_bytes()));
+                    cmd.push(format!("--key-file {}", shell_quote(&file.path().to_string_lossy())));
+                    Some(file)
+                },
+                None => None,
+            },
+            _ca_cert: match repo.ca_data {
+                Some(ref ca_cert) => {
+                    let file = try!(private_file(ca_cert.as_bytes()));
+                    cmd.push(format!("--ca-file {}", shell_quote(&file.to_path_buf().to_string_lossy())));
+                    Some(file)
+                },
+                None => None,
+            },
+        };
+
+        cmd.push(format!("{} {}", shell_quote(&repo.name), shell_quote(&repo.url)));
+        try!(self.exec(&cmd.join(" ")));
+        if repo.has_tls() {
+            self.repo_tls.lock().unwrap().push(files);
+        }
+        Ok(())
+    }
+
+    /// Add `repo`, or update its url and credentials if it's already there.
+    pub fn repo_add(&self, repo: &Repo) -> Result<(), HelmError> {
+        try!(self.ensure_cli());
+        self.add_repo(repo)
+    }
+
+    /// Forget the repo called `name`.
+    pub fn repo_remove(&self, name: &str) -> Result<(), HelmError> {
+        try!(self.run(&format!("{} {}", self.helm_command("repo remove"), shell_quote(name))));
+        Ok(())
+    }
+
+    /// The repos helm knows about.
+    pub fn repo_list(&self) -> Result<Vec<RepoEntry>, HelmError> {
+        try!(self.ensure_cli());
+        self.list_repos()
+    }
+
+    // `helm repo list`, whether or not the cli is set up yet
+    fn list_repos(&self) -> Result<Vec<RepoEntry>, HelmError> {
+        let cmd = self.helm_command("repo list");
+        let output = try!(self.exec_unchecked(&cmd));
+        let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+        if !output.status.success() {
+            // helm 3 fails rather than print an empty table
+            if String::from_utf8_lossy(&output.stderr).contains("no repositories") {
+                return Ok(Vec::new());
+            }
+            return Err(HelmError::CmdFailed(cmd));
+        }
+
+        // a table of `NAME URL` under a header
+        Ok(stdout.lines()
+            .skip_while(|line| !line.trim_left().starts_with("NAME"))
+            .skip(1)
+            .filter_map(|line| {
+                let mut columns = line.split_whitespace();
+                match (columns.next(), columns.next()) {
+                    (Some(name), Some(url)) => Some(RepoEntry {
+                        name: name.to_string(),
+                        url: url.to_string(),
+                    }),
+                    _ => None,
+                }
+            })
+            .collect())
+    }
+
+    // the cached index of `repo`
+    fn repo_index(&self, repo: &str) -> Result<RepoIndex, HelmError> {
+        RepoIndex::read(&self.repo_index_path(repo))
+    }
+
+    // where the cached index of `repo` is, wherever this helm keeps it
+    fn repo_index_path(&self, repo: &str) -> PathBuf {
+        let file = format!("{}-index.yaml", repo);
+        let helm_home = self.helm_home.to_path_buf();
+        // helm 2 caches under $HELM_HOME, helm 3 under $HELM_CACHE_HOME
+        [
+            helm_home.join("repository").join("cache").join(&file),
+            helm_home.join("cache").join("repository").join(&file),
+        ].iter().find(|path| path.exists()).cloned()
+            .unwrap_or(helm_home.join("repository").join("cache").join(&file))
+    }
+
+    /// The versions of chart `name` in `repo`, narrowed down to `version` if
+    /// given, which may be exact or a range.
+    pub fn search(&self, repo: &str, name: &str, version: Option<&str>) -> Result<Vec<ChartVersion>, HelmError> {
+        // a pinned version is usually in the cached index already, refresh it only if it isn't
+        if version.is_some() {
+            let cached = self.repo_index(repo).map(|index| index.search(name, version)).unwrap_or_default();
+            if !cached.is_empty() {
+                return Ok(cached);
+            }
+        }
+        try!(self.update_repos());
+        let index = try!(self.repo_index(repo));
+        Ok(index.search(name, version))
+    }
+
+    /// Download the archive of chart `name` from `repo`, `stable` unless given, into
+    /// the `dest` directory, at `version` or the latest one.
+    pub fn fetch(&self, repo: Option<&str>, name: &str, version: Option<&str>, dest: &Path) -> Result<(), HelmError> {
+        let mut cmd = vec![self.helm_command("fetch")];
+        if let Some(version) = version {
+            cmd.push(format!("--version {}", shell_quote(version)));
+        } else {
+            // the latest version is only as recent as our repo indexes
+            try!(self.update_repos());
+        }
+        cmd.push(format!("--destination {}", shell_quote(&dest.to_string_lossy())));
+        cmd.push(format!("{}/{}", repo.unwrap_or(DEFAULT_REPO), name));
+
+        let phase = self.phase("fetch", Some(name));
+        try!(self.run(&cmd.join(" ")));
+        phase.succeeded();
+        Ok(())
+    }
+
+    /// The metadata and default values of `chart` at `version`, or at the chart's own version.
+    pub fn inspect(&self, chart: &Chart, version: Option<&str>) -> Result<ChartInfo, HelmError> {
+        let flags = try!(self.show_args(chart, version));
+
+        // helm 3 renamed `inspect` to `show`
+        let command = if try!(self.client_version()).major >= 3 { "show" } else { "inspect" };
+        let metadata = try!(self.run(&format!("{} {}",
+            self.helm_command(&format!("{} chart", command)), flags.join(" "))));
+        let values = try!(self.run(&format!("{} {}",
+            self.helm_command(&format!("{} values", command)), flags.join(" "))));
+
+        let metadata: ChartYaml = try!(serde_yaml::from_str(&metadata)
+            .map_err(|e| HelmError::from(e).context(format!("parsing the Chart.yaml of `{}`", chart.name))));
+        Ok(ChartInfo {
+            name: metadata.name,
+            version: metadata.version,
+            app_version: metadata.app_version,
+            description: metadata.description,
+            home: metadata.home,
+            keywords: metadata.keywords.unwrap_or_default(),
+            values: try!(values::parse(&values)),
+        })
+    }
+
+    // the arguments of `helm show` for `chart` at `version`, or at the chart's own version
+    fn show_args(&self, chart: &Chart, version: Option<&str>) -> Result<Vec<String>, HelmError> {
+        let version = version.or(chart.version.as_ref().map(|v| v as &str));
+        let mut args = vec![];
+        if let Some(version) = version {
+            args.push(format!("--version {}", shell_quote(version)));
+        } else if chart.path.is_none() {
+            // the latest version is only as recent as our repo indexes
+            try!(self.update_repos());
+        }
+        if chart.devel.unwrap_or(false) {
+            args.push("--devel".to_string());
+        }
+        if let Some(host) = chart.oci_host() {
+            try!(self.registry_login(host));
+        }
+        args.push(shell_quote(&chart.reference()));
+        Ok(args)
+    }
+
+    /// Apply the CRDs in the `crds/` directory of `chart` ahead of its upgrade, and wait
+    /// up to `timeout` for the apiserver to serve them, returning their names.
+    /// Operator charts otherwise race their own custom resources on first install.
+    pub fn install_crds(&self, chart: &Chart, timeout: Duration) -> Result<Vec<String>, HelmError> {
+        // helm 2 charts install theirs with `crd-install` hooks instead
+        let version = try!(self.client_version());
+        if version.major < 3 {
+            return Err(HelmError::WrongHelmVersion(">=3".to_string(), version.to_string()));
+        }
+
+        let phase = self.phase("crds", Some(&chart.release));
+        let cmd = format!("{} {}", self.helm_command("show crds"), try!(self.show_args(chart, None)).join(" "));
+        let output = try!(self.run(&cmd));
+
+        let mut documents = vec![String::new()];
+        for line in output.lines() {
+            if line.trim_right() == "---" {
+                documents.push(String::new());
+            } else {
+                let document = documents.last_mut().unwrap();
+                document.push_str(line);
+                document.push('\n');
+            }
+        }
+
+        let mut crds = Vec::new();
+        for document in documents {
+            let crd = try!(values::parse(&document));
+            // helm leaves `# Source:` comments between the documents
+            let name = match serde_json::from_value::<kube::Object>(crd.clone()) {
+                Ok(object) => object.metadata.name,
+                Err(_) => continue,
+            };
+            let resource = match crd.as_object().and_then(|crd| crd.get("apiVersion")) {
+                Some(&Value::String(ref api_version)) if api_version == "apiextensions.k8s.io/v1beta1" =>
+                    kube::CUSTOM_RESOURCE_DEFINITIONS_V1BETA1,
+                _ => kube::CUSTOM_RESOURCE_DEFINITIONS,
+            };
+            try!(self.apply_crd(resource, &name, crd));
+            crds.push((resource, name));
+        }
+
+        // custom resources are only accepted once their definition is established
+        let started = Instant::now();
+        loop {
+            let mut pending = Vec::new();
+            for &(resource, ref name) in &crds {
+                let url = try!(self.kube.cluster_object_url(resource, name));
+                let crd: kube::CustomResourceDefinition = try!(self.kube.get(&url));
+                if !crd.is_established() {
+                    pending.push(name.clone());
+                }
+            }
+            if pending.is_empty() {
+                break;
+            }
+            if started.elapsed() >= timeout {
+                return Err(HelmError::CrdsNotEstablished(chart.release.clone(), pending));
+            }
+            thread::sleep(Duration::from_secs(CRD_POLL_SECS));
+        }
+
+        logger::info(format_args!("Installed {} CRDs of `{}` ahead of it.", crds.len(), chart.release));
+        phase.succeeded();
+        Ok(crds.into_iter().map(|(_, name)| name).collect())
+    }
+
+    // create the CRD `name`, or replace the one that's there with it
+    fn apply_crd(&self, resource: Resource, name: &str, mut crd: Value) -> Result<(), HelmError> {
+        let collection_url = try!(self.kube.cluster_collection_url(resource));
+        if try!(self.kube.create(&collection_url, &crd)) {
+            logger::debug(format_args!("Created CRD `{}`.", name));
+            return Ok(());
+        }
+
+        // a replacement has to name the version it replaces
+        let url = try!(self.kube.cluster_object_url(resource, name));
+        let current: kube::Object = try!(self.kube.get(&url));
+        if let (&mut Value::Object(ref mut fields), Some(version)) = (&mut crd, current.metadata.resource_version) {
+            if let Some(&mut Value::Object(ref mut metadata)) = fields.get_mut("metadata") {
+                metadata.insert("resourceVersion".to_string(), Value::String(version));
+            }
+        }
+        try!(self.kube.replace(&url, &crd));
+        logger::debug(format_args!("Replaced CRD `{}`.", name));
+        Ok(())
+    }
+
+    /// `chart` with a version range like `^1.2` or `~2.0.3` resolved to the
+    /// newest version in the repo that satisfies it. Exact versions, local
+    /// charts and unpinned charts are left alone.
+    pub fn resolve_version(&self, chart: &Chart) -> Result<Chart, HelmError> {
+        let requirement = match (&chart.version, &chart.path) {
+            (&Some(ref version), &None) => match repo::version_range(version) {
+                Some(requirement) => requirement,
+                None => return Ok(chart.clone()),
+            },
+            _ => return Ok(chart.clone()),
+        };
+
+        try!(self.update_repos());
+        let index = try!(self.repo_index(chart.repo()));
+        let resolved = match index.resolve(&chart.name, &requirement) {
+            Some(resolved) => resolved.version.clone(),
+            None => return Err(HelmError::NoMatchingVersion(
+                chart.name.clone(), chart.version.clone().unwrap_or_default())),
+        };
+
+        logger::info(format_args!("Resolved `{}` version `{}` to `{}`.",
+            chart.name, requirement, resolved));
+        let mut chart = chart.clone();
+        chart.version = Some(resolved);
+        Ok(chart)
+    }
+
+    // a helm command, run with whichever helm we were configured with
+    fn helm_command(&self, subcommand: &str) -> String {
+        match self.kube_context {
+            Some(ref context) => format!("{} {} --kube-context {}",
+                shell_quote(&self.helm_bin), subcommand, shell_quote(context)),
+            None => format!("{} {}", shell_quote(&self.helm_bin), subcommand),
+        }
+    }
+
+    /// The version of the helm cli, which fails if there's no helm to run at all.
+    pub fn helm_version(&self) -> Result<String, HelmError> {
+        self.client_version().map(|version| version.to_string())
+    }
+
+    /// The version of tiller, if the helm cli needs one. Helm 3 doesn't.
+    pub fn tiller_version(&self) -> Result<Option<String>, HelmError> {
+        if try!(self.client_version()).major >= 3 {
+            return Ok(None);
+        }
+        try!(self.ensure_cli());
+        self.server_version().map(|version| Some(version.to_string()))
+    }
+
+    /// Whether the apiserver answers, and lets us read the workloads of the namespace.
+    pub fn check_api_access(&self) -> Result<(), HelmError> {
+        for resource in &self.workloads() {
+            try!(self.kube.list(*resource, self.listed_namespace(), ""));
+        }
+        Ok(())
+    }
+
+    /// Whether every chart repo can be updated.
+    pub fn check_repos(&self) -> Result<(), HelmError> {
+        self.update_repos()
+    }
+
+    fn client_version(&self) -> Result<Version, HelmError> {
+        let output = try!(self.exec(&self.helm_command("version --client --short")));
+        parse_helm_version(&output)
+    }
+
+    // `--timeout` of `timeout` seconds, which helm 3 takes as a duration
+    fn timeout_flag(&self, timeout: u64) -> Result<String, HelmError> {
+        Ok(if try!(self.client_version()).major >= 3 {
+            format!("--timeout {}s", timeout)
+        } else {
+            format!("--timeout {}", timeout)
+        })
+    }
+
+    // the version of tiller
+    fn server_version(&self) -> Result<Version, HelmError> {
+        let output = try!(self.exec(&self.tiller_command("version --server --short")));
+        parse_helm_version(&output)
+    }
+
+    // fail unless the client and tiller share a major and minor version
+    fn check_version_skew(&self) -> Result<(), HelmError> {
+        let client = try!(self.client_version());
+        let server = try!(self.server_version());
+        if client.major != server.major || client.minor != server.minor {
+            return Err(HelmError::VersionSkew(client.to_string(), server.to_string()));
+        }
+        Ok(())
+    }
+
+    // a helm command that talks to tiller, wherever it lives and however it's secured
+    fn tiller_command(&self, subcommand: &str) -> String {
+        let mut cmd = vec![self.helm_command(subcommand)];
+        if let Some(ref namespace) = self.tiller_namespace {
+            cmd.push(format!("--tiller-namespace {}", namespace));
+        }
+        if let Some(ref tls) = self.tiller_tls {
+            cmd.push(format!("--tls --tls-cert {} --tls-key {}",
+                tls.cert.to_path_buf().display(), tls.key.path().display()));
+            if let Some(ref ca_cert) = tls.ca_cert {
+                cmd.push(format!("--tls-verify --tls-ca-cert {}", ca_cert.to_path_buf().display()));
+            }
+        }
+        cmd.join(" ")
+    }
+
+    /// The apiserver of the cluster we deploy to.
+    pub fn server(&self) -> String {
+        self.kube.server().to_string()
+    }
+
+    /// How long each phase took so far, in the order they ended.
+    pub fn timings(&self) -> Vec<Timing> {
+        self.timings.lock().unwrap().clone()
+    }
+
+    // begin a phase whose timing ends up in `timings`
+    fn phase(&self, name: &'static str, release: Option<&str>) -> Phase {
+        logger::phase(name, release).recorded(&self.timings)
+    }
+
+    /// The namespace releases are deployed into, if it isn't every one.
+    pub fn namespace(&self) -> Option<&str> {
+        self.namespace.as_ref().map(|n| n as &str)
+    }
+
+    // the namespace to list things in, every one if `None`
+    fn listed_namespace(&self) -> Option<&str> {
+        self.namespace()
+    }
+
+    // whether things in `namespace` are ours
+    fn in_namespace(&self, namespace: &str) -> bool {
+        self.namespace.as_ref().map(|ours| ours == namespace).unwrap_or(true)
+    }
+
+    // which namespaces `helm list` looks in: ours, or every one if we have none
+    fn list_namespace_flag(&self) -> Result<String, HelmError> {
+        Ok(match self.namespace {
+            Some(ref namespace) => format!("--namespace {}", namespace),
+            None if try!(self.client_version()).major >= 3 => "--all-namespaces".to_string(),
+            // helm 2 lists every namespace unless told otherwise
+            None => String::new(),
+        })
+    }
+
+    // where `chart` is deployed: our namespace, or the chart's own when we have none
+    fn chart_namespace(&self, chart: &Chart) -> Result<String, HelmError> {
+        match (self.namespace.as_ref(), chart.namespace.as_ref()) {
+            (Some(ours), Some(theirs)) if ours != theirs => Err(HelmError::InvalidChart(chart.release.clone(),
+                vec![format!("the namespace `{}` isn't the source's `{}`", theirs, ours)])),
+            (Some(namespace), _) | (None, Some(namespace)) => Ok(namespace.clone()),
+            (None, None) => Err(HelmError::InvalidChart(chart.release.clone(),
+                vec!["the source deploys to every namespace, so the chart has to name one".to_string()])),
+        }
+    }
+
+    // the namespace `release` is deployed in, looked up if we have none
+    fn release_namespace(&self, release: &str) -> Result<String, HelmError> {
+        if let Some(ref namespace) = self.namespace {
+            return Ok(namespace.clone());
+        }
+        if let Some(namespace) = self.release_namespaces.lock().unwrap().get(release) {
+            return Ok(namespace.clone());
+        }
+        match try!(self.helm_release(release)) {
+            Some(r) => {
+                self.release_namespaces.lock().unwrap().insert(release.to_string(), r.namespace.clone());
+                Ok(r.namespace)
+            },
+            None => Err(HelmError::MissingConfig(format!(
+                "release `{}` isn't deployed in any namespace, and the source doesn't name one", release))),
+        }
+    }
+
+    // a helm command about `release`, pointed at its namespace when the kubeconfig's isn't it
+    fn release_command(&self, subcommand: &str, release: &str) -> Result<String, HelmError> {
+        let cmd = self.tiller_command(subcommand);
+        // tiller finds releases by name alone
+        if self.namespace.is_some() || try!(self.client_version()).major < 3 {
+            return Ok(cmd);
+        }
+        Ok(format!("{} --namespace {}", cmd, try!(self.release_namespace(release))))
+    }
+
+    // the name helm knows `release` by, with our prefix and suffix
+    fn release_name(&self, release: &str) -> String {
+        format!("{}{}{}", self.release_prefix, release, self.release_suffix)
+    }
+
+    // the release called `name` by helm, if it has our prefix and suffix
+    fn release_of(&self, name: &str) -> Option<String> {
+        if name.len() < self.release_prefix.len() + self.release_suffix.len()
+            || !name.starts_with(&self.release_prefix as &str)
+            || !name.ends_with(&self.release_suffix as &str)
+        {
+            return None;
+        }
+        Some(name[self.release_prefix.len()..name.len() - self.release_suffix.len()].to_string())
+    }
+
+    // select the workloads labelled by `convention`, of our release if we're filtering
+    fn label_selector(&self, convention: &LabelConvention) -> String {
+        self.workload_selector(convention, self.release.as_ref().map(|release| release as &str))
+    }
+
+    // select the workloads labelled by `convention`, only those of `release` if given
+    fn workload_selector(&self, convention: &LabelConvention, release: Option<&str>) -> String {
+        let mut selector = convention.selector.iter()
+            .map(|&(ref label, ref value)| format!("{}={}", label, value))
+            .collect::<Vec<_>>()
+            .join(",");
+        if let Some(release) = release {
+            selector.push_str(&format!(",{}={}", convention.release, self.release_name(release)));
+        }
+        selector
+    }
+
+    pub fn list(&self) -> Result<Releases, HelmError> {
+        self.list_with_status(&self.statuses)
+    }
+
+    /// The deployed releases, or only those in one of `statuses` if any are given.
+    pub fn list_with_status(&self, statuses: &[StatusFilter]) -> Result<Releases, HelmError> {
+        match (self.list_backend, statuses.is_empty()) {
+            (ListBackend::Kube, true) => self.list_from_kube(),
+            _ => self.list_from_helm(statuses),
+        }
+    }
+
+    fn list_from_helm(&self, statuses: &[StatusFilter]) -> Result<Releases, HelmError> {
+        let flags = if statuses.contains(&StatusFilter::All) {
+            StatusFilter::All.flag().to_string()
+        } else {
+            statuses.iter().map(|status| status.flag()).collect::<Vec<_>>().join(" ")
+        };
+
+        let mut releases: Releases = try!(self.helm_releases(&flags)).into_iter()
+            .map(|(release, r)| {
+                let (chart, version) = split_chart_label(&r.chart)
+                    .map(|(chart, version)| (chart, Some(version)))
+                    .unwrap_or((r.chart.clone(), None));
+                Release {
+                    name: release,
+                    chart: chart,
+                    version: version,
+                    // helm leaves it empty for charts that don't set one
+                    app_version: r.app_version.and_then(|v| if v.is_empty() { None } else { Some(v) }),
+                    namespace: r.namespace,
+                    revision: r.revision,
+                    status: r.status.as_ref().map(|status| ReleaseStatus::parse(status)),
+                    updated: r.updated,
+                }
+            })
+            .collect();
+
+        // helm sorts every namespace's releases together, by name alone
+        if self.namespace.is_none() {
+            releases.sort_by(|a, b| (&a.namespace, &a.name).cmp(&(&b.namespace, &b.name)));
+        }
+        Ok(releases)
+    }
+
+    /// The releases whose latest revision failed, e.g. to clean them up.
+    pub fn failed_releases(&self) -> Result<Vec<String>, HelmError> {
+        Ok(try!(self.helm_releases("--failed")).into_iter()
+            .map(|(release, _)| release)
+            .collect())
+    }
+
+    // every page of `helm list <flags>` in our namespace, by release name without prefix and suffix
+    fn helm_releases(&self, flags: &str) -> Result<Vec<(String, HelmReleaseEntry)>, HelmError> {
+        let mut releases = Vec::new();
+        let mut offset = String::new();
+
+        loop {
+            let mut cmd = format!("{} --output json {}", self.tiller_command("list"), try!(self.list_namespace_flag()));
+            if !flags.is_empty() {
+                cmd.push_str(&format!(" {}", flags));
+            }
+            if let Some(ref release) = self.release {
+                cmd.push_str(&format!(" '^{}$'", self.release_name(release)));
+            }
+            if !offset.is_empty() {
+                cmd.push_str(&format!(" --offset {}", offset));
+            }
+
+            // helm prints nothing at all when there are no releases
+            let output = try!(self.run(&cmd));
+            if output.is_empty() {
+                return Ok(releases);
+            }
+
+            let (page, next) = try!(parse_helm_list(&output, try!(self.client_version()).major));
+            releases.extend(page.into_iter()
+                .filter(|r| self.in_namespace(&r.namespace))
+                .filter_map(|r| self.release_of(&r.name).map(|release| (release, r))));
+
+            match next {
+                Some(next) => if next.is_empty() {
+                    return Ok(releases);
+                } else {
+                    offset = next;
+                },
+                None => return Ok(releases),
+            }
+        }
+    }
+
+    fn list_from_kube(&self) -> Result<Releases, HelmError> {
+        // a release usually owns several workloads, keep one per release
+        let mut releases = BTreeMap::new();
+        let records = self.release_records();
+
+        // releases may be labelled by tiller or by helm 3, e.g. after a migration
+        for convention in &self.label_conventions {
+            // let the apiserver do the filtering so we only download helm's workloads
+            let selector = self.label_selector(convention);
+
+            // gather every workload in the namespace, whatever its kind
+            let mut workloads = Vec::new();
+            for resource in &self.workloads() {
+                workloads.extend(try!(self.kube.list(*resource, self.listed_namespace(), &selector)));
+            }
+
+            for workload in workloads {
+                let metadata = workload.metadata;
+
+                let namespace = match metadata.namespace {
+                    Some(ref namespace) if self.in_namespace(namespace) => namespace.clone(),
+                    _ => continue,
+                };
+                if !convention.selects(&metadata.labels) {
+                    continue;
+                }
+
+                // helm 3 records the exact release name in an annotation
+                let release = match convention.release_annotation.as_ref()
+                    .and_then(|annotation| metadata.annotations.get(annotation))
+                    .or_else(|| metadata.labels.get(&convention.release))
+                    .and_then(|release| self.release_of(release))
+                {
+                    Some(release) => release,
+                    None => continue,
+                };
+                let (name, version) = match metadata.labels.get(&convention.chart).and_then(|c| split_chart_label(c)) {
+                    Some(chart) => chart,
+                    None => continue,
+                };
+
+                let name_in_helm = self.release_name(&release);
+                let record = records.get(&(Some(namespace.clone()), name_in_helm.clone()))
+                    .or_else(|| records.get(&(None, name_in_helm)));
+                releases.insert((namespace.clone(), release.clone()), Release {
+                    name: release.clone(),
+                    chart: name,
+                    version: Some(version),
+                    app_version: metadata.labels.get("app.kubernetes.io/version").cloned(),
+                    namespace: namespace,
+                    revision: record.map(|record| record.revision),
+                    status: record.map(|record| record.status),
+                    updated: record.and_then(|record| record.updated.clone()),
+                });
+            }
+        }
+
+        Ok(releases.into_iter().map(|(_, release)| release).collect())
+    }
+
+    // the latest revision of every release tiller or helm 3 recorded, by namespace and
+    // release name, tiller's without a namespace as it names releases cluster-wide.
+    // they're a nice to have, so not being allowed to read them isn't an error
+    fn release_records(&self) -> HashMap<(Option<String>, String), ReleaseRecord> {
+        let tiller_namespace = self.tiller_namespace.as_ref().map(|n| n as &str).unwrap_or("kube-system");
+        let stores = [
+            // tiller keeps configmaps in its own namespace, labelled in capitals
+            (CONFIGMAPS, Some(tiller_namespace), false, "OWNER=TILLER",
+                ["NAME", "VERSION", "STATUS", "MODIFIED_AT"]),
+            // helm 3 keeps secrets next to the release
+            (SECRETS, self.listed_namespace(), true, "owner=helm",
+                ["name", "version", "status", "modifiedAt"]),
+        ];
+
+        let mut records: HashMap<(Option<String>, String), ReleaseRecord> = HashMap::new();
+        for &(resource, namespace, next_to_release, selector, labels) in &stores {
+            let objects = match self.kube.list(resource, namespace, selector) {
+                Ok(objects) => objects,
+                Err(e) => {
+                    logger::debug(format_args!("Could not read release records from {}: {}", resource.name, e));
+                    continue;
+                },
+            };
+
+            for object in objects {
+                let labels_of = &object.metadata.labels;
+                let (name, revision) = match (labels_of.get(labels[0]),
+                    labels_of.get(labels[1]).and_then(|v| v.parse::<u64>().ok()))
+                {
+                    (Some(name), Some(revision)) => (name.clone(), revision),
+                    _ => continue,
+                };
+                let key = (if next_to_release { object.metadata.namespace.clone() } else { None }, name);
+                if records.get(&key).map(|record| record.revision >= revision).unwrap_or(false) {
+                    continue;
+                }
+                records.insert(key, ReleaseRecord {
+                    revision: revision,
+                    status: labels_of.get(labels[2])
+                        .map(|status| ReleaseStatus::parse(status))
+                        .unwrap_or(ReleaseStatus::Unknown),
+                    updated: labels_of.get(labels[3]).cloned(),
+                });
+            }
+        }
+        records
+    }
+
+    /// Where the cluster is at, to ask `changed_since` about later. `None` if
+    /// the apiserver wouldn't say, e.g. because we may not list anything.
+    pub fn resource_version(&self) -> Option<String> {
+        // a fast check makes no request it doesn't have to
+        if self.fast_check {
+            return None;
+        }
+        // resource versions are global to the cluster, any listing's will do
+        self.digest_sources().into_iter()
+            .filter_map(|(resource, namespace, selector)| {
+                match self.kube.resource_version(resource, namespace, selector) {
+                    Ok(resource_version) => resource_version,
+                    Err(e) => {
+                        logger::debug(format_args!("Could not list {}: {}", resource.name, e));
+                        None
+                    },
+                }
+            })
+            .next()
+    }
+
+    /// Whether the digest may have changed since `resource_version`, without
+    /// computing it. Anything we can't watch counts as changed.
+    pub fn changed_since(&self, resource_version: &str) -> bool {
+        if self.fast_check {
+            return true;
+        }
+        self.digest_sources().into_iter().any(|(resource, namespace, selector)| {
+            match self.kube.changed_since(resource, namespace, selector, resource_version) {
+                Ok(changed) => changed,
+                Err(e) => {
+                    logger::debug(format_args!("Could not watch {}: {}", resource.name, e));
+                    true
+                },
+            }
+        })
+    }
+
+    // what the digest is made of, as listings to watch: the release records of
+    // tiller and helm 3, and the workloads too when they're listed or hashed
+    fn digest_sources(&self) -> Vec<(Resource, Option<&str>, &'static str)> {
+        let tiller_namespace = self.tiller_namespace.as_ref().map(|n| n as &str).unwrap_or("kube-system");
+        let mut sources = vec![
+            (CONFIGMAPS, Some(tiller_namespace), "OWNER=TILLER"),
+            (SECRETS, self.listed_namespace(), "owner=helm"),
+        ];
+        if self.detect_drift || self.list_backend == ListBackend::Kube {
+            sources.extend(self.workloads().into_iter().map(|resource| (resource, self.listed_namespace(), "")));
+        }
+        sources
+    }
+
+    pub fn digest(&self) -> Result<String, HelmError> {
+        self.digest_with(self.digest_algorithm)
+    }
+
+    /// Whether `digest` is of the same state as `current`, even when it was
+    /// taken with another algorithm, e.g. before `digest_algorithm` changed.
+    pub fn same_digest(&self, digest: &str, current: &str) -> Result<bool, HelmError> {
+        if digest == current {
+            return Ok(true);
+        }
+        let algorithm = DigestAlgorithm::of(digest);
+        if algorithm == self.digest_algorithm {
+            return Ok(false);
+        }
+        Ok(try!(self.digest_with(algorithm)) == digest)
+    }
+
+    fn digest_with(&self, algorithm: DigestAlgorithm) -> Result<String, HelmError> {
+        if self.fast_check {
+            return self.fast_digest(algorithm);
+        }
+
+        let mut hash = Hasher::new(algorithm);
+        for release in try!(self.list()) {
+            // release names are only unique within a namespace, or tiller
+            if self.namespace.is_none() {
+                hash.consume(release.namespace);
+            }
+            hash.consume(release.name);
+            hash.consume(release.chart);
+            if let Some(version) = release.version {
+                hash.consume(version);
+            }
+        }
+
+        // a `kubectl edit` leaves the labels alone, but bumps the generation
+        if self.detect_drift {
+            for generation in try!(self.workload_generations()) {
+                hash.consume(generation);
+            }
+        }
+        Ok(hash.finish())
+    }
+
+    // the latest revision of every release helm 3 recorded, from a single listing of its secrets.
+    // any upgrade, rollback or uninstall records a new one
+    fn fast_digest(&self, algorithm: DigestAlgorithm) -> Result<String, HelmError> {
+        let mut selector = "owner=helm".to_string();
+        if let Some(ref release) = self.release {
+            selector.push_str(&format!(",name={}", self.release_name(release)));
+        }
+
+        let mut revisions = BTreeMap::new();
+        for secret in try!(self.kube.list(SECRETS, self.listed_namespace(), &selector)) {
+            let metadata = secret.metadata;
+            let namespace = match metadata.namespace {
+                Some(ref namespace) if self.in_namespace(namespace) => namespace.clone(),
+                _ => continue,
+            };
+            let (release, revision) = match (metadata.labels.get("name").and_then(|name| self.release_of(name)),
+                metadata.labels.get("version").and_then(|v| v.parse::<u64>().ok()))
+            {
+                (Some(release), Some(revision)) => (release, revision),
+                _ => continue,
+            };
+            let latest = revisions.entry((namespace, release)).or_insert(revision);
+            *latest = revision.max(*latest);
+        }
+
+        let mut hash = Hasher::new(algorithm);
+        for ((namespace, release), revision) in revisions {
+            if self.namespace.is_none() {
+                hash.consume(namespace);
+            }
+            hash.consume(release);
+            hash.consume(revision.to_string());
+        }
+        Ok(hash.finish())
+    }
+
+    // every kind of workload, each in the newest group version the cluster serves it in.
+    // if the cluster won't say, the newest ones it may serve
+    fn workloads(&self) -> Vec<Resource> {
+        let mut discovered = self.workloads.lock().unwrap();
+        if let Some(ref workloads) = *discovered {
+            return workloads.clone();
+        }
+
+        let workloads = self.discover_workloads().unwrap_or_else(|e| {
+            logger::debug(format_args!("Could not discover the workload api groups: {}", e));
+            WORKLOAD_KINDS.iter()
+                .map(|&(kind, groups)| Resource { group: groups[0], name: kind })
+                .collect()
+        });
+        *discovered = Some(workloads.clone());
+        workloads
+    }
+
+    fn discover_workloads(&self) -> Result<Vec<Resource>, HelmError> {
+        // what each group version serves, most of them serve several workloads
+        let mut served: HashMap<&str, Option<Vec<String>>> = HashMap::new();
+        let mut workloads = Vec::new();
+        for &(kind, groups) in WORKLOAD_KINDS {
+            let mut found = None;
+            for &group in groups {
+                if !served.contains_key(group) {
+                    served.insert(group, try!(self.kube.group_resources(group)));
+                }
+                if served[group].as_ref().map(|resources| resources.iter().any(|r| r == kind)).unwrap_or(false) {
+                    found = Some(group);
+                    break;
+                }
+            }
+            match found {
+                Some(group) => workloads.push(Resource { group: group, name: kind }),
+                None => logger::debug(format_args!("The cluster serves no {}.", kind)),
+            }
+        }
+        Ok(workloads)
+    }
+
+    // `<kind>/<name>:<generation>` of every workload helm manages in the namespace, sorted,
+    // each under `<namespace>/` when we manage every namespace
+    fn workload_generations(&self) -> Result<Vec<String>, HelmError> {
+        let mut generations = Vec::new();
+        for convention in &self.label_conventions {
+            let selector = self.label_selector(convention);
+            for resource in &self.workloads() {
+                for workload in try!(self.kube.list(*resource, self.listed_namespace(), &selector)) {
+                    let mut generation = format!("{}/{}:{}", resource.name, workload.metadata.name,
+                        workload.metadata.generation.unwrap_or(0));
+                    if self.namespace.is_none() {
+                        generation = format!("{}/{}", workload.metadata.namespace.unwrap_or_default(), generation);
+                    }
+                    generations.push(generation);
+                }
+            }
+        }
+        generations.sort();
+        generations.dedup();
+        Ok(generations)
+    }
+
+    // the flags that pick the chart version and values, shared by upgrade and diff.
+    // the returned values files have to outlive the command using them.
+    fn chart_args(&self, chart: &Chart) -> Result<(Vec<String>, ValuesFiles), HelmError> {
+        let phase = self.phase("render", Some(&chart.release));
+        let mut cmd = vec![];
+
+        // charts in a private registry need us logged in, and umbrella charts their subcharts
+        if let Some(host) = chart.oci_host() {
+            try!(self.registry_login(host));
+        } else if let Some(ref path) = chart.path {
+            try!(self.dependency_update(path));
+        }
+
+        if chart.devel.unwrap_or(false) {
+            cmd.push("--devel".to_string());
+        }
+        if let Some(ref version) = chart.version {
+            cmd.push(format!("--version {}", version));
+        } else if chart.path.is_none() {
+            // the latest version is only as recent as our repo indexes
+            try!(self.update_repos());
+        }
+
+        if chart.merge_strategy == Some(MergeStrategy::Deep) {
+            let (values, secret_paths) = try!(self.merged_values(chart));
+            let resolved = try!(private_file(try!(serde_yaml::to_string(&values)).as_bytes()));
+            cmd.push(format!("--values {}", resolved.to_path_buf().to_string_lossy().into_owned()));
+
+            logger::info(format_args!("Using resolved values:\n{}",
+                try!(serde_yaml::to_string(&redact_merged(chart, &values, secret_paths)))));
+
+            phase.succeeded();
+            return Ok((cmd, ValuesFiles {
+                resolved: Some(resolved),
+                ..ValuesFiles::default()
+            }));
+        }
+
+        // plain values files are the least specific of all
+        for values_file in chart.values_files.as_ref().map(|f| f as &[String]).unwrap_or(&[]) {
+            cmd.push(format!("--values {}", shell_quote(values_file)));
+        }
+
+        // JSON is YAML to helm, but a broken file should say which it is
+        for json_file in chart.values_json_files.as_ref().map(|f| f as &[String]).unwrap_or(&[]) {
+            try!(File::open(json_file)
+                .map_err(HelmError::from)
+                .and_then(|file| serde_json::from_reader::<_, serde_json::Map<String, Value>>(file)
+                    .map_err(HelmError::from))
+                .map_err(|e| e.context(format!("reading the JSON values file `{}`", json_file))));
+            cmd.push(format!("--values {}", shell_quote(json_file)));
+        }
+
+        // decrypted secrets go first so the inline overrides can still win
+        let mut secrets = Vec::new();
+        for secret_values_file in chart.secret_values_files.as_ref().map(|f| f as &[String]).unwrap_or(&[]) {
+            let decrypted = try!(self.sops.decrypt(secret_values_file));
+            cmd.push(format!("--values {}", decrypted.path().to_string_lossy().into_owned()));
+            secrets.push(decrypted);
+        }
+
+        // the rendered template comes before the inline overrides too, they're the most specific
+        let template_file = if let Some(ref values_template) = chart.values_template {
+            let vars = chart.template_vars.clone().unwrap_or_default();
+            let values = try!(render_values_template(values_template, &vars));
+            let template_path = try!(private_file(&values));
+            cmd.push(format!("--values {}",
+                template_path.to_path_buf().to_string_lossy().into_owned()));
+            Some(template_path)
+        } else {
+            None
+        };
+
+        let json_file = if let Some(ref values_json) = chart.values_json {
+            let values: serde_json::Map<String, Value> = try!(serde_json::from_str(values_json)
+                .map_err(|e| HelmError::from(e).context("parsing `values_json`")));
+            let json_path = try!(private_file(try!(serde_yaml::to_string(&values)).as_bytes()));
+            cmd.push(format!("--values {}",
+                json_path.to_path_buf().to_string_lossy().into_owned()));
+            Some(json_path)
+        } else {
+            None
+        };
+
+        let overrides_file = if let Some(ref overrides) = chart.overrides {
+            // overrides may hold secrets, e.g. resolved from vault
+            let values = try!(serde_yaml::to_string(overrides));
+            let override_path = try!(private_file(values.as_bytes()));
+
+            // set values file flag
+            cmd.push(format!("--values {}",
+                override_path.to_path_buf().to_string_lossy().into_owned()));
+
+            // log values used, without the secrets among them
+            let secret_keys = chart.secret_keys.as_ref().map(|k| k as &[String]).unwrap_or(&[]);
+            logger::info(format_args!("Using values:\n{}",
+                try!(serde_yaml::to_string(&redact::values(overrides, secret_keys)))));
+
+            Some(override_path)
+        } else {
+            None
+        };
+
+        phase.succeeded();
+        Ok((cmd, ValuesFiles {
+            resolved: None,
+            template: template_file,
+            json: json_file,
+            overrides: overrides_file,
+            secrets: secrets,
+        }))
+    }
+
+    /// Every values source of `chart` merged the way `MergeStrategy::Deep` does,
+    /// with secrets masked so they're safe to log or archive.
+    pub fn resolved_values(&self, chart: &Chart) -> Result<HashMap<String, Value>, HelmError> {
+        let (values, secret_paths) = try!(self.merged_values(chart));
+        Ok(redact_merged(chart, &values, secret_paths))
+    }
+
+    // the values sources of `chart` deep-merged in the order helm would apply them,
+    // and the paths of the values that came out of encrypted files
+    fn merged_values(&self, chart: &Chart) -> Result<(HashMap<String, Value>, Vec<String>), HelmError> {
+        let mut merged = Value::Object(serde_json::Map::new());
+        let mut secret_paths = Vec::new();
+
+        let values_files = chart.values_files.iter().chain(chart.values_json_files.iter()).flat_map(|f| f);
+        for values_file in values_files {
+            values::merge(&mut merged, try!(values::read_file(values_file)));
+        }
+        for secret_values_file in chart.secret_values_files.as_ref().map(|f| f as &[String]).unwrap_or(&[]) {
+            let decrypted = try!(self.sops.decrypt(secret_values_file));
+            let secrets = try!(values::read_file(&decrypted.path().to_string_lossy()));
+            secret_paths.extend(values::leaf_paths(&secrets));
+            values::merge(&mut merged, secrets);
+        }
+        if let Some(ref values_template) = chart.values_template {
+            let vars = chart.template_vars.clone().unwrap_or_default();
+            let rendered = try!(render_values_template(values_template, &vars));
+            values::merge(&mut merged, try!(values::parse(&String::from_utf8_lossy(&rendered))
+                .map_err(|e| e.context(format!("parsing the rendered `{}`", values_template)))));
+        }
+        if let Some(ref values_json) = chart.values_json {
+            values::merge(&mut merged, try!(serde_json::from_str(values_json)
+                .map_err(|e| HelmError::from(e).context("parsing `values_json`"))));
+        }
+        if let Some(ref overrides) = chart.overrides {
+            values::merge(&mut merged, Value::Object(overrides.clone().into_iter().collect()));
+        }
+
+        match merged {
+            Value::Object(fields) => Ok((fields.into_iter().collect(), secret_paths)),
+            _ => Ok((HashMap::new(), secret_paths)),
+        }
+    }
+
+    /// Whether this helm can upgrade with `options` at all, to find out before deploying anything.
+    pub fn check_options(&self, options: &UpgradeOptions) -> Result<(), HelmError> {
+        // helm 2 charts install their CRDs with `crd-install` hooks, there's nothing to skip
+        if options.skip_crds {
+            let version = try!(self.client_version());
+            if version.major < 3 {
+                return Err(HelmError::WrongHelmVersion(">=3".to_string(), version.to_string()));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn upgrade(&self, chart: &Chart, options: &UpgradeOptions) -> Result<(), HelmError> {
+        try!(self.check_options(options));
+        // a failed or pending release refuses upgrades until it's dealt with
+        if let (Some(policy), false) = (options.recover, options.dry_run) {
+            try!(self.recover(&chart.release, policy));
+        }
+
+        // fail on a typo'd chart or version before helm gets halfway through
+        if chart.path.is_none() {
+            let version = chart.version.as_ref().map(|v| v as &str);
+            if try!(self.search(chart.repo(), &chart.name, version)).is_empty() {
+                return Err(HelmError::ChartNotFound(
+                    chart.repo().to_string(), chart.name.clone(), chart.version.clone()));
+            }
+        }
+
+        let mut cmd = vec![];
+
+        // start of the command
+        let namespace = try!(self.chart_namespace(chart));
+        self.release_namespaces.lock().unwrap().insert(chart.release.clone(), namespace.clone());
+        cmd.push(format!("{} --namespace {}", self.tiller_command("upgrade"), namespace));
+
+        if options.install {
+            cmd.push("--install".to_string());
+        }
+        if options.wait {
+            cmd.push("--wait".to_string());
+        }
+        if let Some(timeout) = options.timeout {
+            cmd.push(try!(self.timeout_flag(timeout)));
+        }
+        if options.dry_run {
+            cmd.push("--dry-run --debug".to_string());
+        }
+        if options.no_hooks {
+            cmd.push("--no-hooks".to_string());
+        }
+        if options.reuse_values {
+            cmd.push("--reuse-values".to_string());
+        }
+        if options.reset_values {
+            cmd.push("--reset-values".to_string());
+        }
+        if let Some(ref description) = options.description {
+            cmd.push(format!("--description {}", shell_quote(description)));
+        }
+        if options.force {
+            cmd.push("--force".to_string());
+        }
+        if options.recreate_pods {
+            cmd.push("--recreate-pods".to_string());
+        }
+        if options.skip_crds {
+            cmd.push("--skip-crds".to_string());
+        }
+        // helm 3 prunes revisions itself, tiller's revisions we prune once we're done
+        let prune_history = match self.history_max {
+            Some(history_max) => if try!(self.client_version()).major >= 3 {
+                cmd.push(format!("--history-max {}", history_max));
+                None
+            } else if options.dry_run {
+                None
+            } else {
+                Some(history_max)
+            },
+            None => None,
+        };
+        if chart.verify.unwrap_or(false) {
+            let keyring = try!(self.keyring.as_ref().ok_or(HelmError::MissingConfig(
+                format!("chart `{}` is to be verified, but there's no `keyring`", chart.release))));
+            cmd.push(format!("--verify --keyring {}", shell_quote(&keyring.to_string_lossy())));
+        }
+
+        // helm only takes the post renderer's path, so wrap it up with its args
+        let _post_renderer = if let Some(ref post_renderer) = options.post_renderer {
+            let wrapper_path = try!(Temp::new_file());
+            {
+                // the file has to be closed again before anything can execute it
+                let mut wrapper = try!(File::create(&wrapper_path));
+                let mut exec = vec![shell_quote(&post_renderer.path)];
+                exec.extend(post_renderer.args.iter().map(|arg| shell_quote(arg)));
+                try!(wrapper.write_fmt(format_args!("#!/bin/sh\nexec {} \"$@\"\n", exec.join(" "))));
+                try!(wrapper.flush());
+            }
+            try!(fs::set_permissions(&wrapper_path, fs::Permissions::from_mode(0o700)));
+
+            cmd.push(format!("--post-renderer {}",
+                wrapper_path.to_path_buf().to_string_lossy().into_owned()));
+            Some(wrapper_path)
+        } else {
+            None
+        };
+
+        // the values files are removed when they go out of scope, whether or not this works
+        let (chart_args, _values_files) = try!(self.chart_args(chart));
+        cmd.extend(chart_args);
+
+        // end of the command
+        cmd.push(format!("{} {}", self.release_name(&chart.release), shell_quote(&chart.reference())));
+
+        // flags helm grew that we don't wrap, quoted so each reaches helm as one argument
+        cmd.extend(self.extra_args.iter().chain(&options.extra_args).map(|arg| shell_quote(arg)));
+
+        // with --wait this can take a while, so keep saying it's still going
+        let mut phase = self.phase("upgrade", Some(&chart.release));
+        let hook_timeout = options.hook_timeout.map(Duration::from_secs);
+        // no hooks run in a dry run
+        let watch_hooks = options.watch_hooks && !options.no_hooks && !options.dry_run;
+        try!(self.run_watching_hooks(&cmd.join(" "), &chart.release, hook_timeout, watch_hooks, options.deadline,
+            &mut phase));
+
+        if let Some(history_max) = prune_history {
+            try!(self.prune_history(&chart.release, history_max));
+        }
+        phase.succeeded();
+        Ok(())
+    }
+
+    // delete all but the newest `keep` revisions of `release` that tiller stored in configmaps
+    fn prune_history(&self, release: &str, keep: u32) -> Result<(), HelmError> {
+        let namespace = self.tiller_namespace.as_ref().map(|n| n as &str).unwrap_or("kube-system");
+        let selector = format!("OWNER=TILLER,NAME={}", self.release_name(release));
+
+        let mut revisions: Vec<(u64, String)> = try!(self.kube.list(CONFIGMAPS, Some(namespace), &selector))
+            .into_iter()
+            .filter_map(|configmap| configmap.metadata.labels.get("VERSION")
+                .and_then(|version| version.parse().ok())
+                .map(|version| (version, configmap.metadata.name.clone())))
+            .collect();
+        revisions.sort_by(|a, b| b.0.cmp(&a.0));
+
+        for &(version, ref name) in revisions.iter().skip(keep as usize) {
+            logger::debug(format_args!("Pruning revision {} of `{}`.", version, release));
+            try!(self.kube.delete(&try!(self.kube.object_url(CONFIGMAPS, namespace, name, None))));
+        }
+        Ok(())
+    }
+
+    // run an upgrade of `release`, killing it once a hook job has been running for longer
+    // than `timeout` or at the `deadline`, and keeping `phase` alive in the logs meanwhile.
+    // to `watch` the hook jobs also fails it on the first that fails, and waits for the rest
+    fn run_watching_hooks(&self, cmd: &str, release: &str, timeout: Option<Duration>, watch: bool,
+        deadline: Option<Instant>, phase: &mut logger::Phase) -> Result<String, HelmError>
+    {
+        try!(self.ensure_cli());
+
+        // the hook jobs of earlier upgrades say nothing about this one
+        let mut finished = HashSet::new();
+        if watch {
+            match self.hook_jobs(release) {
+                Ok(jobs) => finished.extend(jobs.into_iter().filter_map(|job| job.metadata.uid)),
+                Err(e) => logger::debug(format_args!("Could not list the hooks of `{}`: {}", release, e)),
+            }
+        }
+
+        // when we first saw each hook job that hasn't finished
+        let mut first_seen: HashMap<String, Instant> = HashMap::new();
+        let output = try!(self.spawn_watched(cmd, Duration::from_secs(ROLLOUT_POLL_SECS), || {
+            phase.heartbeat();
+            if deadline.map(|deadline| Instant::now() >= deadline).unwrap_or(false) {
+                return Err(HelmError::DeadlineExceeded(release.to_string()));
+            }
+            if watch {
+                match self.check_hook_jobs(release, &mut finished) {
+                    Err(e @ HelmError::HookFailed(..)) => return Err(e),
+                    Err(e) => logger::warn(format_args!("Could not check on the hooks of `{}`: {}", release, e)),
+                    Ok(_) => (),
+                }
+            }
+            let timeout = match timeout {
+                Some(timeout) => timeout,
+                None => return Ok(()),
+            };
+            let pending = match self.pending_hooks(release) {
+                Ok(pending) => pending,
+                Err(e) => {
+                    logger::warn(format_args!("Could not check on the hooks of `{}`: {}", release, e));
+                    return Ok(());
+                },
+            };
+            first_seen.retain(|hook, _| pending.contains(hook));
+            for hook in pending {
+                first_seen.entry(hook).or_insert(Instant::now());
+            }
+
+            let mut overdue: Vec<String> = first_seen.iter()
+                .filter(|&(_, seen)| seen.elapsed() > timeout)
+                .map(|(hook, _)| hook.clone())
+                .collect();
+            if overdue.is_empty() {
+                return Ok(());
+            }
+            overdue.sort();
+            Err(HelmError::HookTimeout(release.to_string(), overdue))
+        }));
+        let stdout = try!(checked_stdout(cmd, output));
+
+        // helm is done, but a hook job it didn't wait for may still be going
+        if watch {
+            let give_up = Instant::now() + timeout.unwrap_or(Duration::from_secs(DEFAULT_HOOK_WAIT_SECS));
+            loop {
+                let pending = try!(self.check_hook_jobs(release, &mut finished));
+                if pending.is_empty() {
+                    break;
+                }
+                if deadline.map(|deadline| Instant::now() >= deadline).unwrap_or(false) {
+                    return Err(HelmError::DeadlineExceeded(release.to_string()));
+                }
+                if Instant::now() >= give_up {
+                    return Err(HelmError::HookTimeout(release.to_string(), pending));
+                }
+                phase.heartbeat();
+                thread::sleep(Duration::from_secs(ROLLOUT_POLL_SECS));
+            }
+        }
+        Ok(stdout)
+    }
+
+    // the hook jobs of `release`, however it's labelled
+    fn hook_jobs(&self, release: &str) -> Result<Vec<kube::Deployment>, HelmError> {
+        let namespace = try!(self.release_namespace(release));
+        let mut jobs: Vec<kube::Deployment> = Vec::new();
+        for convention in &self.label_conventions {
+            let selector = format!("{}={}", convention.release, self.release_name(release));
+            for job in try!(self.kube.list(JOBS, Some(&namespace), &selector)) {
+                if job.metadata.annotations.contains_key("helm.sh/hook")
+                    && !jobs.iter().any(|j| j.metadata.name == job.metadata.name)
+                {
+                    jobs.push(job);
+                }
+            }
+        }
+        Ok(jobs)
+    }
+
+    // the hook jobs of `release` that haven't succeeded yet
+    fn pending_hooks(&self, release: &str) -> Result<Vec<String>, HelmError> {
+        Ok(try!(self.hook_jobs(release)).into_iter()
+            .filter(|job| job.status.as_ref().and_then(|status| status.succeeded).unwrap_or(0) == 0)
+            .map(|job| format!("{}/{}", JOBS.name, job.metadata.name))
+            .collect())
+    }
+
+    // log the pods of each hook job of `release` that finished since, keeping track of
+    // them in `finished`, and fail with those that failed. The rest are still running
+    fn check_hook_jobs(&self, release: &str, finished: &mut HashSet<String>) -> Result<Vec<String>, HelmError> {
+        let mut pending = Vec::new();
+        let mut failed = Vec::new();
+        for job in try!(self.hook_jobs(release)) {
+            if job.metadata.uid.as_ref().map(|uid| finished.contains(uid)).unwrap_or(false) {
+                continue;
+            }
+            let name = format!("{}/{}", JOBS.name, job.metadata.name);
+            if job.has_condition("Failed") {
+                failed.push(name);
+            } else if job.has_condition("Complete") {
+                logger::info(format_args!("Hook `{}` of `{}` completed.", name, release));
+            } else {
+                pending.push(name);
+                continue;
+            }
+            if let Some(ref namespace) = job.metadata.namespace {
+                self.log_job_pods(namespace, &job.metadata.name);
+            }
+            finished.extend(job.metadata.uid);
+        }
+
+        if !failed.is_empty() {
+            failed.sort();
+            return Err(HelmError::HookFailed(release.to_string(), failed));
+        }
+        Ok(pending)
+    }
+
+    // copy what the pods of `job` printed to stderr, as far as they can still be read
+    fn log_job_pods(&self, namespace: &str, job: &str) {
+        let pods = match self.kube.list(PODS, Some(namespace), &format!("job-name={}", job)) {
+            Ok(pods) => pods,
+            Err(e) => return logger::warn(format_args!("Could not list the pods of job `{}`: {}", job, e)),
+        };
+        for pod in pods {
+            let logs = self.kube.object_url(PODS, namespace, &pod.metadata.name, Some("log"))
+                .and_then(|url| self.kube.get_text(&url));
+            match logs {
+                Ok(logs) => logger::info(format_args!("Logs of hook pod `{}`:\n{}", pod.metadata.name, logs)),
+                Err(e) => logger::warn(format_args!("Could not get the logs of hook pod `{}`: {}",
+                    pod.metadata.name, e)),
+            }
+        }
+    }
+
+    /// The chart and app version `release` is running, if it's deployed.
+    pub fn release_version(&self, release: &str) -> Result<Option<ReleaseVersion>, HelmError> {
+        Ok(try!(self.helm_release(release))
+            .map(|r| {
+                let (chart, version) = split_chart_label(&r.chart)
+                    .map(|(chart, version)| (chart, Some(version)))
+                    .unwrap_or((r.chart.clone(), None));
+                ReleaseVersion {
+                    chart: chart,
+                    version: version,
+                    // helm leaves it empty for charts that don't set one
+                    app_version: r.app_version.and_then(|v| if v.is_empty() { None } else { Some(v) }),
+                }
+            }))
+    }
+
+    // how `helm list` sees `release` in our namespace, whatever state it's in
+    fn helm_release(&self, release: &str) -> Result<Option<HelmReleaseEntry>, HelmError> {
+        let name = self.release_name(release);
+        let cmd = format!("{} --all --output json {} '^{}$'",
+            self.tiller_command("list"), try!(self.list_namespace_flag()), name);
+
+        // helm prints nothing at all when there are no releases
+        let output = try!(self.run(&cmd));
+        if output.is_empty() {
+            return Ok(None);
+        }
+
+        let (page, _) = try!(parse_helm_list(&output, try!(self.client_version()).major));
+        Ok(page.into_iter()
+            .find(|r| r.name == name && self.in_namespace(&r.namespace)))
+    }
+
+    /// The state of the latest revision of `release`, if it exists.
+    pub fn status(&self, release: &str) -> Result<Option<ReleaseStatus>, HelmError> {
+        Ok(try!(self.helm_release(release))
+            .map(|r| r.status.as_ref().map(|s| ReleaseStatus::parse(s)).unwrap_or(ReleaseStatus::Unknown)))
+    }
+
+    /// Get `release` out of a failed or pending state a crashed deploy left it in,
+    /// so it can be upgraded again. Healthy and missing releases are left alone.
+    pub fn recover(&self, release: &str, policy: Recover) -> Result<(), HelmError> {
+        let status = match try!(self.status(release)) {
+            Some(status) => status,
+            None => return Ok(()),
+        };
+        if !status.is_stuck() {
+            return Ok(());
+        }
+
+        match policy {
+            Recover::Fail => Err(HelmError::StuckRelease(release.to_string(), status.to_string())),
+            Recover::Rollback => {
+                logger::warn(format_args!("Release `{}` is {}.", release, status));
+                self.rollback(release)
+            },
+            Recover::Delete => {
+                logger::warn(format_args!("Release `{}` is {}, deleting it.", release, status));
+                self.delete(release, &DeleteOptions { purge: true, ..DeleteOptions::default() })
+            },
+        }
+    }
+
+    /// Roll `release` back to the newest revision that deployed successfully,
+    /// or delete it if there's none, e.g. when its first install failed.
+    pub fn rollback(&self, release: &str) -> Result<(), HelmError> {
+        let revision = try!(self.last_good_revision(release, false));
+        self.rollback_to(release, revision)
+    }
+
+    /// Lock the release of `chart` against other deploys until the returned lock
+    /// is dropped, naming `holder` as the one deploying it. A lock that's never
+    /// released expires after `ttl`.
+    pub fn lock_release(&self, chart: &Chart, holder: &str, ttl: Duration, mode: LockMode)
+        -> Result<ReleaseLock, HelmError>
+    {
+        let phase = self.phase("lock", Some(&chart.release));
+        let namespace = try!(self.chart_namespace(chart));
+        let held = try!(lock::acquire(&self.kube, &namespace, &self.release_name(&chart.release),
+            holder, ttl, mode));
+        phase.succeeded();
+        Ok(held)
+    }
+
+    /// Undo the latest revision of `release`, even one that deployed fine:
+    /// roll back to the good revision before it, or delete the release if
+    /// that revision installed it.
+    pub fn undo(&self, release: &str) -> Result<(), HelmError> {
+        let revision = try!(self.last_good_revision(release, true));
+        self.rollback_to(release, revision)
+    }
+
+    fn rollback_to(&self, release: &str, revision: Option<u64>) -> Result<(), HelmError> {
+        match revision {
+            Some(revision) => {
+                logger::warn(format_args!("Rolling `{}` back to revision {}.", release, revision));
+                try!(self.run(&format!("{} {} {}",
+                    try!(self.release_command("rollback", release)), self.release_name(release), revision)));
+                Ok(())
+            },
+            // a first install that failed has nothing to go back to
+            None => {
+                logger::warn(format_args!("Release `{}` was never deployed, deleting it.", release));
+                self.delete(release, &DeleteOptions { purge: true, ..DeleteOptions::default() })
+            },
+        }
+    }
+
+    // the newest revision of `release` that was deployed successfully, before the latest one if asked
+    fn last_good_revision(&self, release: &str, before_latest: bool) -> Result<Option<u64>, HelmError> {
+        let history = try!(self.helm_history(release));
+        let latest = history.iter().map(|r| r.revision).max().unwrap_or(0);
+        Ok(history.iter()
+            .filter(|r| !before_latest || r.revision < latest)
+            .filter(|r| match ReleaseStatus::parse(&r.status) {
+                ReleaseStatus::Deployed | ReleaseStatus::Superseded => true,
+                _ => false,
+            })
+            .map(|r| r.revision)
+            .max())
+    }
+
+    /// Every revision of `release` helm still keeps, oldest first.
+    pub fn history(&self, release: &str) -> Result<Vec<Revision>, HelmError> {
+        let mut history: Vec<Revision> = try!(self.helm_history(release)).into_iter()
+            .map(|r| {
+                let (chart, version) = split_chart_label(&r.chart)
+                    .map(|(chart, version)| (chart, Some(version)))
+                    .unwrap_or((r.chart, None));
+                Revision {
+                    revision: r.revision,
+                    chart: chart,
+                    version: version,
+                    app_version: r.app_version,
+                    status: ReleaseStatus::parse(&r.status),
+                    description: r.description,
+                    updated: r.updated,
+                }
+            })
+            .collect();
+        history.sort_by_key(|r| r.revision);
+        Ok(history)
+    }
+
+    fn helm_history(&self, release: &str) -> Result<Vec<HelmRevision>, HelmError> {
+        let output = try!(self.run(&format!("{} --output json {}",
+            try!(self.release_command("history", release)), self.release_name(release))));
+        serde_json::from_str(&output)
+            .map_err(|e| HelmError::from(e).context("parsing the output of `helm history`"))
+    }
+
+    /// The rendered manifests of the currently deployed revision of `release`.
+    pub fn manifest(&self, release: &str) -> Result<String, HelmError> {
+        self.run_quiet(&format!("{} {}",
+            try!(self.release_command("get manifest", release)), self.release_name(release)))
+    }
+
+    /// What changed in the manifests of `release` from revision `from` to revision `to`.
+    pub fn diff_revisions(&self, release: &str, from: u64, to: u64) -> Result<ManifestDiff, HelmError> {
+        let old = try!(self.revision_manifest(release, from));
+        let new = try!(self.revision_manifest(release, to));
+        Ok(ManifestDiff::new(release, from, &old, to, &new))
+    }
+
+    fn revision_manifest(&self, release: &str, revision: u64) -> Result<String, HelmError> {
+        self.run_quiet(&format!("{} --revision {} {}",
+            try!(self.release_command("get manifest", release)), revision, self.release_name(release)))
+    }
+
+    /// The NOTES.txt of `release` as rendered by its last deploy, if its chart has one.
+    pub fn notes(&self, release: &str) -> Result<Option<String>, HelmError> {
+        // helm 2 only shows the notes as part of the status
+        let command = if try!(self.client_version()).major >= 3 { "get notes" } else { "status" };
+        let output = try!(self.run_quiet(&format!("{} {}",
+            try!(self.release_command(command, release)), self.release_name(release))));
+
+        // either way they come last, after a `NOTES:` line
+        Ok(output.find("NOTES:")
+            .map(|at| output[at + "NOTES:".len()..].trim().to_string())
+            .filter(|notes| !notes.is_empty()))
+    }
+
+    /// The values the user supplied when `release` was last deployed.
+    pub fn values(&self, release: &str) -> Result<HashMap<String, Value>, HelmError> {
+        let output = try!(self.run_quiet(&format!("{} {}",
+            try!(self.release_command("get values", release)), self.release_name(release))));
+        let output = strip_values_header(&output);
+        if output.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let values: Option<HashMap<String, Value>> = try!(serde_yaml::from_str(output)
+            .map_err(|e| HelmError::from(e).context(format!("parsing the values of `{}`", release))));
+        Ok(values.unwrap_or_default())
+    }
+
+    /// Whether upgrading `deployed` to `chart` would be a no-op: the same
+    /// chart at the same pinned version with the same values, deployed successfully.
+    /// Unpinned and local charts could have changed underneath us, and we can't
+    /// compare encrypted values without decrypting them, so those never are.
+    /// Neither are upgrades that force, recreate pods or reset values, which do something anyway.
+    pub fn unchanged(&self, chart: &Chart, deployed: &Release, options: &UpgradeOptions) -> Result<bool, HelmError> {
+        if options.force || options.recreate_pods || options.reset_values {
+            return Ok(false);
+        }
+        if chart.path.is_some() || chart.version.is_none() || chart.secret_values_files.is_some()
+            || chart.values_template.is_some() || chart.values_files.is_some()
+            || chart.values_json_files.is_some() || chart.values_json.is_some()
+        {
+            return Ok(false);
+        }
+        if chart.name != deployed.chart || chart.version != deployed.version {
+            return Ok(false);
+        }
+        // a failed or pending release has to be upgraded to get out of it
+        let status = match deployed.status {
+            Some(status) => Some(status),
+            None => try!(self.status(&chart.release)),
+        };
+        if status != Some(ReleaseStatus::Deployed) {
+            return Ok(false);
+        }
+
+        // compare both sides after the same trip through yaml,
+        // so numbers and such end up with the same representation
+        let requested = chart.overrides.clone().unwrap_or_default();
+        let requested: Option<HashMap<String, Value>> =
+            try!(serde_yaml::from_str(&try!(serde_yaml::to_string(&requested))));
+
+        Ok(requested.unwrap_or_default() == try!(self.values(&chart.release)))
+    }
+
+    /// Show what upgrading `chart` would change using the helm-diff plugin.
+    /// An empty diff means the upgrade would be a no-op.
+    pub fn diff(&self, chart: &Chart) -> Result<String, HelmError> {
+        let mut cmd = vec![];
+
+        // start of the command, diff a new release against nothing
+        cmd.push(format!("{} --allow-unreleased --namespace {}",
+            self.tiller_command("diff upgrade"), try!(self.chart_namespace(chart))));
+
+        let (chart_args, _values_files) = try!(self.chart_args(chart));
+        cmd.extend(chart_args);
+
+        // end of the command
+        cmd.push(format!("{} {}", self.release_name(&chart.release), shell_quote(&chart.reference())));
+
+        self.run(&cmd.join(" "))
+    }
+
+    /// What the `label` in the selector of `service`, in the namespace of `chart`, selects,
+    /// e.g. the color a blue-green deploy routes traffic to.
+    pub fn service_selector(&self, chart: &Chart, service: &str, label: &str) -> Result<Option<String>, HelmError> {
+        let namespace = try!(self.chart_namespace(chart));
+        let url = try!(self.kube.object_url(kube::SERVICES, &namespace, service, None));
+        let service: Value = try!(self.kube.get(&url));
+        Ok(service.as_object()
+            .and_then(|service| service.get("spec"))
+            .and_then(Value::as_object)
+            .and_then(|spec| spec.get("selector"))
+            .and_then(Value::as_object)
+            .and_then(|selector| selector.get(label))
+            .and_then(Value::as_str)
+            .map(|value| value.to_string()))
+    }
+
+    /// Point `service`, in the namespace of `chart`, at the pods whose `label` is `value`,
+    /// leaving the rest of its selector alone.
+    pub fn select_service(&self, chart: &Chart, service: &str, label: &str, value: &str) -> Result<(), HelmError> {
+        let namespace = try!(self.chart_namespace(chart));
+        let url = try!(self.kube.object_url(kube::SERVICES, &namespace, service, None));
+        // the service comes back with its resourceVersion, so nobody else's change is overwritten
+        let mut current: Value = try!(self.kube.get(&url));
+        let selector = current.as_object_mut()
+            .and_then(|service| service.get_mut("spec"))
+            .and_then(Value::as_object_mut)
+            .map(|spec| spec.entry("selector".to_string()).or_insert_with(|| Value::Object(serde_json::Map::new())))
+            .and_then(Value::as_object_mut);
+        match selector {
+            Some(selector) => selector.insert(label.to_string(), Value::String(value.to_string())),
+            None => return Err(HelmError::MissingConfig(format!("service `{}` has no spec to select with", service))),
+        };
+        try!(self.kube.replace(&url, &current));
+        logger::info(format_args!("Pointed service `{}` at `{}={}`.", service, label, value));
+        Ok(())
+    }
+
+    /// Poll the release's deployments and statefulsets until all their
+    /// replicas are ready, failing if that hasn't happened within `timeout`.
+    /// A release without any has nothing to verify, which is warned about.
+    pub fn verify_rollout(&self, release: &str, timeout: Duration) -> Result<(), HelmError> {
+        let phase = self.phase("verify", Some(release));
+        let deadline = Instant::now() + timeout;
+        let namespace = try!(self.release_namespace(release));
+
+        loop {
+            // the release may be labelled by tiller, helm 3 or the source's own convention
+            let mut seen = HashSet::new();
+            let mut pending = Vec::new();
+            for convention in &self.label_conventions {
+                let selector = self.workload_selector(convention, Some(release));
+                for resource in self.workloads().iter().filter(|resource| ROLLOUT_KINDS.contains(&resource.name)) {
+                    for workload in try!(self.kube.list(*resource, Some(&namespace), &selector)) {
+                        let name = format!("{}/{}", resource.name, workload.metadata.name);
+                        if !convention.selects(&workload.metadata.labels) || !seen.insert(name.clone()) {
+                            continue;
+                        }
+                        if !workload.is_ready() {
+                            pending.push(name);
+                        }
+                    }
+                }
+            }
+
+            if seen.is_empty() {
+                logger::warn(format_args!("Release `{}` has no deployments or statefulsets, \
+                    there's no rollout to verify.", release));
+                phase.succeeded();
+                return Ok(());
+            }
+            if pending.is_empty() {
+                logger::info(format_args!("Release `{}` rolled out.", release));
+                phase.succeeded();
+                return Ok(());
+            }
+            if Instant::now() >= deadline {
+                return Err(HelmError::RolloutTimeout(release.to_string(), pending));
+            }
+
+            logger::info(format_args!("Waiting for `{}` to roll out: {}", release, pending.join(", ")));
+            thread::sleep(Duration::from_secs(ROLLOUT_POLL_SECS));
+        }
+    }
+
+    /// Fetch the dependencies of the chart directory at `path` into its `charts/`,
+    /// exactly as locked if there's a lock file and as required otherwise.
+    /// Private dependency repos have to be among the `repos` we were configured with.
+    pub fn dependency_update(&self, path: &str) -> Result<(), HelmError> {
+        let mut ready = self.dependencies_ready.lock().unwrap();
+        if ready.contains(path) {
+            return Ok(());
+        }
+
+        // helm 2 locks in requirements.lock, helm 3 in Chart.lock
+        let locked = ["requirements.lock", "Chart.lock"].iter()
+            .any(|lock| Path::new(path).join(lock).exists());
+        let subcommand = if locked { "dependency build" } else { "dependency update" };
+        try!(self.run(&format!("{} {}", self.helm_command(subcommand), shell_quote(path))));
+
+        ready.insert(path.to_string());
+        Ok(())
+    }
+
+    /// Package the chart directory at `path` into an archive in `dest`,
+    /// optionally overriding the chart's version and app version.
+    pub fn package(&self, path: &str, version: Option<&str>, app_version: Option<&str>, dest: &Path)
+        -> Result<PathBuf, HelmError>
+    {
+        let mut cmd = vec![self.helm_command("package")];
+        if let Some(version) = version {
+            cmd.push(format!("--version {}", shell_quote(version)));
+        }
+        if let Some(app_version) = app_version {
+            cmd.push(format!("--app-version {}", shell_quote(app_version)));
+        }
+        cmd.push(format!("--destination {}", shell_quote(&dest.to_string_lossy())));
+        cmd.push(shell_quote(path));
+
+        // helm says where it put the archive, e.g. `Successfully packaged chart and saved it to: <path>`
+        let output = try!(self.run(&cmd.join(" ")));
+        output.lines()
+            .filter_map(|line| line.splitn(2, "saved it to:").nth(1))
+            .map(|archive| PathBuf::from(archive.trim()))
+            .next()
+            .ok_or(HelmError::CmdFailed(format!("helm package {}", path)))
+    }
+
+    /// Push the chart archive at `archive` to the registry on `host`, or the first
+    /// registry configured, returning the reference it was pushed to.
+    pub fn push_oci(&self, archive: &Path, host: Option<&str>) -> Result<String, HelmError> {
+        let registry = try!(self.registries.iter()
+            .find(|registry| host.map(|host| registry.host == host).unwrap_or(true))
+            .ok_or(HelmError::MissingConfig(match host {
+                Some(host) => format!("no registry is configured for `{}`", host),
+                None => "`registries` is needed to push charts to an OCI registry".to_string(),
+            })));
+
+        try!(self.registry_login(&registry.host));
+
+        // archives are named `<chart>-<version>.tgz`
+        let file_name = archive.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+        let (name, version) = try!(split_chart_label(file_name.trim_right_matches(".tgz"))
+            .ok_or(HelmError::CmdFailed(format!("`{}` is not a chart archive", archive.display()))));
+        let base = match registry.repository {
+            Some(ref repository) => format!("{}/{}", registry.host, repository.trim_matches('/')),
+            None => registry.host.clone(),
+        };
+        let archive = shell_quote(&archive.to_string_lossy());
+
+        // helm 3.8 made OCI support stable, and replaced `helm chart` with `helm push`
+        if try!(self.client_version()) >= Version::parse("3.8.0").unwrap() {
+            try!(self.run(&format!("{} {} oci://{}", self.helm_command("push"), archive, base)));
+            Ok(format!("oci://{}/{}:{}", base, name, version))
+        } else {
+            let reference = format!("{}/{}:{}", base, name, version);
+            try!(self.run(&format!("{} {} {}", self.helm_command("chart save"), archive, reference)));
+            try!(self.run(&format!("{} {}", self.helm_command("chart push"), reference)));
+            Ok(reference)
+        }
+    }
+
+    // log in to the registry configured for `host`, unless we still are.
+    // Registries that aren't configured are taken to be public
+    fn registry_login(&self, host: &str) -> Result<(), HelmError> {
+        let registry = match self.registries.iter().find(|registry| registry.host == host) {
+            Some(registry) => registry,
+            None => return Ok(()),
+        };
+        let mut logins = self.registry_logins.lock().unwrap();
+        if let Some(&expires) = logins.get(host) {
+            if expires.map(|expires| expires > Instant::now()).unwrap_or(true) {
+                return Ok(());
+            }
+            logger::info(format_args!("The login to `{}` expired, logging in again.", host));
+        }
+
+        let (username, password, expires) = if let Some(ref ecr) = registry.ecr {
+            // hosts look like `<account>.dkr.ecr.<region>.amazonaws.com`
+            let region = try!(ecr.region.clone()
+                .or_else(|| host.split('.').nth(3).map(|region| region.to_string()))
+                .ok_or(HelmError::MissingConfig(format!("`region` is needed to log in to `{}`", host))));
+            let token = try!(self.run_quiet(&format!("aws ecr get-login-password --region {}",
+                shell_quote(&region))));
+            // the token lasts 12 hours, get another one a little before then
+            ("AWS".to_string(), token.trim().to_string(), Some(Instant::now() + Duration::from_secs(ECR_TOKEN_SECS)))
+        } else if let Some(ref acr) = registry.acr {
+            (acr.client_id.clone(), acr.client_secret.clone(), None)
+        } else if let Some(ref username) = registry.username {
+            (username.clone(), registry.password.clone().unwrap_or_default(), None)
+        } else {
+            return Ok(());
+        };
+
+        // the password goes in through a file so it never shows up in a command line
+        let password = try!(private_file(password.as_bytes()));
+        try!(self.run(&format!("{} {} --username {} --password-stdin < {}",
+            self.helm_command("registry login"), shell_quote(host), shell_quote(&username),
+            password.to_path_buf().to_string_lossy())));
+        logins.insert(host.to_string(), expires);
+        Ok(())
+    }
+
+    /// Run `helm lint` on a chart directory, failing on errors and,
+    /// when `strict`, on warnings too.
+    pub fn lint(&self, path: &str, strict: bool) -> Result<(), HelmError> {
+        let cmd = if strict {
+            format!("{} {}", self.helm_command("lint --strict"), path)
+        } else {
+            format!("{} {}", self.helm_command("lint"), path)
+        };
+        self.run(&cmd)
+            .map(|_| { () })
+            .map_err(|_| HelmError::LintFailed(path.to_string()))
+    }
+
+    /// Run the release's test pods with `helm test`, copy their logs to
+    /// stderr and fail with the names of the pods whose tests didn't pass.
+    pub fn test(&self, release: &str) -> Result<(), HelmError> {
+        let phase = self.phase("test", Some(release));
+        let cmd = format!("{} {}", try!(self.release_command("test", release)), self.release_name(release));
+        let output = try!(self.run_unchecked(&cmd));
+        let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+
+        // helm reports each test pod as `RUNNING: <pod>` then `PASSED: <pod>` or `FAILED: <pod>`
+        let mut failed = Vec::new();
+        for line in stdout.lines() {
+            let mut parts = line.trim().splitn(2, ": ");
+            let (state, pod) = match (parts.next(), parts.next()) {
+                (Some(state), Some(pod)) => (state, pod.trim()),
+                _ => continue,
+            };
+            if state != "PASSED" && state != "FAILED" {
+                continue;
+            }
+
+            let log_url = try!(self.kube.object_url(PODS, &try!(self.release_namespace(release)), pod, Some("log")));
+            // missing logs shouldn't hide whether the test passed
+            match self.kube.get_text(&log_url) {
+                Ok(logs) => logger::info(format_args!("Logs of test pod `{}`:\n{}", pod, logs)),
+                Err(e) => logger::warn(format_args!("Could not get the logs of test pod `{}`: {}", pod, e)),
+            }
+
+            if state == "FAILED" {
+                failed.push(pod.to_string());
+            }
+        }
+
+        if !failed.is_empty() {
+            return Err(HelmError::TestsFailed(release.to_string(), failed));
+        }
+        if !output.status.success() {
+            return Err(HelmError::CmdFailed(redact::command(&cmd)));
+        }
+        phase.succeeded();
+        Ok(())
+    }
+
+    /// Whether `release` matches one of the protected release patterns.
+    pub fn is_protected(&self, release: &str) -> bool {
+        self.protected_releases.iter().any(|pattern| glob_match(pattern, release))
+    }
+
+    /// Delete `release`, saying whether there was anything to delete. Without
+    /// `ignore_missing` a release that's already gone fails like helm does.
+    pub fn delete(&self, release: &str, options: &DeleteOptions) -> Result<bool, HelmError> {
+        if self.is_protected(release) {
+            return Err(HelmError::ProtectedRelease(release.to_string()));
+        }
+
+        if options.ignore_missing {
+            let absent = match try!(self.helm_release(release)) {
+                None => true,
+                // tiller keeps deleted releases around until they're purged
+                Some(ref deployed) => {
+                    let status = deployed.status.as_ref().map(|status| ReleaseStatus::parse(status));
+                    !options.purge && status == Some(ReleaseStatus::Deleted)
+                },
+            };
+            if absent {
+                logger::info(format_args!("Release `{}` is already absent, not deleting it.", release));
+                return Ok(false);
+            }
+        }
+
+        let mut cmd = vec![try!(self.release_command("delete", release))];
+
+        // helm 3 has no `--purge`, it purges whatever it deletes
+        if options.purge && try!(self.client_version()).major < 3 {
+            cmd.push("--purge".to_string());
+        }
+        if let Some(timeout) = options.timeout {
+            cmd.push(try!(self.timeout_flag(timeout)));
+        }
+        if options.no_hooks {
+            cmd.push("--no-hooks".to_string());
+        }
+
+        cmd.push(self.release_name(release));
+
+        self.run(&cmd.join(" ")).map(|_| true)
+    }
+}
+
+// split a `<name>-<version>` chart label, e.g. `mysql-0.3.0`
+fn split_chart_label(label: &str) -> Option<(String, String)> {
+    let mut split = label.rsplitn(2, '-');
+    split.next().and_then(|version| {
+        split.next().map(|name| (name.to_string(), version.to_string()))
+    })
+}
+
+// match `text` against a shell-style glob supporting `*` and `?`
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    // position to resume from after the last `*`, if any
+    let mut star: Option<(usize, usize)> = None;
+    let (mut p, mut t) = (0, 0);
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            // let the last `*` swallow one more character and retry
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+// the semver in helm's short version output, e.g. `Client: v2.5.0+g012cb0a`
+fn parse_helm_version(output: &str) -> Result<Version, HelmError> {
+    let version = output.split_whitespace().last().unwrap_or("")
+        .trim_left_matches('v')
+        .split('+').next().unwrap_or("");
+    Version::parse(version).map_err(|_| HelmError::BadVersion(output.to_string()))
+}
+
+// the values `helm get values` printed, without the header helm 3 puts on top
+fn strip_values_header(output: &str) -> &str {
+    let output = output.trim();
+    if output.starts_with(USER_VALUES_HEADER) {
+        output[USER_VALUES_HEADER.len()..].trim()
+    } else {
+        output
+    }
+}
+
+// a page of `helm list --output json` from a helm of the `major` version, and the offset of the next.
+// Helm 2 pages an object with capitalized fields, helm 3 prints every release in a bare list
+fn parse_helm_list(output: &str, major: u64) -> Result<(Vec<HelmReleaseEntry>, Option<String>), HelmError> {
+    let parsed = if major >= 3 {
+        serde_json::from_str::<Vec<Helm3ReleaseEntry>>(output)
+            .map(|releases| (releases.into_iter().map(HelmReleaseEntry::from).collect(), None))
+    } else {
+        serde_json::from_str::<HelmReleaseList>(output).map(|page| (page.releases, page.next))
+    };
+    parsed.map_err(|e| HelmError::from(e).context("parsing the output of `helm list`"))
+}
+
+// a config field that's only optional when a kubeconfig is given instead
+fn required<'a>(field: &'a Option<String>, name: &str) -> Result<&'a str, HelmError> {
+    field.as_ref()
+        .map(|f| f as &str)
+        .ok_or(HelmError::MissingConfig(format!("`{}` is required without a kubeconfig", name)))
+}
+
+// point the caches in `helm_home` into `cache_dir`, the repos and their indexes
+// under `repository` for helm 2 and everything under `cache` for helm 3
+fn link_cache_dir(helm_home: &Path, cache_dir: &Path) -> Result<(), HelmError> {
+    for dir in &["cache", "repository"] {
+        let target = cache_dir.join(dir);
+        try!(fs::create_dir_all(&target)
+            .map_err(|e| HelmError::from(e).context(format!("creating `{}`", target.display()))));
+        try!(symlink(&target, helm_home.join(dir)));
+    }
+    Ok(())
+}
+
+// convert ASCII-armored public keys into a binary keyring file
+fn dearmor_keyring(keys: &str) -> Result<Temp, HelmError> {
+    let armored = try!(private_file(keys.as_bytes()));
+    let keyring = try!(private_file(&[]));
+    let output = try!(Command::new("gpg")
+        .arg("--batch")
+        .arg("--yes")
+        .arg("--dearmor")
+        .arg("--output")
+        .arg(keyring.to_path_buf())
+        .arg(armored.to_path_buf())
+        .output()
+        .map_err(|e| HelmError::from(e).context("running `gpg --dearmor`")));
+    logger::output(&output.stderr);
+    if !output.status.success() {
+        return Err(HelmError::CmdFailed("gpg --dearmor".to_string()));
+    }
+    Ok(keyring)
+}
+
+// read a PEM CA bundle, making sure it holds at least one certificate
+fn read_ca_file(path: &str) -> Result<String, HelmError> {
+    let mut ca_data = String::new();
+    try!(File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut ca_data))
+        .map_err(|_| HelmError::BadCaFile(path.to_string())));
+    if !ca_data.contains("-----BEGIN CERTIFICATE-----") {
+        return Err(HelmError::BadCaFile(path.to_string()));
+    }
+    Ok(ca_data)
+}
+
+// the apiserver as seen from inside a pod
+fn in_cluster_url() -> Result<String, HelmError> {
+    match (env::var("KUBERNETES_SERVICE_HOST"), env::var("KUBERNETES_SERVICE_PORT")) {
+        (Ok(host), Ok(port)) => Ok(format!("https://{}:{}", host, port)),
+        _ => Err(HelmError::MissingConfig(
+            "`url`, KUBERNETES_SERVICE_HOST and _PORT aren't set for `in_cluster`".to_string())),
+    }
+}
+
+// a file of the mounted service account, e.g. `token`
+fn read_service_account(name: &str) -> Result<String, HelmError> {
+    let mut contents = String::new();
+    try!(File::open(Path::new(SERVICE_ACCOUNT_DIR).join(name))
+        .and_then(|mut file| file.read_to_string(&mut contents))
+        .map_err(|e| HelmError::from(e).context(format!("reading the service account's {}", name))));
+    Ok(contents)
+}
+
+// merged values without the chart's secret keys, nor anything from its encrypted files
+fn redact_merged(chart: &Chart, values: &HashMap<String, Value>, mut secret_paths: Vec<String>)
+    -> HashMap<String, Value>
+{
+    secret_paths.extend(chart.secret_keys.iter().flat_map(|keys| keys.iter().cloned()));
+    redact::values(values, &secret_paths)
+}
+
+// render the mustache values file at `path` with `vars`
+fn render_values_template(path: &str, vars: &HashMap<String, String>) -> Result<Vec<u8>, HelmError> {
+    let mut template = String::new();
+    try!(File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut template))
+        .map_err(|e| HelmError::from(e).context(format!("reading values template `{}`", path))));
+
+    let data = vars.iter().fold(HashBuilder::new(), |data, (name, value)| {
+        data.insert(name as &str, value as &str)
+    });
+    let mut values = Vec::new();
+    try!(data.render(&template, &mut values)
+        .map_err(|e| HelmError::ValuesTemplate(path.to_string(), e)));
+    Ok(values)
+}
+
+// whether `name` is a valid DNS-1123 label, like every namespace name has to be
+fn is_dns_label(name: &str) -> bool {
+    name.len() > 0 && name.len() <= 63
+        && name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        && !name.starts_with('-') && !name.ends_with('-')
+}
+
+// kubeconfigs may be base64 encoded, anything else is taken to be YAML
+fn decode_kube_config(kubeconfig: &str) -> String {
+    base64::decode(kubeconfig.trim()).ok()
+        .and_then(|decoded| String::from_utf8(decoded).ok())
+        .unwrap_or(kubeconfig.to_string())
+}
+
+fn decode_base64(data: &str, what: &str) -> Result<Vec<u8>, HelmError> {
+    base64::decode(data.trim())
+        .map_err(|_| HelmError::KubeConfig(format!("`{}` is not valid base64", what)))
+}
+
+// the command and subcommand of a command line, e.g. `helm get values`, without its flags and arguments
+fn command_head(cmd: &str) -> String {
+    cmd.split(' ')
+        .take_while(|word| !word.starts_with('-'))
+        .take(3)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// the trimmed stdout of a command that has to have succeeded
+fn checked_stdout(cmd: &str, output: Output) -> Result<String, HelmError> {
+    if !output.status.success() {
+        return Err(HelmError::CmdFailed(redact::command(cmd)));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+// quote `arg` so /bin/sh passes it through as a single word
+fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace("'", "'\\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a helm 3 that deploys anywhere, running its commands with `executor`
+    fn fake_helm(executor: &FakeExecutor) -> Helm {
+        let mut helm = Helm::configure(Config::builder(None::<&str>)
+            .url("https://127.0.0.1:6443".to_string())
+            .token("token".to_string())
+            .skip_tls_verify(true)
+            .build().unwrap()).unwrap();
+        executor.respond("version --client", 0, "v3.2.4+g0ad800e");
+        helm.set_executor(Box::new(executor.clone()));
+        helm
+    }
+
+    // `web` as helm 3 lists it, in `status`
+    fn listed_web(status: &str) -> String {
+        format!(r#"[{{"name": "web", "namespace": "prod", "revision": "2", "updated": "2020-01-01 00:00:00 +0000 UTC",
+            "status": "{}", "chart": "nginx-1.2.0", "app_version": "1.19"}}]"#, status)
+    }
+
+    fn deployed_web(status: ReleaseStatus) -> Release {
+        Release {
+            name: "web".to_string(),
+            chart: "nginx".to_string(),
+            version: Some("1.2.0".to_string()),
+            app_version: Some("1.19".to_string()),
+            namespace: "prod".to_string(),
+            revision: Some(2),
+            status: Some(status),
+            updated: None,
+        }
+    }
+
+    fn web_chart() -> Chart {
+        let mut overrides = HashMap::new();
+        overrides.insert("replicas".to_string(), Value::U64(2));
+        Chart::builder("web", "nginx")
+            .version("1.2.0".to_string())
+            .overrides(overrides)
+            .namespace("prod".to_string())
+            .build().unwrap()
+    }
+
+    #[test]
+    fn parses_helm_2_list() {
+        let output = r#"{"Next": "web", "Releases": [{"Name": "api", "Revision": 3,
+            "Updated": "Mon Jan  1 00:00:00 2018", "Status": "DEPLOYED",
+            "Chart": "api-1.2.0", "AppVersion": "1.2", "Namespace": "prod"}]}"#;
+        let (releases, next) = parse_helm_list(output, 2).unwrap();
+        assert_eq!(next, Some("web".to_string()));
+        assert_eq!(releases.len(), 1);
+        assert_eq!(releases[0].name, "api");
+        assert_eq!(releases[0].namespace, "prod");
+        assert_eq!(releases[0].revision, Some(3));
+        assert_eq!(releases[0].status, Some("DEPLOYED".to_string()));
+    }
+
+    #[test]
+    fn parses_helm_3_list() {
+        let output = r#"[{"name": "api", "namespace": "prod", "revision": "3",
+            "updated": "2020-01-01 00:00:00 +0000 UTC", "status": "deployed",
+            "chart": "api-1.2.0", "app_version": "1.2"}]"#;
+        let (releases, next) = parse_helm_list(output, 3).unwrap();
+        assert_eq!(next, None);
+        assert_eq!(releases.len(), 1);
+        assert_eq!(releases[0].name, "api");
+        assert_eq!(releases[0].namespace, "prod");
+        assert_eq!(releases[0].chart, "api-1.2.0");
+        assert_eq!(releases[0].revision, Some(3));
+        assert_eq!(releases[0].status.as_ref().map(|s| ReleaseStatus::parse(s)), Some(ReleaseStatus::Deployed));
+    }
+
+    #[test]
+    fn rejects_the_other_helms_list() {
+        assert!(parse_helm_list(r#"[{"name": "api", "namespace": "prod", "chart": "api-1.2.0"}]"#, 2).is_err());
+        assert!(parse_helm_list(r#"{"Next": "", "Releases": []}"#, 3).is_err());
+    }
+
+    #[test]
+    fn charts_come_from_their_repo() {
+        assert_eq!(Chart::builder("web", "nginx").build().unwrap().reference(), "stable/nginx");
+        assert_eq!(Chart::builder("web", "nginx").repo("private".to_string()).build().unwrap().reference(),
+            "private/nginx");
+        assert_eq!(Chart::builder("web", "nginx").path("./charts/web".to_string()).build().unwrap().reference(),
+            "./charts/web");
+        assert!(Chart::builder("web", "nginx").repo("private".to_string()).path("./charts/web".to_string())
+            .build().is_err());
+    }
+
+    #[test]
+    fn strips_helm_3_values_header() {
+        assert_eq!(strip_values_header("USER-SUPPLIED VALUES:\nimage:\n  tag: v1\n"), "image:\n  tag: v1");
+        assert_eq!(strip_values_header("USER-SUPPLIED VALUES:\nnull\n"), "null");
+        assert_eq!(strip_values_header("image:\n  tag: v1\n"), "image:\n  tag: v1");
+        assert_eq!(strip_values_header(""), "");
+    }
+
+    #[test]
+    fn digests_match_across_algorithms() {
+        let kube = MockKubeClient::new();
+        kube.respond("GET", "/apis/apps/v1/deployments", 200, r#"{"items": [{"metadata": {"name": "web",
+            "namespace": "prod", "labels": {"heritage": "Tiller", "release": "web", "chart": "nginx-1.2.0"}}}]}"#);
+        kube.respond("GET", "/apis/apps/v1", 200, r#"{"resources": [{"name": "deployments"}]}"#);
+
+        let mut md5 = fake_helm(&FakeExecutor::new());
+        md5.set_kube_client(Box::new(kube.clone()));
+        let mut sha256 = fake_helm(&FakeExecutor::new());
+        sha256.digest_algorithm = DigestAlgorithm::Sha256;
+        sha256.set_kube_client(Box::new(kube.clone()));
+
+        let (old, new) = (md5.digest().unwrap(), sha256.digest().unwrap());
+        assert!(new.starts_with("sha256:"));
+        // the state didn't change along with the algorithm
+        assert!(sha256.same_digest(&old, &new).unwrap());
+        assert!(md5.same_digest(&new, &old).unwrap());
+        assert!(!sha256.same_digest("d41d8cd98f00b204e9800998ecf8427e", &new).unwrap());
+        // the digest of nothing at all is of another state
+        assert!(old != "d41d8cd98f00b204e9800998ecf8427e");
+    }
+
+    // `web` deployed by helm 3 into `prod`, as the apiserver lists its deployments
+    fn helm_3_web_kube(ready_replicas: u64) -> MockKubeClient {
+        let kube = MockKubeClient::new();
+        kube.respond("GET", "/namespaces/prod/deployments", 200, &format!(r#"{{"items": [{{"metadata": {{
+            "name": "web", "namespace": "prod", "generation": 1,
+            "labels": {{"app.kubernetes.io/managed-by": "Helm", "app.kubernetes.io/instance": "web"}}}},
+            "spec": {{"replicas": 2}}, "status": {{"observedGeneration": 1, "readyReplicas": {}}}}}]}}"#,
+            ready_replicas));
+        kube.respond("GET", "/namespaces/prod/statefulsets", 200, r#"{"items": []}"#);
+        kube.respond("GET", "/apis/apps/v1", 200,
+            r#"{"resources": [{"name": "deployments"}, {"name": "statefulsets"}]}"#);
+        kube
+    }
+
+    #[test]
+    fn rollouts_of_helm_3_releases_wait_for_their_workloads() {
+        let executor = FakeExecutor::new();
+        executor.respond("list --all", 0, &listed_web("deployed"));
+        let mut helm = fake_helm(&executor);
+        helm.set_kube_client(Box::new(helm_3_web_kube(1)));
+
+        match helm.verify_rollout("web", Duration::from_secs(0)) {
+            Err(HelmError::RolloutTimeout(release, pending)) => {
+                assert_eq!(release, "web");
+                assert_eq!(pending, vec!["deployments/web"]);
+            },
+            other => panic!("expected a rollout timeout, got {:?}", other),
+        }
+
+        helm.set_kube_client(Box::new(helm_3_web_kube(2)));
+        assert!(helm.verify_rollout("web", Duration::from_secs(0)).is_ok());
+    }
+
+    #[test]
+    fn unchanged_releases_are_deployed_with_the_same_values() {
+        let executor = FakeExecutor::new();
+        executor.respond("list --all", 0, &listed_web("deployed"));
+        executor.respond("get values", 0, "USER-SUPPLIED VALUES:\nreplicas: 2\n");
+        let helm = fake_helm(&executor);
+
+        let options = UpgradeOptions::default();
+        assert!(helm.unchanged(&web_chart(), &deployed_web(ReleaseStatus::Deployed), &options).unwrap());
+        // the namespace came from `helm list`, as the source has none
+        assert!(executor.invocations().iter().any(|cmd| cmd.contains("get values --namespace prod")));
+    }
+
+    #[test]
+    fn stuck_releases_are_never_unchanged() {
+        let executor = FakeExecutor::new();
+        executor.respond("get values", 0, "USER-SUPPLIED VALUES:\nreplicas: 2\n");
+        let helm = fake_helm(&executor);
+
+        let options = UpgradeOptions::default();
+        for &status in &[ReleaseStatus::Failed, ReleaseStatus::PendingUpgrade] {
+            assert!(!helm.unchanged(&web_chart(), &deployed_web(status), &options).unwrap());
+        }
+    }
+
+    #[test]
+    fn upgrades_that_do_something_anyway_are_never_unchanged() {
+        let executor = FakeExecutor::new();
+        executor.respond("list --all", 0, &listed_web("deployed"));
+        executor.respond("get values", 0, "USER-SUPPLIED VALUES:\nreplicas: 2\n");
+        let helm = fake_helm(&executor);
+
+        let deployed = deployed_web(ReleaseStatus::Deployed);
+        for options in &[
+            UpgradeOptions { force: true, ..UpgradeOptions::default() },
+            UpgradeOptions { recreate_pods: true, ..UpgradeOptions::default() },
+            UpgradeOptions { reset_values: true, ..UpgradeOptions::default() },
+        ] {
+            assert!(!helm.unchanged(&web_chart(), &deployed, options).unwrap());
+        }
+    }
+
+    #[test]
+    fn changed_values_are_not_unchanged() {
+        let executor = FakeExecutor::new();
+        executor.respond("list --all", 0, &listed_web("deployed"));
+        executor.respond("get values", 0, "USER-SUPPLIED VALUES:\nreplicas: 3\n");
+        let helm = fake_helm(&executor);
+
+        let options = UpgradeOptions::default();
+        assert!(!helm.unchanged(&web_chart(), &deployed_web(ReleaseStatus::Deployed), &options).unwrap());
+    }
+
+    #[test]
+    fn upgrades_time_out_in_a_duration_on_helm_3() {
+        let executor = FakeExecutor::new();
+        let helm = fake_helm(&executor);
+
+        let chart = Chart::builder("web", "nginx")
+            .path("./charts/web".to_string())
+            .namespace("prod".to_string())
+            .build().unwrap();
+        helm.upgrade(&chart, &UpgradeOptions { timeout: Some(300), ..UpgradeOptions::default() }).unwrap();
+        assert!(executor.invocations().iter().any(|cmd| cmd.contains(" upgrade --namespace prod ")
+            && cmd.contains(" --timeout 300s ")));
+    }
+
+    #[test]
+    fn charts_are_quoted_however_their_paths_look() {
+        let executor = FakeExecutor::new();
+        let helm = fake_helm(&executor);
+
+        let chart = Chart::builder("web", "nginx")
+            .path("./charts/my web".to_string())
+            .namespace("prod".to_string())
+            .build().unwrap();
+        helm.upgrade(&chart, &UpgradeOptions::default()).unwrap();
+        helm.diff(&chart).unwrap();
+        let quoted = executor.invocations().into_iter()
+            .filter(|cmd| cmd.contains(" web './charts/my web'"))
+            .count();
+        assert_eq!(quoted, 2);
+    }
+
+    #[test]
+    fn deletes_of_missing_releases_are_skipped() {
+        let executor = FakeExecutor::new();
+        executor.respond("list --all", 0, "[]");
+        let helm = fake_helm(&executor);
+
+        assert_eq!(helm.delete("web", &DeleteOptions::default()).unwrap(), false);
+        assert!(!executor.invocations().iter().any(|cmd| cmd.contains(" delete ") || cmd.contains(" uninstall ")));
+    }
+
+    #[test]
+    fn deletes_of_deployed_releases_go_ahead() {
+        let executor = FakeExecutor::new();
+        executor.respond("list --all", 0, &listed_web("deployed"));
+        let helm = fake_helm(&executor);
+
+        let options = DeleteOptions { purge: true, timeout: Some(60), ..DeleteOptions::default() };
+        assert_eq!(helm.delete("web", &options).unwrap(), true);
+        let delete = executor.invocations().into_iter().find(|cmd| cmd.contains(" delete --namespace prod")).unwrap();
+        assert!(delete.ends_with(" web"));
+        // helm 3 purges by itself and wants durations
+        assert!(!delete.contains("--purge"));
+        assert!(delete.contains("--timeout 60s"));
+    }
+
+    #[test]
+    fn skipping_crds_needs_helm_3() {
+        let options = UpgradeOptions { skip_crds: true, ..UpgradeOptions::default() };
+        assert!(fake_helm(&FakeExecutor::new()).check_options(&options).is_ok());
+
+        let executor = FakeExecutor::new();
+        executor.respond("version --client", 0, "Client: v2.16.1+gbbdfe5e");
+        let helm = fake_helm(&executor);
+        match helm.check_options(&options) {
+            Err(HelmError::WrongHelmVersion(ref required, ref version)) =>
+                assert_eq!((required as &str, version as &str), (">=3", "2.16.1")),
+            other => panic!("expected the wrong helm version, got {:?}", other),
+        }
+        assert!(helm.check_options(&UpgradeOptions::default()).is_ok());
     }
 
-    pub fn delete(&self, release: &str) -> Result<(), HelmError> {
-        let cmd = format!("helm delete {}", release);
-        self.run(&cmd).map(|_| { () })
+    #[test]
+    fn delete_options_ignore_missing_releases_however_they_are_made() {
+        assert!(DeleteOptions::default().ignore_missing);
+        let options: DeleteOptions = serde_json::from_str(r#"{"purge": true}"#).unwrap();
+        assert!(options.ignore_missing);
+        let options: DeleteOptions = serde_json::from_str(r#"{"ignore_missing": false}"#).unwrap();
+        assert!(!options.ignore_missing);
     }
 }
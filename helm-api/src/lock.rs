@@ -0,0 +1,135 @@
+use std::collections::HashMap;
+use std::thread;
+use std::time::{
+    Duration,
+    SystemTime,
+    UNIX_EPOCH,
+};
+use super::error::HelmError;
+use super::kube::{
+    self,
+    Client,
+};
+use super::logger;
+use super::url::Url;
+
+
+const HOLDER_ANNOTATION: &'static str = "helm-resource/holder";
+const EXPIRES_ANNOTATION: &'static str = "helm-resource/expires";
+
+// how often a waiting deploy checks whether the lock is free yet
+const POLL_SECS: u64 = 5;
+
+/// What to do when another deploy holds the lock of a release.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub enum LockMode {
+    /// wait for it to be released or to expire
+    #[serde(rename = "wait")]
+    Wait,
+    /// fail straight away
+    #[serde(rename = "fail")]
+    Fail,
+}
+
+/// The lock of one release, a configmap in its namespace naming who holds it
+/// and until when. It's released when dropped.
+pub struct ReleaseLock<'a> {
+    kube: &'a Client,
+    url: Url,
+    release: String,
+    holder: String,
+    expires: u64,
+}
+
+/// Take the lock of `release` for `holder`, for at most `ttl` should we never release it.
+pub fn acquire<'a>(kube: &'a Client, namespace: &str, release: &str, holder: &str, ttl: Duration,
+    mode: LockMode) -> Result<ReleaseLock<'a>, HelmError>
+{
+    let name = format!("helm-resource-lock.{}", release);
+    let collection_url = try!(kube.collection_url(kube::CONFIG_MAPS, namespace));
+    let url = try!(kube.object_url(kube::CONFIG_MAPS, namespace, &name, None));
+    let mut waiting = false;
+
+    loop {
+        let expires = now() + ttl.as_secs();
+        let mut annotations = HashMap::new();
+        annotations.insert(HOLDER_ANNOTATION.to_string(), holder.to_string());
+        annotations.insert(EXPIRES_ANNOTATION.to_string(), expires.to_string());
+
+        if try!(kube.create(&collection_url, &kube::ConfigMap::new(&name, annotations))) {
+            logger::debug(format_args!("Locked `{}` for {}.", release, holder));
+            return Ok(ReleaseLock {
+                kube: kube,
+                url: url,
+                release: release.to_string(),
+                holder: holder.to_string(),
+                expires: expires,
+            });
+        }
+
+        // someone else has it, unless it went away in the meantime
+        let held: kube::Object = match kube.get(&url) {
+            Ok(held) => held,
+            Err(HelmError::KubeApi { status: 404, .. }) => continue,
+            Err(e) => return Err(e),
+        };
+        let annotations = &held.metadata.annotations;
+        let other = annotations.get(HOLDER_ANNOTATION).cloned().unwrap_or_else(|| "unknown".to_string());
+        let other_expires = annotations.get(EXPIRES_ANNOTATION).and_then(|e| e.parse::<u64>().ok()).unwrap_or(0);
+
+        // a deploy that died holding the lock doesn't get to keep it
+        if other_expires <= now() {
+            logger::warn(format_args!("Taking over the expired lock of `{}` from {}.", release, other));
+            try!(kube.delete_unchanged(&url, held.metadata.resource_version.as_ref().map(|v| v as &str)));
+            continue;
+        }
+
+        if mode == LockMode::Fail {
+            return Err(HelmError::ReleaseLocked(release.to_string(), other));
+        }
+        if !waiting {
+            logger::info(format_args!("Waiting for {} to release the lock of `{}`, it expires in {}s.",
+                other, release, other_expires.saturating_sub(now())));
+            waiting = true;
+        }
+        let until_expired = Duration::from_secs(other_expires.saturating_sub(now()) + 1);
+        thread::sleep(Duration::from_secs(POLL_SECS).min(until_expired));
+    }
+}
+
+impl<'a> ReleaseLock<'a> {
+    fn release(&self) -> Result<(), HelmError> {
+        let held: kube::Object = match self.kube.get(&self.url) {
+            Ok(held) => held,
+            Err(HelmError::KubeApi { status: 404, .. }) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        // only ever delete our own lock, not whoever's took it over once ours expired
+        let annotations = &held.metadata.annotations;
+        let ours = annotations.get(HOLDER_ANNOTATION) == Some(&self.holder)
+            && annotations.get(EXPIRES_ANNOTATION) == Some(&self.expires.to_string());
+        if !ours {
+            logger::warn(format_args!("The lock of `{}` expired before the deploy finished.", self.release));
+            return Ok(());
+        }
+        try!(self.kube.delete_unchanged(&self.url, held.metadata.resource_version.as_ref().map(|v| v as &str)));
+        logger::debug(format_args!("Released the lock of `{}`.", self.release));
+        Ok(())
+    }
+}
+
+impl<'a> Drop for ReleaseLock<'a> {
+    fn drop(&mut self) {
+        // it'll expire on its own if this fails
+        if let Err(e) = self.release() {
+            logger::warn(format_args!("Could not release the lock of `{}`: {}", self.release, e));
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or(0)
+}
@@ -0,0 +1,283 @@
+use std::fmt;
+use std::io::{
+    self,
+    Write,
+};
+use std::sync::{
+    Arc,
+    Mutex,
+};
+use std::sync::atomic::{
+    AtomicBool,
+    ATOMIC_BOOL_INIT,
+    Ordering,
+};
+use std::time::{
+    Duration,
+    Instant,
+    SystemTime,
+    UNIX_EPOCH,
+};
+use super::serde_json::{
+    self,
+    Map,
+    Value,
+};
+
+
+static DEBUG: AtomicBool = ATOMIC_BOOL_INIT;
+static JSON: AtomicBool = ATOMIC_BOOL_INIT;
+
+// how often a long running phase says it's still at it
+const HEARTBEAT_SECS: u64 = 30;
+
+/// How log lines are written.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub enum LogFormat {
+    /// lines for humans
+    #[serde(rename = "text")]
+    Text,
+    /// one JSON object per line, for log aggregators
+    #[serde(rename = "json")]
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Level {
+    /// details only needed to debug the resource, e.g. full command lines
+    Debug,
+    /// concise progress
+    Info,
+    Warn,
+    Error,
+}
+
+/// Log debug lines too, they're left out by default.
+pub fn set_debug(debug: bool) {
+    DEBUG.store(debug, Ordering::SeqCst);
+}
+
+pub fn debug_enabled() -> bool {
+    DEBUG.load(Ordering::SeqCst)
+}
+
+pub fn set_format(format: LogFormat) {
+    JSON.store(format == LogFormat::Json, Ordering::SeqCst);
+}
+
+pub fn json_enabled() -> bool {
+    JSON.load(Ordering::SeqCst)
+}
+
+pub fn log(level: Level, message: fmt::Arguments) {
+    event(level, message, Vec::new());
+}
+
+/// Log `message` along with structured `fields`, which only show up in JSON logs.
+pub fn event(level: Level, message: fmt::Arguments, fields: Vec<(&str, Value)>) {
+    if level == Level::Debug && !debug_enabled() {
+        return;
+    }
+
+    let line = if json_enabled() {
+        json_line(level, &message.to_string(), fields)
+    } else {
+        match level {
+            Level::Warn => format!("Warning: {}\n", message),
+            Level::Error => format!("Error: {}\n", message),
+            Level::Debug | Level::Info => format!("{}\n", message),
+        }
+    };
+
+    // logging must never be the reason a build fails
+    let stderr = io::stderr();
+    let mut stderr = stderr.lock();
+    let _ = stderr.write_all(line.as_bytes());
+    let _ = stderr.flush();
+}
+
+fn json_line(level: Level, message: &str, fields: Vec<(&str, Value)>) -> String {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or(0);
+
+    let mut object = Map::new();
+    object.insert("timestamp".to_string(), Value::U64(timestamp));
+    object.insert("level".to_string(), Value::String(match level {
+        Level::Debug => "debug",
+        Level::Info => "info",
+        Level::Warn => "warn",
+        Level::Error => "error",
+    }.to_string()));
+    object.insert("message".to_string(), Value::String(message.to_string()));
+    for (key, value) in fields {
+        object.insert(key.to_string(), value);
+    }
+
+    serde_json::to_string(&object).unwrap_or_default() + "\n"
+}
+
+pub fn debug(message: fmt::Arguments) {
+    log(Level::Debug, message);
+}
+
+pub fn info(message: fmt::Arguments) {
+    log(Level::Info, message);
+}
+
+pub fn warn(message: fmt::Arguments) {
+    log(Level::Warn, message);
+}
+
+pub fn error(message: fmt::Arguments) {
+    log(Level::Error, message);
+}
+
+/// Pass through the output of a command we ran, e.g. helm's stderr.
+pub fn output(output: &[u8]) {
+    // keep every line of JSON logs an object
+    if json_enabled() {
+        for line in String::from_utf8_lossy(output).lines().filter(|l| !l.trim().is_empty()) {
+            event(Level::Info, format_args!("{}", line), vec![("stream", Value::String("output".to_string()))]);
+        }
+        return;
+    }
+
+    let stderr = io::stderr();
+    let mut stderr = stderr.lock();
+    let _ = stderr.write_all(output);
+    let _ = stderr.flush();
+}
+
+/// A duration in whole milliseconds, for json logs.
+pub fn millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + (duration.subsec_nanos() / 1_000_000) as u64
+}
+
+/// A duration for humans, e.g. `1.250s`.
+pub fn duration(duration: Duration) -> String {
+    format!("{}.{:03}s", duration.as_secs(), duration.subsec_nanos() / 1_000_000)
+}
+
+/// How long a phase took, for the metrics of a deploy.
+#[derive(Debug, Clone, Serialize)]
+pub struct Timing {
+    pub phase: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub release: Option<String>,
+    pub duration_ms: u64,
+    pub succeeded: bool,
+}
+
+/// Where finished phases record their `Timing`.
+pub type Timings = Arc<Mutex<Vec<Timing>>>;
+
+/// One step of a deploy, e.g. the upgrade of a release, that logs when it
+/// begins and ends. It ends as failed when dropped before `succeeded`.
+pub struct Phase {
+    name: &'static str,
+    release: Option<String>,
+    started: Instant,
+    last_heartbeat: Instant,
+    ended: bool,
+    timings: Option<Timings>,
+}
+
+/// Begin the phase `name`, of `release` if it's about one.
+pub fn phase(name: &'static str, release: Option<&str>) -> Phase {
+    let phase = Phase {
+        name: name,
+        release: release.map(|release| release.to_string()),
+        started: Instant::now(),
+        last_heartbeat: Instant::now(),
+        ended: false,
+        timings: None,
+    };
+    phase.log("begin", format_args!("Started {}.", phase.subject()), Vec::new());
+    phase
+}
+
+impl Phase {
+    /// Record how long the phase took in `timings` once it ends.
+    pub fn recorded(mut self, timings: &Timings) -> Self {
+        self.timings = Some(timings.clone());
+        self
+    }
+
+    pub fn succeeded(mut self) {
+        self.end(true);
+    }
+
+    /// Say the phase is still running, if it hasn't in a while.
+    pub fn heartbeat(&mut self) {
+        if self.last_heartbeat.elapsed() < Duration::from_secs(HEARTBEAT_SECS) {
+            return;
+        }
+        self.last_heartbeat = Instant::now();
+        let elapsed = self.started.elapsed();
+        self.log("running", format_args!("Still running {} after {}.", self.subject(), duration(elapsed)),
+            vec![("duration_ms", Value::U64(millis(elapsed)))]);
+    }
+
+    fn end(&mut self, succeeded: bool) {
+        if self.ended {
+            return;
+        }
+        self.ended = true;
+        let elapsed = self.started.elapsed();
+        let fields = vec![
+            ("outcome", Value::String(if succeeded { "succeeded" } else { "failed" }.to_string())),
+            ("duration_ms", Value::U64(millis(elapsed))),
+        ];
+        if let Some(ref timings) = self.timings {
+            timings.lock().unwrap().push(Timing {
+                phase: self.name.to_string(),
+                release: self.release.clone(),
+                duration_ms: millis(elapsed),
+                succeeded: succeeded,
+            });
+        }
+        if succeeded {
+            self.log("end", format_args!("Finished {} in {}.", self.subject(), duration(elapsed)), fields);
+        } else {
+            self.log("end", format_args!("Failed {} after {}.", self.subject(), duration(elapsed)), fields);
+        }
+    }
+
+    // e.g. `upgrade of `web``
+    fn subject(&self) -> String {
+        match self.release {
+            Some(ref release) => format!("{} of `{}`", self.name, release),
+            None => self.name.to_string(),
+        }
+    }
+
+    fn log(&self, event: &str, message: fmt::Arguments, mut fields: Vec<(&str, Value)>) {
+        fields.push(("phase", Value::String(self.name.to_string())));
+        fields.push(("event", Value::String(event.to_string())));
+        if let Some(ref release) = self.release {
+            fields.push(("release", Value::String(release.clone())));
+        }
+
+        // json lines carry their own timestamp
+        if json_enabled() {
+            self::event(Level::Info, message, fields);
+        } else {
+            self::event(Level::Info, format_args!("[{}] {}", clock(), message), fields);
+        }
+    }
+}
+
+impl Drop for Phase {
+    fn drop(&mut self) {
+        self.end(false);
+    }
+}
+
+// the time of day in UTC, e.g. `14:03:12`
+fn clock() -> String {
+    let seconds = SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or(0) % 86400;
+    format!("{:02}:{:02}:{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60)
+}
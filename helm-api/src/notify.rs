@@ -0,0 +1,54 @@
+use super::error::HelmError;
+use super::http;
+use super::mktemp::Temp;
+use super::secrets;
+
+
+/// A webhook told how each put went.
+#[derive(Debug, Clone, Deserialize)]
+pub struct NotifyConfig {
+    /// where the summary is POSTed as JSON
+    pub url: String,
+    /// a whole header to authenticate with, e.g. `Authorization: Bearer ...`
+    pub auth_header: Option<String>,
+    /// PEM encoded CA to trust for the webhook's certificate
+    pub ca_data: Option<String>,
+}
+
+/// A client for a deploy notification webhook.
+pub struct Notifier {
+    url: String,
+    auth_header: Option<String>,
+    ca_cert: Option<Temp>,
+}
+
+impl Notifier {
+    pub fn new(config: NotifyConfig) -> Result<Self, HelmError> {
+        let ca_cert = match config.ca_data {
+            Some(ref ca_data) => Some(try!(secrets::private_file(ca_data.as_bytes()))),
+            None => None,
+        };
+
+        Ok(Notifier {
+            url: config.url,
+            auth_header: config.auth_header,
+            ca_cert: ca_cert,
+        })
+    }
+
+    /// POST `summary`, a JSON document.
+    pub fn send(&self, summary: &[u8]) -> Result<(), HelmError> {
+        let mut headers = vec!["Content-Type: application/json".to_string()];
+        headers.extend(self.auth_header.clone());
+        let ca_cert = self.ca_cert.as_ref().map(|ca_cert| ca_cert.to_path_buf());
+
+        let (status, response) = try!(http::request("POST", &self.url, &headers, Some(summary),
+            ca_cert.as_ref().map(|path| path.as_path())));
+        if status < 200 || status >= 300 {
+            return Err(HelmError::Notify(format!("`{}` returned {}: {}",
+                self.url, status, String::from_utf8_lossy(&response).trim())));
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,79 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::Path;
+use super::error::HelmError;
+use super::semver::{
+    Version,
+    VersionReq,
+};
+use super::serde_yaml;
+
+
+/// The `index.yaml` of a chart repository, as cached by `helm repo update`.
+#[derive(Debug, Deserialize)]
+pub struct RepoIndex {
+    #[serde(default)]
+    pub entries: HashMap<String, Vec<ChartVersion>>,
+}
+
+/// One published version of a chart.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ChartVersion {
+    pub version: String,
+    #[serde(rename = "appVersion")]
+    pub app_version: Option<String>,
+}
+
+impl RepoIndex {
+    pub fn read(path: &Path) -> Result<Self, HelmError> {
+        File::open(path)
+            .map_err(HelmError::from)
+            .and_then(|file| serde_yaml::from_reader(file).map_err(HelmError::from))
+            .map_err(|e| e.context(format!("reading repo index `{}`", path.display())))
+    }
+
+    /// Every published version of `chart`, in no particular order.
+    pub fn versions(&self, chart: &str) -> &[ChartVersion] {
+        self.entries.get(chart).map(|v| v as &[ChartVersion]).unwrap_or(&[])
+    }
+
+    /// The versions of `chart` that are exactly `version` or satisfy it as a
+    /// range, or all of them without a version.
+    pub fn search(&self, chart: &str, version: Option<&str>) -> Vec<ChartVersion> {
+        let requirement = version.and_then(version_range);
+        self.versions(chart).iter()
+            .filter(|entry| match (version, &requirement) {
+                (None, _) => true,
+                (Some(_), &Some(ref requirement)) => parse_version(&entry.version)
+                    .map(|v| requirement.matches(&v))
+                    .unwrap_or(false),
+                (Some(version), &None) => entry.version == version
+                    || entry.version.trim_left_matches('v') == version.trim_left_matches('v'),
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// The newest version of `chart` that satisfies `requirement`.
+    pub fn resolve(&self, chart: &str, requirement: &VersionReq) -> Option<&ChartVersion> {
+        self.versions(chart).iter()
+            .filter_map(|entry| parse_version(&entry.version).map(|version| (version, entry)))
+            .filter(|&(ref version, _)| requirement.matches(version))
+            .max_by(|&(ref a, _), &(ref b, _)| a.cmp(b))
+            .map(|(_, entry)| entry)
+    }
+}
+
+/// A version constraint like `^1.2` or `~2.0.3`, as opposed to an exact
+/// version, which helm understands by itself.
+pub fn version_range(version: &str) -> Option<VersionReq> {
+    if parse_version(version).is_some() {
+        return None;
+    }
+    VersionReq::parse(version).ok()
+}
+
+// chart versions are sometimes prefixed with a `v`
+fn parse_version(version: &str) -> Option<Version> {
+    Version::parse(version.trim_left_matches('v')).ok()
+}
@@ -0,0 +1,154 @@
+use std::fs::{
+    self,
+    File,
+    OpenOptions,
+};
+use std::collections::HashMap;
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::process::Command;
+use super::error::HelmError;
+use super::logger;
+use super::mktemp::Temp;
+
+
+/// Create a temp file only we can read and write, holding `contents`.
+pub fn private_file(contents: &[u8]) -> Result<Temp, HelmError> {
+    let path = try!(Temp::new_file());
+
+    // lock the file down before anything sensitive is written to it
+    try!(fs::set_permissions(&path, fs::Permissions::from_mode(0o600)));
+
+    let mut file = try!(File::create(&path));
+    try!(file.write_all(contents));
+    try!(file.flush());
+
+    Ok(path)
+}
+
+/// A private temp file that is overwritten with zeros before it's removed,
+/// for plaintext secrets that should not linger on disk.
+pub struct ShreddedFile {
+    temp: Temp,
+    len: usize,
+}
+
+impl ShreddedFile {
+    pub fn new(contents: &[u8]) -> Result<Self, HelmError> {
+        Ok(ShreddedFile {
+            temp: try!(private_file(contents)),
+            len: contents.len(),
+        })
+    }
+
+    pub fn path(&self) -> PathBuf {
+        self.temp.to_path_buf()
+    }
+}
+
+impl Drop for ShreddedFile {
+    fn drop(&mut self) {
+        // best effort, the temp file removes itself afterwards either way
+        if let Ok(mut file) = OpenOptions::new().write(true).open(&self.temp) {
+            let _ = file.write_all(&vec![0; self.len]);
+            let _ = file.sync_all();
+        }
+    }
+}
+
+/// Key material sops needs to decrypt values files.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SopsConfig {
+    /// an age private key
+    pub age_key: Option<String>,
+    /// an ASCII-armored GPG private key
+    pub gpg_key: Option<String>,
+    /// extra environment for sops, e.g. `AWS_*` or `GOOGLE_*` credentials for cloud KMS
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+}
+
+/// Decrypts sops encrypted files with the key material from a `SopsConfig`.
+pub struct Sops {
+    env: Vec<(String, String)>,
+    // the key files have to stick around for as long as we might decrypt
+    _age_key: Option<ShreddedFile>,
+    _gnupg_home: Option<Temp>,
+}
+
+impl Sops {
+    pub fn configure(config: Option<SopsConfig>) -> Result<Self, HelmError> {
+        let config = match config {
+            Some(config) => config,
+            // sops may still find credentials on the worker, e.g. an IAM role
+            None => return Ok(Sops {
+                env: Vec::new(),
+                _age_key: None,
+                _gnupg_home: None,
+            }),
+        };
+
+        let mut env: Vec<(String, String)> = config.env.into_iter().collect();
+
+        let age_key = if let Some(ref age_key) = config.age_key {
+            let key_file = try!(ShreddedFile::new(age_key.as_bytes()));
+            env.push(("SOPS_AGE_KEY_FILE".to_string(), key_file.path().to_string_lossy().into_owned()));
+            Some(key_file)
+        } else {
+            None
+        };
+
+        // import the gpg key into a keyring of our own
+        let gnupg_home = if let Some(ref gpg_key) = config.gpg_key {
+            let gnupg_home = try!(Temp::new_dir());
+            try!(fs::set_permissions(&gnupg_home, fs::Permissions::from_mode(0o700)));
+            let gnupg_home_path = gnupg_home.to_path_buf().to_string_lossy().into_owned();
+
+            let key_file = try!(ShreddedFile::new(gpg_key.as_bytes()));
+            let output = try!(Command::new("gpg")
+                .env("GNUPGHOME", &gnupg_home_path)
+                .arg("--batch")
+                .arg("--import")
+                .arg(key_file.path())
+                .output()
+                .map_err(|e| HelmError::from(e).context("running `gpg --batch --import`")));
+            logger::output(&output.stderr);
+            if !output.status.success() {
+                return Err(HelmError::CmdFailed("gpg --batch --import".to_string()));
+            }
+
+            env.push(("GNUPGHOME".to_string(), gnupg_home_path));
+            Some(gnupg_home)
+        } else {
+            None
+        };
+
+        Ok(Sops {
+            env: env,
+            _age_key: age_key,
+            _gnupg_home: gnupg_home,
+        })
+    }
+
+    /// Decrypt the sops encrypted file at `path` into a shredded temp file.
+    /// The plaintext is never logged.
+    pub fn decrypt(&self, path: &str) -> Result<ShreddedFile, HelmError> {
+        logger::info(format_args!("Decrypting `{}`.", path));
+
+        let mut cmd = Command::new("sops");
+        for &(ref key, ref value) in &self.env {
+            cmd.env(key, value);
+        }
+        let output = try!(cmd.arg("--decrypt").arg(path).output()
+            .map_err(|e| HelmError::from(e).context(format!("running `sops --decrypt {}`", path))));
+
+        // only stderr is safe to show, stdout is the plaintext
+        logger::output(&output.stderr);
+        if !output.status.success() {
+            return Err(HelmError::CmdFailed(format!("sops --decrypt {}", path)));
+        }
+
+        ShreddedFile::new(&output.stdout)
+    }
+}
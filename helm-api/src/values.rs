@@ -0,0 +1,104 @@
+use std::fs::File;
+use std::io::Read;
+use super::error::HelmError;
+use super::serde_json::{
+    Map,
+    Value,
+};
+use super::serde_yaml;
+
+
+/// Lay `over` on top of `base`: objects are merged key by key, however deeply
+/// nested, anything else, lists included, is replaced by what's in `over`.
+pub fn merge(base: &mut Value, over: Value) {
+    match (base, over) {
+        (&mut Value::Object(ref mut fields), Value::Object(over)) => for (key, value) in over {
+            if fields.contains_key(&key) {
+                merge(fields.get_mut(&key).unwrap(), value);
+            } else {
+                fields.insert(key, value);
+            }
+        },
+        (base, over) => *base = over,
+    }
+}
+
+/// The values in a YAML (or JSON) document, an empty one holding none.
+pub fn parse(document: &str) -> Result<Value, HelmError> {
+    if document.trim().is_empty() {
+        return Ok(Value::Object(Map::new()));
+    }
+    match try!(serde_yaml::from_str(document)) {
+        Value::Null => Ok(Value::Object(Map::new())),
+        values => Ok(values),
+    }
+}
+
+/// The values in the file at `path`.
+pub fn read_file(path: &str) -> Result<Value, HelmError> {
+    let mut document = String::new();
+    try!(File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut document))
+        .map_err(|e| HelmError::from(e).context(format!("reading the values file `{}`", path))));
+    parse(&document).map_err(|e| e.context(format!("parsing the values file `{}`", path)))
+}
+
+/// The dotted path of every value in `values` that isn't an object, e.g. `db.password`.
+pub fn leaf_paths(values: &Value) -> Vec<String> {
+    let mut paths = Vec::new();
+    if let Value::Object(ref fields) = *values {
+        for (key, value) in fields {
+            collect_leaves(key, value, &mut paths);
+        }
+    }
+    paths
+}
+
+fn collect_leaves(path: &str, value: &Value, paths: &mut Vec<String>) {
+    match *value {
+        Value::Object(ref fields) => for (key, value) in fields {
+            collect_leaves(&format!("{}.{}", path, key), value, paths);
+        },
+        _ => paths.push(path.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::serde_json;
+
+    fn json(document: &str) -> Value {
+        serde_json::from_str(document).unwrap()
+    }
+
+    #[test]
+    fn merges_objects_deeply() {
+        let mut base = json(r#"{"image": {"repository": "nginx", "tag": "1.0"}, "replicas": 1}"#);
+        merge(&mut base, json(r#"{"image": {"tag": "1.1"}, "service": {"port": 80}}"#));
+        assert_eq!(base, json(r#"{"image": {"repository": "nginx", "tag": "1.1"}, "replicas": 1,
+            "service": {"port": 80}}"#));
+    }
+
+    #[test]
+    fn merge_replaces_lists_and_scalars() {
+        let mut base = json(r#"{"args": ["a", "b"], "debug": {"level": 1}}"#);
+        merge(&mut base, json(r#"{"args": ["c"], "debug": false}"#));
+        assert_eq!(base, json(r#"{"args": ["c"], "debug": false}"#));
+    }
+
+    #[test]
+    fn parses_empty_documents_as_no_values() {
+        assert_eq!(parse("").unwrap(), json("{}"));
+        assert_eq!(parse("~\n").unwrap(), json("{}"));
+        assert_eq!(parse("replicas: 2\n").unwrap(), json(r#"{"replicas": 2}"#));
+    }
+
+    #[test]
+    fn finds_leaf_paths() {
+        let mut paths = leaf_paths(&json(r#"{"db": {"password": "x", "auth": {"user": "y"}}, "tags": ["a"]}"#));
+        paths.sort();
+        assert_eq!(paths, vec!["db.auth.user", "db.password", "tags"]);
+        assert!(leaf_paths(&json(r#""scalar""#)).is_empty());
+    }
+}
@@ -0,0 +1,156 @@
+use std::collections::HashMap;
+use super::error::HelmError;
+use super::http;
+use super::mktemp::Temp;
+use super::secrets;
+use super::serde_json::{
+    self,
+    Map,
+    Value,
+};
+
+
+// override values of this form are looked up in vault: `vault:secret/path#key`
+const VAULT_PREFIX: &'static str = "vault:";
+
+
+/// Where vault is and how to log in to it.
+#[derive(Debug, Clone, Deserialize)]
+pub struct VaultConfig {
+    /// e.g. `https://vault.example.com:8200`
+    pub address: String,
+    pub token: Option<String>,
+    pub approle: Option<AppRole>,
+    /// PEM encoded CA to trust for vault's certificate
+    pub ca_data: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AppRole {
+    pub role_id: String,
+    pub secret_id: String,
+    /// where the approle auth method is mounted, defaults to `approle`
+    pub mount: Option<String>,
+}
+
+/// A logged in vault client that resolves `vault:` override values.
+pub struct Vault {
+    address: String,
+    token: String,
+    ca_cert: Option<Temp>,
+    // secrets already read, by path
+    cache: HashMap<String, Map<String, Value>>,
+}
+
+impl Vault {
+    pub fn login(config: VaultConfig) -> Result<Self, HelmError> {
+        let ca_cert = match config.ca_data {
+            Some(ref ca_data) => Some(try!(secrets::private_file(ca_data.as_bytes()))),
+            None => None,
+        };
+
+        let mut vault = Vault {
+            address: config.address.trim_right_matches('/').to_string(),
+            token: String::new(),
+            ca_cert: ca_cert,
+            cache: HashMap::new(),
+        };
+
+        vault.token = match (config.token, config.approle) {
+            (Some(token), _) => token,
+            (None, Some(approle)) => {
+                let mount = approle.mount.unwrap_or("approle".to_string());
+                let mut body = Map::new();
+                body.insert("role_id".to_string(), Value::String(approle.role_id));
+                body.insert("secret_id".to_string(), Value::String(approle.secret_id));
+
+                let response = try!(vault.call("POST", &format!("auth/{}/login", mount),
+                    Some(&try!(serde_json::to_vec(&body)))));
+
+                try!(response.get("auth")
+                    .and_then(|auth| auth.find("client_token"))
+                    .and_then(Value::as_str)
+                    .map(|token| token.to_string())
+                    .ok_or(HelmError::Vault("approle login returned no token".to_string())))
+            },
+            (None, None) => return Err(HelmError::Vault(
+                "either a token or an approle is needed to log in".to_string())),
+        };
+
+        Ok(vault)
+    }
+
+    // call the vault http api at `/v1/<path>`
+    fn call(&self, method: &str, path: &str, body: Option<&[u8]>) -> Result<Map<String, Value>, HelmError> {
+        let url = format!("{}/v1/{}", self.address, path);
+        // logging in is the one call we make without a token
+        let headers = if self.token.is_empty() {
+            vec![]
+        } else {
+            vec![format!("X-Vault-Token: {}", self.token)]
+        };
+        let ca_cert = self.ca_cert.as_ref().map(|ca_cert| ca_cert.to_path_buf());
+
+        let (status, response) = try!(http::request(method, &url, &headers, body,
+            ca_cert.as_ref().map(|path| path.as_path())));
+        if status < 200 || status >= 300 {
+            return Err(HelmError::Vault(format!("{} {} returned {}", method, path, status)));
+        }
+
+        Ok(try!(serde_json::from_slice(&response)))
+    }
+
+    /// Read `key` out of the secret at `path`, for both v1 and v2 KV engines.
+    pub fn read(&mut self, path: &str, key: &str) -> Result<Value, HelmError> {
+        if !self.cache.contains_key(path) {
+            let mut response = try!(self.call("GET", path, None));
+            let mut data = match response.remove("data") {
+                Some(Value::Object(data)) => data,
+                _ => Map::new(),
+            };
+
+            // KV v2 nests the secret itself under data.data, next to its metadata
+            if data.contains_key("metadata") {
+                if let Some(Value::Object(inner)) = data.remove("data") {
+                    data = inner;
+                }
+            }
+
+            self.cache.insert(path.to_string(), data);
+        }
+
+        self.cache[path].get(key)
+            .cloned()
+            .ok_or(HelmError::Vault(format!("secret `{}` has no key `{}`", path, key)))
+    }
+
+    /// Replace every `vault:<path>#<key>` string in `value` with the secret it names.
+    pub fn resolve(&mut self, value: &mut Value) -> Result<(), HelmError> {
+        let reference = match *value {
+            Value::String(ref s) if s.starts_with(VAULT_PREFIX) => s[VAULT_PREFIX.len()..].to_string(),
+            Value::Array(ref mut items) => {
+                for item in items {
+                    try!(self.resolve(item));
+                }
+                return Ok(());
+            },
+            Value::Object(ref mut fields) => {
+                for (_, field) in fields.iter_mut() {
+                    try!(self.resolve(field));
+                }
+                return Ok(());
+            },
+            _ => return Ok(()),
+        };
+
+        let mut parts = reference.splitn(2, '#');
+        let (path, key) = match (parts.next(), parts.next()) {
+            (Some(path), Some(key)) => (path, key),
+            _ => return Err(HelmError::Vault(format!(
+                "`{}{}` should look like `{}<path>#<key>`", VAULT_PREFIX, reference, VAULT_PREFIX))),
+        };
+
+        *value = try!(self.read(path, key));
+        Ok(())
+    }
+}